@@ -0,0 +1,678 @@
+//! Runtime configuration.
+//!
+//! Settings are merged from, lowest priority first: built-in defaults, a TOML
+//! file (`--config` or `CONFIG_FILE`), environment variables and command-line
+//! flags. Every section and field of the file is optional.
+
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::state::{MAX_BPM, MIN_BPM};
+use the_song_model::{SongDimensions, DEFAULT_BPM, MAX_PITCHES};
+use the_song_protocol::PROTOCOL_VERSION;
+
+/// Accent colors given to the tracks of a new song, repeated if there are more tracks
+const DEFAULT_ACCENT_COLORS: [&str; 16] = [
+    "#00ff88", // Mint green
+    "#ff6b6b", // Coral red
+    "#4ecdc4", // Turquoise
+    "#ffe66d", // Yellow
+    "#a8dadc", // Light blue
+    "#ff69b4", // Hot pink
+    "#98d8c8", // Seafoam
+    "#f7b731", // Orange
+    "#a29bfe", // Lavender
+    "#fd79a8", // Pink
+    "#74b9ff", // Sky blue
+    "#55efc4", // Aqua
+    "#fdcb6e", // Gold
+    "#e17055", // Terra cotta
+    "#81ecec", // Cyan
+    "#a29bfe", // Purple
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub rooms: RoomsConfig,
+    pub ticks: TickConfig,
+    pub connections: ConnectionConfig,
+    pub rate_limits: RateLimitConfig,
+    pub abuse: AbuseConfig,
+    pub song: SongConfig,
+    pub render: RenderConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Bearer token for admin endpoints, they are disabled without one
+    pub admin_token: Option<String>,
+    /// How long shutdown may take before remaining connections are dropped
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 3000,
+            admin_token: None,
+            shutdown_timeout_secs: 10,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Directory songs are kept in
+    pub data_dir: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("data"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Rooms without connections are unloaded to storage after this long
+    pub idle_timeout_secs: u64,
+    /// Connections a room accepts before refusing more, 0 for no limit
+    pub max_connections: usize,
+    /// Rooms that may be stored before new ones are refused, 0 for no limit
+    pub max_rooms: usize,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 300,
+            max_connections: 0,
+            max_rooms: 10_000,
+        }
+    }
+}
+
+impl RoomsConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TickConfig {
+    /// How often online user counts are broadcast
+    pub stats_interval_ms: u64,
+    /// How often mouse positions are broadcast
+    pub mouse_interval_ms: u64,
+}
+
+impl Default for TickConfig {
+    fn default() -> Self {
+        Self {
+            stats_interval_ms: 1000,
+            mouse_interval_ms: 100,
+        }
+    }
+}
+
+impl TickConfig {
+    pub fn stats_interval(&self) -> Duration {
+        Duration::from_millis(self.stats_interval_ms)
+    }
+
+    pub fn mouse_interval(&self) -> Duration {
+        Duration::from_millis(self.mouse_interval_ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// Frames queued for a client before it counts as lagging
+    pub send_queue_capacity: usize,
+    /// How long a new connection may take to send `ClientHello` before it is refused
+    pub hello_timeout_ms: u64,
+    /// How long to try delivering the close frame to a lagging client
+    pub slow_consumer_close_timeout_ms: u64,
+    /// Oldest protocol version accepted, 0 also accepts hellos that do not state one
+    pub min_protocol_version: u32,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            send_queue_capacity: 256,
+            hello_timeout_ms: 500,
+            slow_consumer_close_timeout_ms: 1000,
+            min_protocol_version: 0,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    pub fn hello_timeout(&self) -> Duration {
+        Duration::from_millis(self.hello_timeout_ms)
+    }
+
+    pub fn slow_consumer_close_timeout(&self) -> Duration {
+        Duration::from_millis(self.slow_consumer_close_timeout_ms)
+    }
+}
+
+/// Flood protection, a rate of 0 turns that limit off
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Largest frame a client may send
+    pub max_frame_bytes: usize,
+    /// Mouse updates per second and burst of one connection
+    pub mouse_per_sec: f64,
+    pub mouse_burst: u32,
+    /// Synthesizer updates, and any other message, per second and burst of one connection
+    pub synthesizer_per_sec: f64,
+    pub synthesizer_burst: u32,
+    /// Mouse updates per second and burst of all connections from one IP
+    pub ip_mouse_per_sec: f64,
+    pub ip_mouse_burst: u32,
+    /// Synthesizer updates per second and burst of all connections from one IP
+    pub ip_synthesizer_per_sec: f64,
+    pub ip_synthesizer_burst: u32,
+    /// New rooms per second and burst one IP may create
+    pub ip_rooms_per_sec: f64,
+    pub ip_rooms_burst: u32,
+    /// Audio renders per second and burst one IP may ask for
+    pub ip_renders_per_sec: f64,
+    pub ip_renders_burst: u32,
+    /// Dropped messages tolerated within the violation window before disconnecting
+    pub max_violations: u32,
+    pub violation_window_secs: u64,
+    /// Key per-IP limits on the last `X-Forwarded-For` hop, only behind a trusted proxy
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: 256 * 1024,
+            mouse_per_sec: 30.0,
+            mouse_burst: 60,
+            synthesizer_per_sec: 20.0,
+            synthesizer_burst: 100,
+            ip_mouse_per_sec: 120.0,
+            ip_mouse_burst: 240,
+            ip_synthesizer_per_sec: 60.0,
+            ip_synthesizer_burst: 300,
+            ip_rooms_per_sec: 0.01,
+            ip_rooms_burst: 10,
+            ip_renders_per_sec: 0.1,
+            ip_renders_burst: 3,
+            max_violations: 50,
+            violation_window_secs: 10,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn violation_window(&self) -> Duration {
+        Duration::from_secs(self.violation_window_secs)
+    }
+
+    fn buckets(&self) -> [(&'static str, f64, u32); 6] {
+        [
+            ("mouse", self.mouse_per_sec, self.mouse_burst),
+            (
+                "synthesizer",
+                self.synthesizer_per_sec,
+                self.synthesizer_burst,
+            ),
+            ("ip_mouse", self.ip_mouse_per_sec, self.ip_mouse_burst),
+            (
+                "ip_synthesizer",
+                self.ip_synthesizer_per_sec,
+                self.ip_synthesizer_burst,
+            ),
+            ("ip_rooms", self.ip_rooms_per_sec, self.ip_rooms_burst),
+            ("ip_renders", self.ip_renders_per_sec, self.ip_renders_burst),
+        ]
+    }
+}
+
+/// Note quotas per user, a limit of 0 turns that check off
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AbuseConfig {
+    /// Length of the sliding window the quotas apply to
+    pub window_secs: u64,
+    /// Notes one user may add within the window
+    pub max_notes_added: usize,
+    /// Notes one user may remove within the window
+    pub max_notes_removed: usize,
+    /// Other people's notes one user may always remove within the window
+    pub mass_delete_min_notes: usize,
+    /// Beyond that, the largest share of other people's notes one user may remove
+    pub mass_delete_share: f64,
+}
+
+impl Default for AbuseConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            max_notes_added: 300,
+            max_notes_removed: 300,
+            mass_delete_min_notes: 20,
+            mass_delete_share: 0.25,
+        }
+    }
+}
+
+impl AbuseConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+/// Shape of newly created songs, existing songs keep the shape they were created with
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SongConfig {
+    pub tracks: usize,
+    pub pitches: usize,
+    pub default_bpm: f64,
+    pub accent_colors: Vec<String>,
+}
+
+impl Default for SongConfig {
+    fn default() -> Self {
+        let dimensions = SongDimensions::default();
+        Self {
+            tracks: dimensions.tracks,
+            pitches: dimensions.pitches,
+            default_bpm: DEFAULT_BPM,
+            accent_colors: DEFAULT_ACCENT_COLORS.map(String::from).to_vec(),
+        }
+    }
+}
+
+impl SongConfig {
+    pub fn dimensions(&self) -> SongDimensions {
+        SongDimensions {
+            tracks: self.tracks,
+            pitches: self.pitches,
+        }
+    }
+
+    /// Accent color of `track` in a new song
+    pub fn accent_color(&self, track: usize) -> &str {
+        &self.accent_colors[track % self.accent_colors.len()]
+    }
+}
+
+/// Audio rendering of songs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    pub sample_rate: u32,
+    /// Longest render, later notes are cut. Bounds the memory a render takes
+    pub max_seconds: u32,
+    /// Renders running at once across all clients, more are refused
+    pub max_concurrent: usize,
+    /// The editor's sound pack, instruments without a sample in it are synthesized
+    pub sound_pack_dir: PathBuf,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            max_seconds: 300,
+            max_concurrent: 2,
+            sound_pack_dir: PathBuf::from("../ui/src/assets/samplers/sound-pack"),
+        }
+    }
+}
+
+/// Command-line flags, each also readable from the environment variable shown in `--help`
+#[derive(Debug, Default, Parser)]
+#[command(about = "Backend of The Song")]
+pub struct Cli {
+    /// TOML config file
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "LISTEN_HOST")]
+    pub host: Option<String>,
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+    #[arg(long, env = "DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    #[arg(long, env = "ROOM_IDLE_TIMEOUT_SECS")]
+    pub room_idle_timeout_secs: Option<u64>,
+    #[arg(long, env = "ROOM_MAX_CONNECTIONS")]
+    pub room_max_connections: Option<usize>,
+    #[arg(long, env = "MAX_ROOMS")]
+    pub max_rooms: Option<usize>,
+    #[arg(long, env = "STATS_INTERVAL_MS")]
+    pub stats_interval_ms: Option<u64>,
+    #[arg(long, env = "MOUSE_INTERVAL_MS")]
+    pub mouse_interval_ms: Option<u64>,
+    #[arg(long, env = "SEND_QUEUE_CAPACITY")]
+    pub send_queue_capacity: Option<usize>,
+    #[arg(long, env = "HELLO_TIMEOUT_MS")]
+    pub hello_timeout_ms: Option<u64>,
+    #[arg(long, env = "MIN_PROTOCOL_VERSION")]
+    pub min_protocol_version: Option<u32>,
+    #[arg(long, env = "MAX_FRAME_BYTES")]
+    pub max_frame_bytes: Option<usize>,
+    #[arg(long, env = "MOUSE_PER_SEC")]
+    pub mouse_per_sec: Option<f64>,
+    #[arg(long, env = "SYNTHESIZER_PER_SEC")]
+    pub synthesizer_per_sec: Option<f64>,
+    #[arg(long, env = "IP_MOUSE_PER_SEC")]
+    pub ip_mouse_per_sec: Option<f64>,
+    #[arg(long, env = "IP_SYNTHESIZER_PER_SEC")]
+    pub ip_synthesizer_per_sec: Option<f64>,
+    #[arg(long, env = "IP_ROOMS_PER_SEC")]
+    pub ip_rooms_per_sec: Option<f64>,
+    #[arg(long, env = "IP_RENDERS_PER_SEC")]
+    pub ip_renders_per_sec: Option<f64>,
+    #[arg(long, env = "MAX_RATE_VIOLATIONS")]
+    pub max_rate_violations: Option<u32>,
+    #[arg(long, env = "TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: Option<bool>,
+    #[arg(long, env = "MAX_NOTES_ADDED")]
+    pub max_notes_added: Option<usize>,
+    #[arg(long, env = "MAX_NOTES_REMOVED")]
+    pub max_notes_removed: Option<usize>,
+    #[arg(long, env = "MASS_DELETE_MIN_NOTES")]
+    pub mass_delete_min_notes: Option<usize>,
+    #[arg(long, env = "SONG_TRACKS")]
+    pub song_tracks: Option<usize>,
+    #[arg(long, env = "SONG_PITCHES")]
+    pub song_pitches: Option<usize>,
+    #[arg(long, env = "SONG_DEFAULT_BPM")]
+    pub song_default_bpm: Option<f64>,
+    #[arg(long, env = "RENDER_SAMPLE_RATE")]
+    pub render_sample_rate: Option<u32>,
+    #[arg(long, env = "RENDER_MAX_SECONDS")]
+    pub render_max_seconds: Option<u32>,
+    #[arg(long, env = "RENDER_MAX_CONCURRENT")]
+    pub render_max_concurrent: Option<usize>,
+    #[arg(long, env = "SOUND_PACK_DIR")]
+    pub sound_pack_dir: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse {}: {}", path.display(), e),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Build the configuration from the command line, the environment and the config file
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli: Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => Config::default(),
+        };
+        config.apply(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override file settings with flags and environment variables
+    fn apply(&mut self, cli: Cli) {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+
+        set(&mut self.server.host, cli.host);
+        set(&mut self.server.port, cli.port);
+        if cli.admin_token.is_some() {
+            self.server.admin_token = cli.admin_token;
+        }
+        set(
+            &mut self.server.shutdown_timeout_secs,
+            cli.shutdown_timeout_secs,
+        );
+        set(&mut self.storage.data_dir, cli.data_dir);
+        set(
+            &mut self.rooms.idle_timeout_secs,
+            cli.room_idle_timeout_secs,
+        );
+        set(&mut self.rooms.max_connections, cli.room_max_connections);
+        set(&mut self.rooms.max_rooms, cli.max_rooms);
+        set(&mut self.ticks.stats_interval_ms, cli.stats_interval_ms);
+        set(&mut self.ticks.mouse_interval_ms, cli.mouse_interval_ms);
+        set(
+            &mut self.connections.send_queue_capacity,
+            cli.send_queue_capacity,
+        );
+        set(&mut self.connections.hello_timeout_ms, cli.hello_timeout_ms);
+        set(
+            &mut self.connections.min_protocol_version,
+            cli.min_protocol_version,
+        );
+        let limits = &mut self.rate_limits;
+        set(&mut limits.max_frame_bytes, cli.max_frame_bytes);
+        set(&mut limits.mouse_per_sec, cli.mouse_per_sec);
+        set(&mut limits.synthesizer_per_sec, cli.synthesizer_per_sec);
+        set(&mut limits.ip_mouse_per_sec, cli.ip_mouse_per_sec);
+        set(
+            &mut limits.ip_synthesizer_per_sec,
+            cli.ip_synthesizer_per_sec,
+        );
+        set(&mut limits.ip_rooms_per_sec, cli.ip_rooms_per_sec);
+        set(&mut limits.ip_renders_per_sec, cli.ip_renders_per_sec);
+        set(&mut limits.max_violations, cli.max_rate_violations);
+        set(&mut limits.trust_forwarded_for, cli.trust_forwarded_for);
+        set(&mut self.abuse.max_notes_added, cli.max_notes_added);
+        set(&mut self.abuse.max_notes_removed, cli.max_notes_removed);
+        set(
+            &mut self.abuse.mass_delete_min_notes,
+            cli.mass_delete_min_notes,
+        );
+        set(&mut self.song.tracks, cli.song_tracks);
+        set(&mut self.song.pitches, cli.song_pitches);
+        set(&mut self.song.default_bpm, cli.song_default_bpm);
+        set(&mut self.render.sample_rate, cli.render_sample_rate);
+        set(&mut self.render.max_seconds, cli.render_max_seconds);
+        set(&mut self.render.max_concurrent, cli.render_max_concurrent);
+        set(&mut self.render.sound_pack_dir, cli.sound_pack_dir);
+
+        // An empty token, as left by `ADMIN_TOKEN=` in compose files, means no token
+        self.server.admin_token = self
+            .server
+            .admin_token
+            .take()
+            .filter(|token| !token.is_empty());
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| Err(ConfigError::Invalid(reason));
+
+        if self.ticks.stats_interval_ms == 0 || self.ticks.mouse_interval_ms == 0 {
+            return invalid("tick intervals must be positive".to_string());
+        }
+        if self.connections.send_queue_capacity == 0 {
+            return invalid("send_queue_capacity must be positive".to_string());
+        }
+        if self.connections.min_protocol_version > PROTOCOL_VERSION {
+            return invalid(format!(
+                "min_protocol_version is above the server's {}",
+                PROTOCOL_VERSION
+            ));
+        }
+        if self.rate_limits.max_frame_bytes == 0 {
+            return invalid("max_frame_bytes must be positive".to_string());
+        }
+        if self.rate_limits.violation_window_secs == 0 {
+            return invalid("violation_window_secs must be positive".to_string());
+        }
+        for (name, per_sec, burst) in self.rate_limits.buckets() {
+            if !per_sec.is_finite() || per_sec < 0.0 {
+                return invalid(format!("{}_per_sec must not be negative", name));
+            }
+            if per_sec > 0.0 && burst == 0 {
+                return invalid(format!("{}_burst must be positive", name));
+            }
+        }
+        if self.abuse.window_secs == 0 {
+            return invalid("abuse window_secs must be positive".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.abuse.mass_delete_share) {
+            return invalid("mass_delete_share must be within 0..=1".to_string());
+        }
+        if self.song.tracks == 0 {
+            return invalid("a song needs at least one track".to_string());
+        }
+        if !(1..=MAX_PITCHES).contains(&self.song.pitches) {
+            return invalid(format!("pitches must be within 1..={}", MAX_PITCHES));
+        }
+        if !(MIN_BPM..=MAX_BPM).contains(&self.song.default_bpm) {
+            return invalid(format!(
+                "default_bpm must be within {}..={}",
+                MIN_BPM, MAX_BPM
+            ));
+        }
+        if self.song.accent_colors.is_empty() {
+            return invalid("accent_colors must not be empty".to_string());
+        }
+        if !(8000..=192000).contains(&self.render.sample_rate) {
+            return invalid("sample_rate must be within 8000..=192000".to_string());
+        }
+        if self.render.max_seconds == 0 {
+            return invalid("max_seconds must be positive".to_string());
+        }
+        if self.render.max_concurrent == 0 {
+            return invalid("max_concurrent must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_file_keeps_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            port = 8080
+
+            [song]
+            tracks = 8
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.song.tracks, 8);
+        assert_eq!(config.song.pitches, SongDimensions::default().pitches);
+        assert_eq!(config.ticks.mouse_interval_ms, 100);
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        assert!(toml::from_str::<Config>("[server]\nprot = 1").is_err());
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [server]
+            port = 8080
+            admin_token = "from-file"
+
+            [ticks]
+            mouse_interval_ms = 50
+            "#,
+        )
+        .unwrap();
+        config.apply(Cli {
+            port: Some(9000),
+            admin_token: Some(String::new()),
+            ..Cli::default()
+        });
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.admin_token, None);
+        assert_eq!(config.ticks.mouse_interval_ms, 50);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.ticks.mouse_interval_ms = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.song.pitches = MAX_PITCHES + 1;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.rate_limits.ip_mouse_burst = 0;
+        assert!(config.validate().is_err());
+        config.rate_limits.ip_mouse_per_sec = 0.0;
+        assert!(config.validate().is_ok());
+
+        let mut config = Config::default();
+        config.render.sample_rate = 1000;
+        assert!(config.validate().is_err());
+    }
+}
@@ -9,8 +9,9 @@ use uuid::Uuid;
 
 // Re-export all protobuf types
 pub use the_song_protocol::{
-    client_message, server_message, ClientMessage, MousePosition, ServerMessage,
-    ServerMousePositions, ServerStats, ServerStatsUpdate, ServerSynthesizerUpdate, ServerWelcome,
+    client_message, server_message, ClientHello, ClientMessage, ErrorCode, MousePosition,
+    ServerAck, ServerError, ServerMessage, ServerMousePositions, ServerStats, ServerStatsUpdate,
+    ServerSynthesizerUpdate, ServerWelcome, SongShape, PROTOCOL_VERSION, SCHEMA_VERSION,
 };
 
 /// Encode a server message to binary format
@@ -18,6 +19,31 @@ pub fn encode_server_message(msg: &ServerMessage) -> Vec<u8> {
     msg.encode_to_vec()
 }
 
+/// Payload type of an encoded server message, for metrics.
+///
+/// `payload` is the only field of `ServerMessage`, so the first byte is its tag.
+pub fn encoded_payload_type(bytes: &[u8]) -> &'static str {
+    match bytes.first().map(|tag| tag >> 3) {
+        Some(1) => "welcome",
+        Some(2) => "stats",
+        Some(3) => "mouse_positions",
+        Some(4) => "synthesizer_update",
+        Some(5) => "error",
+        Some(6) => "ack",
+        _ => "unknown",
+    }
+}
+
+/// Payload type of a client message, for metrics
+pub fn client_payload_type(msg: &ClientMessage) -> &'static str {
+    match msg.payload {
+        Some(client_message::Payload::MouseUpdate(_)) => "mouse_update",
+        Some(client_message::Payload::SynthesizerUpdate(_)) => "synthesizer_update",
+        Some(client_message::Payload::Hello(_)) => "hello",
+        None => "empty",
+    }
+}
+
 /// Decode a client message from binary format
 pub fn decode_client_message(data: &[u8]) -> Result<ClientMessage, prost::DecodeError> {
     ClientMessage::decode(data)
@@ -28,12 +54,22 @@ pub fn create_welcome_message(
     user_id: Uuid,
     stats: ServerStats,
     synthesizer_snapshot: Vec<u8>,
+    synthesizer_updates: Vec<u8>,
+    song_shape: SongShape,
+    capabilities: Vec<String>,
+    resume_token: Uuid,
 ) -> ServerMessage {
     ServerMessage {
         payload: Some(server_message::Payload::Welcome(ServerWelcome {
             user_id: user_id.to_string(),
             synthesizer_snapshot,
             stats: Some(stats),
+            synthesizer_updates,
+            protocol_version: PROTOCOL_VERSION,
+            schema_version: SCHEMA_VERSION,
+            song_shape: Some(song_shape),
+            capabilities,
+            resume_token: resume_token.to_string(),
         })),
     }
 }
@@ -82,3 +118,62 @@ pub fn create_synthesizer_update_message(data: Vec<u8>) -> ServerMessage {
         )),
     }
 }
+
+/// Helper to create an Error message
+pub fn create_error_message(code: ErrorCode, message: String, seq: u32) -> ServerMessage {
+    ServerMessage {
+        payload: Some(server_message::Payload::Error(ServerError {
+            message,
+            code: code as i32,
+            seq,
+        })),
+    }
+}
+
+/// Helper to create an Ack message
+pub fn create_ack_message(seq: u32) -> ServerMessage {
+    ServerMessage {
+        payload: Some(server_message::Payload::Ack(ServerAck { seq })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoded_payload_type() {
+        let welcome = create_welcome_message(
+            Uuid::now_v7(),
+            ServerStats { online_users: 1 },
+            vec![1],
+            vec![],
+            SongShape {
+                tracks: 16,
+                pitches: 60,
+            },
+            vec![],
+            Uuid::new_v4(),
+        );
+        let cases = [
+            (welcome, "welcome"),
+            (create_stats_message(2), "stats"),
+            (
+                create_mouse_positions_message(HashMap::new()),
+                "mouse_positions",
+            ),
+            (
+                create_synthesizer_update_message(vec![1]),
+                "synthesizer_update",
+            ),
+            (
+                create_error_message(ErrorCode::InvalidUpdate, "nope".to_string(), 1),
+                "error",
+            ),
+            (create_ack_message(1), "ack"),
+        ];
+        for (msg, expected) in cases {
+            assert_eq!(encoded_payload_type(&encode_server_message(&msg)), expected);
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! ABC notation export, a plain text score to paste into chats and issues.
+//!
+//! Each track with notes becomes a voice numbered like the editor's tracks,
+//! written as the slices of the `notation` module with a unit length of a
+//! sixteenth, so a note's length is its count of grid steps.
+
+use std::fmt::Write;
+
+use super::notation::{
+    score_measures, score_tracks, split_length, track_clef, track_slices, Clef, ScoreTooLong,
+    MEASURE,
+};
+use the_song_model::{instrument, Song};
+
+/// Measures per line of music
+const MEASURES_PER_LINE: u32 = 4;
+
+const LETTERS: [(char, bool); 12] = [
+    ('C', false),
+    ('C', true),
+    ('D', false),
+    ('D', true),
+    ('E', false),
+    ('F', false),
+    ('F', true),
+    ('G', false),
+    ('G', true),
+    ('A', false),
+    ('A', true),
+    ('B', false),
+];
+
+/// ABC pitch of a MIDI key, black keys spelled as sharps.
+///
+/// Sharps last until the barline in ABC, so a natural whose letter is in
+/// `sharpened` gets an explicit natural sign. The letter of a sharp is added to it.
+fn pitch(key: u8, sharpened: &mut Vec<char>) -> String {
+    let (letter, sharp) = LETTERS[key as usize % 12];
+    let mut pitch = String::new();
+    if sharp {
+        pitch.push('^');
+        sharpened.push(letter);
+    } else if sharpened.contains(&letter) {
+        pitch.push('=');
+    }
+    // C is middle C, c the octave above
+    let octave = key as i32 / 12 - 1;
+    if octave >= 5 {
+        pitch.push(letter.to_ascii_lowercase());
+        pitch.extend(std::iter::repeat_n('\'', (octave - 5) as usize));
+    } else {
+        pitch.push(letter);
+        pitch.extend(std::iter::repeat_n(',', (4 - octave) as usize));
+    }
+    pitch
+}
+
+/// Length in units of a sixteenth, a single unit is left out
+fn length(steps: u32) -> String {
+    if steps == 1 {
+        String::new()
+    } else {
+        steps.to_string()
+    }
+}
+
+/// Write the song as an ABC 2.1 tune
+pub fn write_abc(song: &Song) -> Result<String, ScoreTooLong> {
+    let measures = score_measures(song)?;
+    let tracks = score_tracks(song);
+
+    let mut abc = String::new();
+    abc.push_str("X:1\n");
+    abc.push_str("T:THE SONG\n");
+    abc.push_str("M:4/4\n");
+    abc.push_str("L:1/16\n");
+    let _ = writeln!(abc, "Q:1/4={}", song.bpm.round());
+    for &track in &tracks {
+        let clef = match track_clef(song, track) {
+            Clef::Treble => "treble",
+            Clef::Bass => "bass",
+            Clef::Percussion => "perc",
+        };
+        let _ = writeln!(
+            abc,
+            "V:{} name=\"{}\" clef={}",
+            track + 1,
+            instrument(track).name,
+            clef
+        );
+    }
+    abc.push_str("K:C\n");
+
+    for &track in &tracks {
+        let _ = writeln!(abc, "V:{}", track + 1);
+        match instrument(track).program {
+            Some(program) => {
+                let _ = writeln!(abc, "%%MIDI program {}", program);
+            }
+            None => abc.push_str("%%MIDI channel 10\n"),
+        }
+        write_voice(&mut abc, song, track, measures);
+    }
+    Ok(abc)
+}
+
+fn write_voice(abc: &mut String, song: &Song, track: usize, measures: u32) {
+    let mut line = Vec::new();
+    let mut sharpened = Vec::new();
+    for slice in track_slices(song.track_notes(track), measures) {
+        if slice.start > 0 && slice.start % MEASURE == 0 {
+            sharpened.clear();
+            let measure = slice.start / MEASURE;
+            if measure.is_multiple_of(MEASURES_PER_LINE) {
+                line.push("|".to_string());
+                let _ = writeln!(abc, "{}", line.join(" "));
+                line.clear();
+            } else {
+                line.push("|".to_string());
+            }
+        }
+
+        let lengths = split_length(slice.length());
+        let last = lengths.len() - 1;
+        for (i, steps) in lengths.into_iter().enumerate() {
+            let notes: Vec<String> = slice
+                .keys
+                .iter()
+                .map(|&(key, tied)| {
+                    let tie = if i < last || tied { "-" } else { "" };
+                    format!("{}{}{}", pitch(key, &mut sharpened), length(steps), tie)
+                })
+                .collect();
+            line.push(match notes.len() {
+                0 => format!("z{}", length(steps)),
+                1 => notes.into_iter().next().unwrap_or_default(),
+                _ => format!("[{}]", notes.concat()),
+            });
+        }
+    }
+    line.push("|]".to_string());
+    let _ = writeln!(abc, "{}", line.join(" "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::notation::fixtures::{note, song, song_from_steps, songs, summary};
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Read back the notes of a tune as `write_abc` writes it
+    fn read_abc(abc: &str) -> Song {
+        let mut bpm = 0.0;
+        let mut notes = Vec::new();
+        let mut body = false;
+        let mut track = 0;
+        let mut cursor = 0;
+        // Start of every tied note by its key
+        let mut tied: HashMap<u8, u32> = HashMap::new();
+
+        for line in abc.lines() {
+            if let Some(tempo) = line.strip_prefix("Q:1/4=") {
+                bpm = tempo.parse().unwrap();
+            } else if line.starts_with("K:") {
+                body = true;
+            } else if let Some(voice) = line.strip_prefix("V:").filter(|_| body) {
+                track = voice.parse::<usize>().unwrap() - 1;
+                cursor = 0;
+            } else if body && !line.starts_with('%') {
+                for token in line.split_whitespace() {
+                    if token.starts_with('|') {
+                        continue;
+                    }
+                    if let Some(rest) = token.strip_prefix('z') {
+                        cursor += rest.parse().unwrap_or(1);
+                        continue;
+                    }
+                    let mut length = 0;
+                    for (key, steps, tie) in read_notes(token.trim_matches(['[', ']'])) {
+                        let start = tied.remove(&key).unwrap_or(cursor);
+                        if tie {
+                            tied.insert(key, start);
+                        } else {
+                            notes.push((track, key, start, cursor + steps - start));
+                        }
+                        length = steps;
+                    }
+                    cursor += length;
+                }
+            }
+        }
+        song_from_steps(bpm, notes)
+    }
+
+    /// Key, length and tie of the notes in `notes`
+    fn read_notes(notes: &str) -> Vec<(u8, u32, bool)> {
+        let mut read = Vec::new();
+        let mut chars = notes.chars().peekable();
+        while let Some(mut c) = chars.next() {
+            let mut sharp = false;
+            if c == '^' || c == '=' {
+                sharp = c == '^';
+                c = chars.next().unwrap();
+            }
+            let semitone = LETTERS
+                .iter()
+                .position(|(letter, _)| *letter == c.to_ascii_uppercase())
+                .unwrap() as i32;
+            let mut octave = if c.is_ascii_lowercase() { 5 } else { 4 };
+            let mut digits = String::new();
+            let mut tie = false;
+            while let Some(&next) = chars.peek() {
+                match next {
+                    '\'' => octave += 1,
+                    ',' => octave -= 1,
+                    '-' => tie = true,
+                    '0'..='9' => digits.push(next),
+                    _ => break,
+                }
+                chars.next();
+            }
+            let key = (octave + 1) * 12 + semitone + i32::from(sharp);
+            read.push((key as u8, digits.parse().unwrap_or(1), tie));
+        }
+        read
+    }
+
+    #[test]
+    fn test_pitch() {
+        let mut sharpened = Vec::new();
+        assert_eq!(pitch(60, &mut sharpened), "C");
+        assert_eq!(pitch(72, &mut sharpened), "c");
+        assert_eq!(pitch(95, &mut sharpened), "b'");
+        assert_eq!(pitch(36, &mut sharpened), "C,,");
+        assert_eq!(pitch(61, &mut sharpened), "^C");
+        // The sharp lasts the bar, so a later C needs a natural
+        assert_eq!(pitch(48, &mut sharpened), "=C,");
+    }
+
+    #[test]
+    fn test_write_abc() {
+        let abc = write_abc(&song(
+            90.0,
+            vec![
+                note(0, 24, 0.0, 1.0),
+                note(0, 28, 0.0, 1.0),
+                note(3, 0, 3.5, 1.0),
+            ],
+        ))
+        .unwrap();
+        assert!(abc.contains("Q:1/4=90\n"));
+        assert!(abc.contains("V:1 name=\"Acoustic Piano\" clef=treble\n"));
+        assert!(abc.contains("V:4 name=\"Acoustic Drum Kit\" clef=perc\n"));
+        assert!(abc.contains("V:1\n%%MIDI program 0\n[C4E4] z12 | z16 |]\n"));
+        // Tied over the barline
+        assert!(abc.contains("V:4\n%%MIDI channel 10\nz12 z2 C,,2- | C,,2 z12 z2 |]\n"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for song in songs() {
+            let abc = write_abc(&song).unwrap();
+            let read = read_abc(&abc);
+            assert_eq!(read.bpm, song.bpm);
+            assert_eq!(summary(&read), summary(&song), "{}", abc);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! LilyPond export, a text score that engraves to PDF and plays back as MIDI.
+//!
+//! Each track with notes becomes a staff holding a single voice, written as the
+//! slices of the `notation` module in absolute pitches, one measure per line.
+
+use std::fmt::Write;
+
+use super::notation::{
+    score_measures, score_tracks, split_length, track_clef, track_slices, Clef, ScoreTooLong,
+    MEASURE,
+};
+use the_song_model::{instrument, Song};
+
+const NAMES: [&str; 12] = [
+    "c", "cis", "d", "dis", "e", "f", "fis", "g", "gis", "a", "ais", "b",
+];
+
+/// Durations of the lengths a single note symbol can show, in grid steps
+const DURATIONS: [(u32, &str); 8] = [
+    (16, "1"),
+    (12, "2."),
+    (8, "2"),
+    (6, "4."),
+    (4, "4"),
+    (3, "8."),
+    (2, "8"),
+    (1, "16"),
+];
+
+/// Absolute pitch of a MIDI key, black keys spelled as sharps
+fn pitch(key: u8) -> String {
+    let mut pitch = NAMES[key as usize % 12].to_string();
+    // c is the C below middle C
+    let octave = key as i32 / 12 - 1;
+    if octave > 3 {
+        pitch.extend(std::iter::repeat_n('\'', (octave - 3) as usize));
+    } else {
+        pitch.extend(std::iter::repeat_n(',', (3 - octave) as usize));
+    }
+    pitch
+}
+
+fn duration(steps: u32) -> &'static str {
+    DURATIONS
+        .iter()
+        .find(|(length, _)| *length == steps)
+        .map(|(_, duration)| *duration)
+        .expect("lengths come from split_length")
+}
+
+/// Write the song as a LilyPond score with a layout and a MIDI block
+pub fn write_lilypond(song: &Song) -> Result<String, ScoreTooLong> {
+    let measures = score_measures(song)?;
+    let tracks = score_tracks(song);
+
+    let mut ly = String::new();
+    ly.push_str("\\version \"2.24.0\"\n\n");
+    ly.push_str("\\header {\n  title = \"THE SONG\"\n  tagline = ##f\n}\n\n");
+    ly.push_str("\\score {\n  <<\n");
+    for (index, &track) in tracks.iter().enumerate() {
+        let _ = writeln!(
+            ly,
+            "    \\new Staff = \"track{}\" \\with {{ instrumentName = \"{}\" }} {{",
+            track + 1,
+            instrument(track).name
+        );
+        let clef = match track_clef(song, track) {
+            Clef::Treble => "treble",
+            Clef::Bass => "bass",
+            Clef::Percussion => "percussion",
+        };
+        let _ = writeln!(ly, "      \\clef {}", clef);
+        ly.push_str("      \\time 4/4\n");
+        if index == 0 {
+            let _ = writeln!(ly, "      \\tempo 4 = {}", song.bpm.round());
+        }
+        write_staff(&mut ly, song, track, measures);
+        ly.push_str("    }\n");
+    }
+    ly.push_str("  >>\n  \\layout { }\n  \\midi { }\n}\n");
+    Ok(ly)
+}
+
+fn write_staff(ly: &mut String, song: &Song, track: usize, measures: u32) {
+    let mut measure = Vec::new();
+    for slice in track_slices(song.track_notes(track), measures) {
+        if slice.start > 0 && slice.start % MEASURE == 0 {
+            let _ = writeln!(ly, "      {} |", measure.join(" "));
+            measure.clear();
+        }
+
+        let lengths = split_length(slice.length());
+        let last = lengths.len() - 1;
+        for (i, steps) in lengths.into_iter().enumerate() {
+            let tie = |tied: bool| if i < last || tied { "~" } else { "" };
+            let duration = duration(steps);
+            measure.push(match slice.keys.as_slice() {
+                [] if steps == MEASURE => "R1".to_string(),
+                [] => format!("r{}", duration),
+                [(key, tied)] => format!("{}{}{}", pitch(*key), duration, tie(*tied)),
+                keys => {
+                    let notes: Vec<String> = keys
+                        .iter()
+                        .map(|(key, tied)| format!("{}{}", pitch(*key), tie(*tied)))
+                        .collect();
+                    format!("<{}>{}", notes.join(" "), duration)
+                }
+            });
+        }
+    }
+    let _ = writeln!(ly, "      {} |", measure.join(" "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::notation::fixtures::{note, song, song_from_steps, songs, summary};
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Read back the notes of a score as `write_lilypond` writes it
+    fn read_lilypond(ly: &str) -> Song {
+        let mut bpm = 0.0;
+        let mut notes = Vec::new();
+        let mut track = 0;
+        let mut cursor = 0;
+        // Start of every tied note by its key
+        let mut tied: HashMap<u8, u32> = HashMap::new();
+
+        for line in ly.lines().map(str::trim) {
+            if let Some(tempo) = line.strip_prefix("\\tempo 4 = ") {
+                bpm = tempo.parse().unwrap();
+            } else if let Some(staff) = line.strip_prefix("\\new Staff = \"track") {
+                let number = staff.split('"').next().unwrap();
+                track = number.parse::<usize>().unwrap() - 1;
+                cursor = 0;
+            } else if let Some(music) = line.strip_suffix('|') {
+                // Keep the notes of a chord together
+                let mut events: Vec<String> = Vec::new();
+                for token in music.split_whitespace() {
+                    match events.last_mut() {
+                        Some(chord) if chord.starts_with('<') && !chord.contains('>') => {
+                            chord.push(' ');
+                            chord.push_str(token);
+                        }
+                        _ => events.push(token.to_string()),
+                    }
+                }
+                for event in events {
+                    if event == "R1" {
+                        cursor += MEASURE;
+                        continue;
+                    }
+                    if let Some(rest) = event.strip_prefix('r') {
+                        cursor += read_duration(rest);
+                        continue;
+                    }
+                    let (keys, steps) = match event.strip_prefix('<') {
+                        Some(chord) => {
+                            let (keys, duration) = chord.split_once('>').unwrap();
+                            let keys: Vec<_> = keys.split(' ').map(read_pitch).collect();
+                            (keys, read_duration(duration))
+                        }
+                        None => {
+                            let split = event.find(|c: char| c.is_ascii_digit()).unwrap();
+                            let key = read_pitch(&event[..split]).0;
+                            let duration = event[split..].trim_end_matches('~');
+                            (vec![(key, event.ends_with('~'))], read_duration(duration))
+                        }
+                    };
+                    for (key, tie) in keys {
+                        let start = tied.remove(&key).unwrap_or(cursor);
+                        if tie {
+                            tied.insert(key, start);
+                        } else {
+                            notes.push((track, key, start, cursor + steps - start));
+                        }
+                    }
+                    cursor += steps;
+                }
+            }
+        }
+        song_from_steps(bpm, notes)
+    }
+
+    /// Key and tie of a pitch like `cis''~`
+    fn read_pitch(pitch: &str) -> (u8, bool) {
+        let name = pitch.trim_end_matches(['\'', ',', '~']);
+        let semitone = NAMES.iter().position(|n| *n == name).unwrap() as i32;
+        let octave = 3 + pitch.matches('\'').count() as i32 - pitch.matches(',').count() as i32;
+        (((octave + 1) * 12 + semitone) as u8, pitch.ends_with('~'))
+    }
+
+    fn read_duration(duration: &str) -> u32 {
+        DURATIONS
+            .iter()
+            .find(|(_, text)| *text == duration)
+            .map(|(steps, _)| *steps)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pitch() {
+        assert_eq!(pitch(60), "c'");
+        assert_eq!(pitch(48), "c");
+        assert_eq!(pitch(36), "c,");
+        assert_eq!(pitch(95), "b'''");
+        assert_eq!(pitch(61), "cis'");
+    }
+
+    #[test]
+    fn test_write_lilypond() {
+        let ly = write_lilypond(&song(
+            90.0,
+            vec![
+                note(0, 24, 0.0, 1.0),
+                note(0, 28, 0.0, 1.0),
+                note(6, 0, 3.5, 1.0),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(ly.matches("\\new Staff").count(), 2);
+        assert_eq!(ly.matches("\\tempo 4 = 90").count(), 1);
+        assert!(
+            ly.contains("\\new Staff = \"track7\" \\with { instrumentName = \"Electric Bass\" }")
+        );
+        assert!(ly.contains("\\clef bass\n"));
+        assert!(ly.contains("      <c' e'>4 r2. |\n      R1 |\n"));
+        // Tied over the barline
+        assert!(ly.contains("      r2. r8 c,8~ |\n      c,8 r2. r8 |\n"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for song in songs() {
+            let ly = write_lilypond(&song).unwrap();
+            let read = read_lilypond(&ly);
+            assert_eq!(read.bpm, song.bpm);
+            assert_eq!(summary(&read), summary(&song), "{}", ly);
+        }
+    }
+}
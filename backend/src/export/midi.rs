@@ -0,0 +1,274 @@
+//! Standard MIDI File export.
+//!
+//! The song is written as a Type-1 file: a conductor track carrying the tempo
+//! followed by one MIDI track per song track.
+
+use midly::{
+    num::{u15, u24, u28, u4, u7},
+    Format, Header, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind,
+};
+
+use the_song_model::{instrument, Song};
+
+/// Resolution of the exported file
+pub const TICKS_PER_BEAT: u16 = 480;
+
+/// General MIDI percussion channel (channel 10, 0-based)
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// MIDI channel a track is played on.
+///
+/// Drum kits share the percussion channel, every other track gets its own
+/// channel in track order, skipping the percussion channel. Songs with more
+/// melodic tracks than channels reuse them from the start.
+pub fn track_channel(track: usize) -> u8 {
+    if instrument(track).program.is_none() {
+        return DRUM_CHANNEL;
+    }
+    let melodic_before = (0..track)
+        .filter(|track| instrument(*track).program.is_some())
+        .count()
+        % 15;
+    let channel = melodic_before as u8;
+    if channel >= DRUM_CHANNEL {
+        channel + 1
+    } else {
+        channel
+    }
+}
+
+/// Last tick a delta time can reach from the start of a track
+const MAX_TICK: u32 = (1 << 28) - 1;
+
+/// Absolute tick of a beat, songs longer than a file can hold are cut at the
+/// last tick
+fn beats_to_ticks(beats: f64) -> u32 {
+    ((beats * TICKS_PER_BEAT as f64).round() as u32).min(MAX_TICK)
+}
+
+/// Turn events at absolute ticks into a track with delta times
+fn into_track(mut events: Vec<(u32, TrackEventKind<'static>)>) -> Track<'static> {
+    // Stable sort keeps the order of events that share a tick
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut track = Vec::with_capacity(events.len() + 1);
+    let mut last_tick = 0;
+    for (tick, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::new(tick - last_tick),
+            kind,
+        });
+        last_tick = tick;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+fn conductor_track(song: &Song) -> Track<'static> {
+    let micros_per_beat = (60_000_000.0 / song.bpm).round() as u32;
+    into_track(vec![
+        (0, TrackEventKind::Meta(MetaMessage::TrackName(b"THE SONG"))),
+        (
+            0,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(micros_per_beat))),
+        ),
+        (
+            0,
+            TrackEventKind::Meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
+        ),
+    ])
+}
+
+fn note_track(song: &Song, track: usize) -> Track<'static> {
+    let instrument = instrument(track);
+    let channel = u4::new(track_channel(track));
+
+    let mut events = vec![(
+        0,
+        TrackEventKind::Meta(MetaMessage::TrackName(instrument.name.as_bytes())),
+    )];
+    if let Some(program) = instrument.program {
+        events.push((
+            0,
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::ProgramChange {
+                    program: u7::new(program),
+                },
+            },
+        ));
+    }
+
+    let mut notes = Vec::new();
+    for note in song.track_notes(track) {
+        let key = u7::new(note.midi_key());
+        // Leave room for the note-off of a note cut at the last tick
+        let start = beats_to_ticks(note.start).min(MAX_TICK - 1);
+        let end = beats_to_ticks(note.end()).max(start.saturating_add(1));
+        // A note-on with velocity 0 would be read as a note-off
+        let vel = u7::new(note.velocity.max(1));
+        notes.push((
+            end,
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOff {
+                    key,
+                    vel: u7::new(0),
+                },
+            },
+        ));
+        notes.push((
+            start,
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn { key, vel },
+            },
+        ));
+    }
+    // Note-offs first so a repeated key is released before it is struck again
+    notes.sort_by_key(|(tick, kind)| {
+        let is_note_on = matches!(
+            kind,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { .. },
+                ..
+            }
+        );
+        (*tick, is_note_on)
+    });
+    events.extend(notes);
+
+    into_track(events)
+}
+
+/// Encode the song as a Type-1 Standard MIDI File
+pub fn write_midi(song: &Song) -> std::io::Result<Vec<u8>> {
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(TICKS_PER_BEAT)),
+    ));
+    smf.tracks.push(conductor_track(song));
+    for track in 0..song.dimensions.tracks {
+        smf.tracks.push(note_track(song, track));
+    }
+
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use the_song_model::{Note, SongDimensions};
+
+    fn note(pitch: usize, start: f64, duration: f64, track: usize) -> Note {
+        Note {
+            id: format!("{}-{}", pitch, start),
+            pitch,
+            start,
+            duration,
+            velocity: 100,
+            track,
+            created_by: "user".to_string(),
+            created_at: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_track_channels() {
+        assert_eq!(track_channel(0), 0);
+        assert_eq!(track_channel(3), DRUM_CHANNEL);
+        assert_eq!(track_channel(5), DRUM_CHANNEL);
+        // Tracks past the instrument list wrap around the channels too
+        let melodic: Vec<u8> = (0..40)
+            .filter(|track| instrument(*track).program.is_some())
+            .map(track_channel)
+            .collect();
+        assert!(!melodic.contains(&DRUM_CHANNEL));
+        assert!(melodic.iter().all(|channel| *channel < 16));
+    }
+
+    #[test]
+    fn test_write_midi() {
+        let song = Song {
+            bpm: 100.0,
+            dimensions: SongDimensions::default(),
+            notes: vec![
+                note(0, 0.0, 1.0, 0),
+                note(0, 1.0, 0.5, 0),
+                note(12, 2.0, 1.0, 6),
+            ],
+        };
+        let bytes = write_midi(&song).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf.tracks.len(), song.dimensions.tracks + 1);
+        assert!(
+            smf.tracks[0]
+                .iter()
+                .any(|event| event.kind
+                    == TrackEventKind::Meta(MetaMessage::Tempo(u24::new(600_000))))
+        );
+
+        let note_ons = |track: &Track| {
+            let mut tick = 0;
+            let mut ons = Vec::new();
+            for event in track {
+                tick += event.delta.as_int();
+                if let TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, .. },
+                    ..
+                } = event.kind
+                {
+                    ons.push((tick, key.as_int()));
+                }
+            }
+            ons
+        };
+        assert_eq!(note_ons(&smf.tracks[1]), vec![(0, 36), (480, 36)]);
+        assert_eq!(note_ons(&smf.tracks[7]), vec![(960, 48)]);
+        assert!(note_ons(&smf.tracks[2]).is_empty());
+    }
+
+    #[test]
+    fn test_notes_past_the_last_tick() {
+        let song = Song {
+            bpm: 120.0,
+            dimensions: SongDimensions::default(),
+            notes: vec![note(0, 1.0, 1.0, 0), note(0, 1e12, 1e300, 0)],
+        };
+        let bytes = write_midi(&song).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+
+        let mut tick = 0;
+        let mut events = Vec::new();
+        for event in &smf.tracks[1] {
+            tick += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { .. },
+                    ..
+                } => events.push((tick, true)),
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOff { .. },
+                    ..
+                } => events.push((tick, false)),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                (480, true),
+                (960, false),
+                (MAX_TICK - 1, true),
+                (MAX_TICK, false)
+            ]
+        );
+    }
+}
@@ -0,0 +1,10 @@
+//! Converters from the song to file formats other tools understand.
+
+pub mod abc;
+pub mod lilypond;
+pub mod midi;
+pub mod musicxml;
+pub mod notation;
+pub mod stems;
+pub mod wav;
+pub mod zip;
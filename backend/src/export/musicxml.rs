@@ -0,0 +1,447 @@
+//! MusicXML export for notation software.
+//!
+//! Every track with notes becomes a part on the grid of the `notation` module.
+//! Notes that share a start and end become chords, and overlapping notes go to
+//! further voices. Notes crossing a
+//! barline, or lasting a value that has no single note symbol, are split into
+//! tied notes.
+
+use std::fmt::Write;
+
+use super::midi::track_channel;
+use super::notation::{
+    quantize, score_measures, score_tracks, split_length, track_clef, Clef, ScoreTooLong,
+    BEATS_PER_BAR, DIVISIONS, MEASURE,
+};
+use the_song_model::{instrument, Note, Song};
+
+/// Type and dot of every length a single note symbol can show
+const VALUES: [(u32, &str, bool); 8] = [
+    (16, "whole", false),
+    (12, "half", true),
+    (8, "half", false),
+    (6, "quarter", true),
+    (4, "quarter", false),
+    (3, "eighth", true),
+    (2, "eighth", false),
+    (1, "16th", false),
+];
+
+const STEPS: [(&str, u8); 12] = [
+    ("C", 0),
+    ("C", 1),
+    ("D", 0),
+    ("D", 1),
+    ("E", 0),
+    ("F", 0),
+    ("F", 1),
+    ("G", 0),
+    ("G", 1),
+    ("A", 0),
+    ("A", 1),
+    ("B", 0),
+];
+
+/// Step, alteration and octave of a MIDI key, black keys spelled as sharps
+fn spell(key: u8) -> (&'static str, u8, i32) {
+    let (step, alter) = STEPS[key as usize % 12];
+    (step, alter, key as i32 / 12 - 1)
+}
+
+/// Split a length in grid steps into notated values, longest first
+fn split_values(length: u32) -> Vec<(u32, &'static str, bool)> {
+    split_length(length)
+        .into_iter()
+        .filter_map(|steps| VALUES.into_iter().find(|value| value.0 == steps))
+        .collect()
+}
+
+/// Notes struck and released together, positions in grid steps
+struct Chord {
+    start: u32,
+    end: u32,
+    /// Keys with their velocities
+    keys: Vec<(u8, u8)>,
+}
+
+/// Group the quantized notes of a track into chords and the chords into voices
+fn voices<'a>(notes: impl Iterator<Item = &'a Note>) -> Vec<Vec<Chord>> {
+    let mut notes: Vec<_> = notes
+        .map(|note| {
+            let start = quantize(note.start);
+            let end = quantize(note.end()).max(start.saturating_add(1));
+            (start, end, note.midi_key(), note.velocity)
+        })
+        .collect();
+    // Longer notes first so they take the lower voices
+    notes.sort_by_key(|(start, end, key, _)| (*start, std::cmp::Reverse(*end), *key));
+
+    let mut voices: Vec<Vec<Chord>> = Vec::new();
+    for (start, end, key, velocity) in notes {
+        let same_chord = voices.iter_mut().find_map(|voice| {
+            voice
+                .last_mut()
+                .filter(|chord| chord.start == start && chord.end == end)
+        });
+        if let Some(chord) = same_chord {
+            if !chord.keys.iter().any(|(chord_key, _)| *chord_key == key) {
+                chord.keys.push((key, velocity));
+            }
+            continue;
+        }
+
+        let chord = Chord {
+            start,
+            end,
+            keys: vec![(key, velocity)],
+        };
+        match voices
+            .iter_mut()
+            .find(|voice| voice.last().is_none_or(|last| last.end <= start))
+        {
+            Some(voice) => voice.push(chord),
+            None => voices.push(vec![chord]),
+        }
+    }
+    voices
+}
+
+/// Write the song as an uncompressed MusicXML 4.0 partwise score.
+///
+/// A song without notes still gets the first track as an empty part, a score
+/// needs at least one.
+pub fn write_musicxml(song: &Song) -> Result<String, ScoreTooLong> {
+    let measures = score_measures(song)?;
+    let tracks = score_tracks(song);
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    xml.push_str(
+        "<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \
+         \"http://www.musicxml.org/dtds/partwise.dtd\">\n",
+    );
+    xml.push_str("<score-partwise version=\"4.0\">\n");
+    xml.push_str("  <work><work-title>THE SONG</work-title></work>\n");
+    xml.push_str(
+        "  <identification><encoding><software>THE SONG</software></encoding></identification>\n",
+    );
+
+    xml.push_str("  <part-list>\n");
+    for &track in &tracks {
+        write_score_part(&mut xml, track);
+    }
+    xml.push_str("  </part-list>\n");
+
+    for (index, &track) in tracks.iter().enumerate() {
+        let _ = writeln!(xml, "  <part id=\"{}\">", part_id(track));
+        let voices = voices(song.track_notes(track));
+        for measure in 0..measures {
+            let _ = writeln!(xml, "    <measure number=\"{}\">", measure + 1);
+            if measure == 0 {
+                write_attributes(&mut xml, song, track);
+                if index == 0 {
+                    write_tempo(&mut xml, song.bpm);
+                }
+            }
+            write_measure(&mut xml, track, &voices, measure);
+            xml.push_str("    </measure>\n");
+        }
+        xml.push_str("  </part>\n");
+    }
+    xml.push_str("</score-partwise>\n");
+    Ok(xml)
+}
+
+fn part_id(track: usize) -> String {
+    format!("P{}", track + 1)
+}
+
+fn is_drums(track: usize) -> bool {
+    instrument(track).program.is_none()
+}
+
+fn write_score_part(xml: &mut String, track: usize) {
+    let id = part_id(track);
+    let name = escape(instrument(track).name);
+    let _ = writeln!(xml, "    <score-part id=\"{}\">", id);
+    let _ = writeln!(xml, "      <part-name>{}</part-name>", name);
+    let _ = writeln!(
+        xml,
+        "      <score-instrument id=\"{}-I1\"><instrument-name>{}</instrument-name></score-instrument>",
+        id, name
+    );
+    let _ = write!(
+        xml,
+        "      <midi-instrument id=\"{}-I1\"><midi-channel>{}</midi-channel>",
+        id,
+        track_channel(track) + 1
+    );
+    if let Some(program) = instrument(track).program {
+        let _ = write!(xml, "<midi-program>{}</midi-program>", program + 1);
+    }
+    xml.push_str("</midi-instrument>\n");
+    xml.push_str("    </score-part>\n");
+}
+
+fn write_attributes(xml: &mut String, song: &Song, track: usize) {
+    let _ = write!(
+        xml,
+        "      <attributes><divisions>{}</divisions><key><fifths>0</fifths></key>\
+         <time><beats>{}</beats><beat-type>4</beat-type></time>",
+        DIVISIONS, BEATS_PER_BAR
+    );
+    let clef = match track_clef(song, track) {
+        Clef::Treble => "<sign>G</sign><line>2</line>",
+        Clef::Bass => "<sign>F</sign><line>4</line>",
+        Clef::Percussion => "<sign>percussion</sign>",
+    };
+    let _ = writeln!(xml, "<clef>{}</clef></attributes>", clef);
+}
+
+fn write_tempo(xml: &mut String, bpm: f64) {
+    let _ = writeln!(
+        xml,
+        "      <direction placement=\"above\"><direction-type><metronome>\
+         <beat-unit>quarter</beat-unit><per-minute>{}</per-minute></metronome>\
+         </direction-type><sound tempo=\"{}\"/></direction>",
+        bpm.round(),
+        bpm
+    );
+}
+
+fn write_measure(xml: &mut String, track: usize, voices: &[Vec<Chord>], measure: u32) {
+    let measure_start = measure * MEASURE;
+    let measure_end = measure_start + MEASURE;
+
+    let mut written = false;
+    for (index, voice) in voices.iter().enumerate() {
+        let chords: Vec<_> = voice
+            .iter()
+            .filter(|chord| chord.end > measure_start && chord.start < measure_end)
+            .collect();
+        // Only the first voice fills empty measures with a rest
+        if chords.is_empty() && index > 0 {
+            continue;
+        }
+        if written {
+            let _ = writeln!(
+                xml,
+                "      <backup><duration>{}</duration></backup>",
+                MEASURE
+            );
+        }
+        written = true;
+
+        let voice = index + 1;
+        if chords.is_empty() {
+            write_measure_rest(xml, voice);
+            continue;
+        }
+        let mut cursor = measure_start;
+        for chord in chords {
+            let start = chord.start.max(measure_start);
+            let end = chord.end.min(measure_end);
+            write_rests(xml, voice, start - cursor);
+
+            let values = split_values(end - start);
+            let last = values.len() - 1;
+            for (i, value) in values.into_iter().enumerate() {
+                let tie_stop = i > 0 || chord.start < measure_start;
+                let tie_start = i < last || chord.end > measure_end;
+                for (n, &key) in chord.keys.iter().enumerate() {
+                    write_note(xml, track, voice, key, value, n > 0, (tie_stop, tie_start));
+                }
+            }
+            cursor = end;
+        }
+        write_rests(xml, voice, measure_end - cursor);
+    }
+    if !written {
+        write_measure_rest(xml, 1);
+    }
+}
+
+fn write_measure_rest(xml: &mut String, voice: usize) {
+    let _ = writeln!(
+        xml,
+        "      <note><rest measure=\"yes\"/><duration>{}</duration><voice>{}</voice></note>",
+        MEASURE, voice
+    );
+}
+
+fn write_rests(xml: &mut String, voice: usize, length: u32) {
+    for (steps, kind, dot) in split_values(length) {
+        let _ = writeln!(
+            xml,
+            "      <note><rest/><duration>{}</duration><voice>{}</voice><type>{}</type>{}</note>",
+            steps,
+            voice,
+            kind,
+            if dot { "<dot/>" } else { "" }
+        );
+    }
+}
+
+fn write_note(
+    xml: &mut String,
+    track: usize,
+    voice: usize,
+    (key, velocity): (u8, u8),
+    (steps, kind, dot): (u32, &str, bool),
+    in_chord: bool,
+    (tie_stop, tie_start): (bool, bool),
+) {
+    // MusicXML dynamics are a percentage of velocity 90, forte
+    let dynamics = velocity as f64 / 90.0 * 100.0;
+    let _ = write!(xml, "      <note dynamics=\"{:.2}\">", dynamics);
+    if in_chord {
+        xml.push_str("<chord/>");
+    }
+    let (step, alter, octave) = spell(key);
+    if is_drums(track) {
+        let _ = write!(
+            xml,
+            "<unpitched><display-step>{}</display-step><display-octave>{}</display-octave></unpitched>",
+            step, octave
+        );
+    } else {
+        let alter = if alter != 0 {
+            format!("<alter>{}</alter>", alter)
+        } else {
+            String::new()
+        };
+        let _ = write!(
+            xml,
+            "<pitch><step>{}</step>{}<octave>{}</octave></pitch>",
+            step, alter, octave
+        );
+    }
+    let _ = write!(xml, "<duration>{}</duration>", steps);
+    if tie_stop {
+        xml.push_str("<tie type=\"stop\"/>");
+    }
+    if tie_start {
+        xml.push_str("<tie type=\"start\"/>");
+    }
+    if is_drums(track) {
+        let _ = write!(xml, "<instrument id=\"{}-I1\"/>", part_id(track));
+    }
+    let _ = write!(xml, "<voice>{}</voice><type>{}</type>", voice, kind);
+    if dot {
+        xml.push_str("<dot/>");
+    }
+    if alter != 0 && !is_drums(track) {
+        xml.push_str("<accidental>sharp</accidental>");
+    }
+    if tie_stop || tie_start {
+        xml.push_str("<notations>");
+        if tie_stop {
+            xml.push_str("<tied type=\"stop\"/>");
+        }
+        if tie_start {
+            xml.push_str("<tied type=\"start\"/>");
+        }
+        xml.push_str("</notations>");
+    }
+    xml.push_str("</note>\n");
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::notation::MAX_MEASURES;
+    use super::*;
+    use the_song_model::SongDimensions;
+
+    fn note(track: usize, pitch: usize, start: f64, duration: f64) -> Note {
+        Note {
+            id: format!("{}-{}-{}", track, pitch, start),
+            pitch,
+            start,
+            duration,
+            velocity: 90,
+            track,
+            created_by: String::new(),
+            created_at: 0.0,
+        }
+    }
+
+    fn song(notes: Vec<Note>) -> Song {
+        Song {
+            bpm: 120.0,
+            dimensions: SongDimensions::default(),
+            notes,
+        }
+    }
+
+    #[test]
+    fn test_spelling_and_values() {
+        assert_eq!(spell(60), ("C", 0, 4));
+        assert_eq!(spell(61), ("C", 1, 4));
+        assert_eq!(spell(36), ("C", 0, 2));
+        assert_eq!(
+            split_values(7),
+            vec![(6, "quarter", true), (1, "16th", false)]
+        );
+        assert_eq!(split_values(16), vec![(16, "whole", false)]);
+    }
+
+    #[test]
+    fn test_one_part_per_track_with_notes() {
+        // Two notes a hair apart land on the same grid step and make a chord
+        let xml = write_musicxml(&song(vec![
+            note(0, 24, 0.0, 1.0),
+            note(0, 28, 0.02, 1.0),
+            note(6, 0, 0.0, 4.0),
+        ]))
+        .unwrap();
+        assert_eq!(xml.matches("<score-part ").count(), 2);
+        assert!(xml.contains("<part id=\"P1\">"));
+        assert!(xml.contains("<part id=\"P7\">"));
+        assert_eq!(xml.matches("<chord/>").count(), 1);
+        assert!(xml.contains("<per-minute>120</per-minute>"));
+        // The bass part reads in bass clef
+        assert!(xml.contains("<sign>F</sign>"));
+        assert_eq!(xml.matches("<measure ").count(), 2);
+    }
+
+    #[test]
+    fn test_notes_across_barlines_are_tied() {
+        // Starts on the last eighth of bar 1 and lasts a quarter
+        let xml = write_musicxml(&song(vec![note(0, 24, 3.5, 1.0)])).unwrap();
+        assert_eq!(xml.matches("<measure ").count(), 2);
+        assert_eq!(xml.matches("<tie type=\"start\"/>").count(), 1);
+        assert_eq!(xml.matches("<tie type=\"stop\"/>").count(), 1);
+        let (first, second) = xml.split_once("<measure number=\"2\">").unwrap();
+        assert!(first.contains("<tie type=\"start\"/>"));
+        assert!(second.contains("<tie type=\"stop\"/>"));
+    }
+
+    #[test]
+    fn test_overlapping_notes_use_voices() {
+        let xml =
+            write_musicxml(&song(vec![note(0, 24, 0.0, 4.0), note(0, 26, 1.0, 1.0)])).unwrap();
+        assert!(xml.contains("<voice>2</voice>"));
+        assert_eq!(xml.matches("<backup>").count(), 1);
+    }
+
+    #[test]
+    fn test_songs_too_long_for_a_score() {
+        let last_bar = (MAX_MEASURES - 1) as f64 * BEATS_PER_BAR as f64;
+        let xml = write_musicxml(&song(vec![note(0, 24, last_bar, 4.0)])).unwrap();
+        assert_eq!(xml.matches("<measure ").count(), MAX_MEASURES as usize);
+        assert_eq!(
+            write_musicxml(&song(vec![note(0, 24, last_bar, 8.0)])),
+            Err(ScoreTooLong {
+                measures: MAX_MEASURES + 1
+            })
+        );
+        assert!(write_musicxml(&song(vec![note(0, 24, 1e300, 1.0)])).is_err());
+    }
+}
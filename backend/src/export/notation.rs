@@ -0,0 +1,337 @@
+//! Grid shared by the notation exports.
+//!
+//! Scores are in 4/4 with note starts and ends quantized to sixteenths. The
+//! text formats write each track as a single voice: the track is cut into
+//! slices wherever a note starts or ends and at every barline, and a slice
+//! holds the keys sounding through it, tied to the next slice when the note
+//! goes on.
+
+use std::fmt;
+
+use the_song_model::{instrument, Note, Song};
+
+/// Grid steps per beat, a sixteenth-note grid
+pub const DIVISIONS: u32 = 4;
+pub const BEATS_PER_BAR: u32 = 4;
+pub const MEASURE: u32 = DIVISIONS * BEATS_PER_BAR;
+
+/// Longest score written. The editor's songs fit in 67 measures, longer ones
+/// only come from notes that slipped past validation and are refused rather
+/// than written out bar by empty bar
+pub const MAX_MEASURES: u32 = 256;
+
+/// Lengths in grid steps that a single note symbol can show, longest first:
+/// whole, dotted half, half, dotted quarter, quarter, dotted eighth, eighth and sixteenth
+const NOTE_LENGTHS: [u32; 8] = [16, 12, 8, 6, 4, 3, 2, 1];
+
+/// Grid position of a beat, saturating at the ends of `u32`
+pub fn quantize(beats: f64) -> u32 {
+    (beats * DIVISIONS as f64)
+        .round()
+        .clamp(0.0, u32::MAX as f64) as u32
+}
+
+/// Split a length in grid steps into lengths of single note symbols, longest first
+pub fn split_length(mut length: u32) -> Vec<u32> {
+    let mut lengths = Vec::new();
+    while length > 0 {
+        let next = NOTE_LENGTHS
+            .into_iter()
+            .find(|steps| *steps <= length)
+            .expect("a sixteenth fits any length");
+        lengths.push(next);
+        length -= next;
+    }
+    lengths
+}
+
+/// Tracks a score shows: those with notes, or the first track when the song
+/// has none since a score needs at least one
+pub fn score_tracks(song: &Song) -> Vec<usize> {
+    let tracks: Vec<usize> = (0..song.dimensions.tracks)
+        .filter(|track| song.track_notes(*track).next().is_some())
+        .collect();
+    if tracks.is_empty() {
+        vec![0]
+    } else {
+        tracks
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clef {
+    Treble,
+    Bass,
+    Percussion,
+}
+
+/// Clef a track reads best in, bass when its notes are below middle C on average
+pub fn track_clef(song: &Song, track: usize) -> Clef {
+    if instrument(track).program.is_none() {
+        return Clef::Percussion;
+    }
+    let keys: Vec<u32> = song
+        .track_notes(track)
+        .map(|note| note.midi_key() as u32)
+        .collect();
+    let average = keys.iter().sum::<u32>() / keys.len().max(1) as u32;
+    if keys.is_empty() || average >= 60 {
+        Clef::Treble
+    } else {
+        Clef::Bass
+    }
+}
+
+/// Measures needed to hold every note, at least one
+fn measure_count(song: &Song) -> u32 {
+    let end = song.notes.iter().map(|note| quantize(note.end())).max();
+    end.unwrap_or(0).div_ceil(MEASURE).max(1)
+}
+
+/// A song too long to be written as a score
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoreTooLong {
+    pub measures: u32,
+}
+
+impl fmt::Display for ScoreTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the song needs {} measures, a score holds at most {}",
+            self.measures, MAX_MEASURES
+        )
+    }
+}
+
+impl std::error::Error for ScoreTooLong {}
+
+/// Measures of the score of a song, up to `MAX_MEASURES`
+pub fn score_measures(song: &Song) -> Result<u32, ScoreTooLong> {
+    let measures = measure_count(song);
+    if measures > MAX_MEASURES {
+        return Err(ScoreTooLong { measures });
+    }
+    Ok(measures)
+}
+
+/// Stretch of a track between two grid positions in which no note starts or ends
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slice {
+    pub start: u32,
+    pub end: u32,
+    /// Keys sounding through the slice, lowest first, with whether each goes
+    /// on into the next slice. No keys is a rest.
+    pub keys: Vec<(u8, bool)>,
+}
+
+impl Slice {
+    pub fn length(&self) -> u32 {
+        self.end - self.start
+    }
+}
+
+/// Cut the notes of a track into slices filling `measures` measures.
+///
+/// A key struck again while it still sounds cuts the earlier note short.
+pub fn track_slices<'a>(notes: impl Iterator<Item = &'a Note>, measures: u32) -> Vec<Slice> {
+    let mut notes: Vec<(u8, u32, u32)> = notes
+        .map(|note| {
+            let start = quantize(note.start);
+            let end = quantize(note.end()).max(start.saturating_add(1));
+            (note.midi_key(), start, end)
+        })
+        .collect();
+    notes.sort_by_key(|(key, start, end)| (*key, *start, std::cmp::Reverse(*end)));
+    let mut kept: Vec<(u8, u32, u32)> = Vec::with_capacity(notes.len());
+    for (key, start, end) in notes {
+        if let Some(last) = kept.last_mut().filter(|last| last.0 == key) {
+            if start == last.1 {
+                continue;
+            }
+            last.2 = last.2.min(start);
+        }
+        kept.push((key, start, end));
+    }
+
+    let mut cuts: Vec<u32> = (0..=measures)
+        .map(|measure| measure.saturating_mul(MEASURE))
+        .collect();
+    for (_, start, end) in &kept {
+        cuts.push(*start);
+        cuts.push(*end);
+    }
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    cuts.windows(2)
+        .map(|cut| {
+            let (start, end) = (cut[0], cut[1]);
+            let keys = kept
+                .iter()
+                .filter(|(_, note_start, note_end)| *note_start <= start && *note_end > start)
+                .map(|(key, _, note_end)| (*key, *note_end > end))
+                .collect();
+            Slice { start, end, keys }
+        })
+        .collect()
+}
+
+/// Small songs for round-trip tests of the exports, on the grid so nothing is lost
+#[cfg(test)]
+pub(super) mod fixtures {
+    use the_song_model::{Note, Song, SongDimensions};
+
+    pub fn note(track: usize, pitch: usize, start: f64, duration: f64) -> Note {
+        Note {
+            id: format!("{}-{}-{}", track, pitch, start),
+            pitch,
+            start,
+            duration,
+            velocity: 100,
+            track,
+            created_by: String::new(),
+            created_at: 0.0,
+        }
+    }
+
+    pub fn song(bpm: f64, notes: Vec<Note>) -> Song {
+        Song {
+            bpm,
+            dimensions: SongDimensions::default(),
+            notes,
+        }
+    }
+
+    pub fn songs() -> Vec<Song> {
+        vec![
+            // Scale with a sharp and the lowest and highest keys
+            song(
+                120.0,
+                vec![
+                    note(0, 0, 0.0, 1.0),
+                    note(0, 25, 1.0, 0.5),
+                    note(0, 24, 1.5, 0.5),
+                    note(0, 59, 2.0, 2.0),
+                ],
+            ),
+            // Chord held across a barline under a shorter melody note
+            song(
+                96.0,
+                vec![
+                    note(2, 24, 3.0, 2.5),
+                    note(2, 28, 3.0, 2.5),
+                    note(2, 31, 3.5, 0.25),
+                    note(2, 31, 3.75, 0.25),
+                ],
+            ),
+            // Several tracks, drums and a note after a silent bar
+            song(
+                140.0,
+                vec![
+                    note(0, 12, 0.0, 3.0),
+                    note(3, 0, 0.0, 0.25),
+                    note(3, 6, 0.5, 0.25),
+                    note(1, 36, 8.25, 1.75),
+                ],
+            ),
+        ]
+    }
+
+    /// Track, start, pitch and duration of every note, in order
+    pub fn summary(song: &Song) -> Vec<(usize, f64, usize, f64)> {
+        let mut notes: Vec<_> = song
+            .notes
+            .iter()
+            .map(|note| (note.track, note.start, note.pitch, note.duration))
+            .collect();
+        notes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        notes
+    }
+
+    /// Build a song back from notes read from a score, keys are MIDI keys
+    /// and times are grid steps
+    pub fn song_from_steps(bpm: f64, notes: Vec<(usize, u8, u32, u32)>) -> Song {
+        let notes = notes
+            .into_iter()
+            .map(|(track, key, start, length)| {
+                let beats = |steps: u32| steps as f64 / super::DIVISIONS as f64;
+                note(track, key as usize - 36, beats(start), beats(length))
+            })
+            .collect();
+        song(bpm, notes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{note, song};
+    use super::*;
+
+    #[test]
+    fn test_split_length() {
+        assert_eq!(split_length(5), vec![4, 1]);
+        assert_eq!(split_length(7), vec![6, 1]);
+        assert_eq!(split_length(16), vec![16]);
+        assert_eq!(split_length(0), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_track_slices() {
+        // A half note across the barline with a quarter on top of it
+        let song = song(120.0, vec![note(0, 0, 3.0, 2.0), note(0, 4, 3.0, 1.0)]);
+        let slices = track_slices(song.track_notes(0), 2);
+        assert_eq!(
+            slices,
+            vec![
+                Slice {
+                    start: 0,
+                    end: 12,
+                    keys: vec![]
+                },
+                Slice {
+                    start: 12,
+                    end: 16,
+                    keys: vec![(36, true), (40, false)]
+                },
+                Slice {
+                    start: 16,
+                    end: 20,
+                    keys: vec![(36, false)]
+                },
+                Slice {
+                    start: 20,
+                    end: 32,
+                    keys: vec![]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restruck_key_cuts_the_note_short() {
+        let song = song(120.0, vec![note(0, 0, 0.0, 2.0), note(0, 0, 1.0, 2.0)]);
+        let slices = track_slices(song.track_notes(0), 1);
+        assert_eq!(slices[0].keys, vec![(36, false)]);
+        assert_eq!((slices[1].start, slices[1].end), (4, 12));
+        assert_eq!(slices[1].keys, vec![(36, false)]);
+    }
+
+    #[test]
+    fn test_score_measures() {
+        assert_eq!(score_measures(&song(120.0, vec![])), Ok(1));
+        assert_eq!(
+            score_measures(&song(120.0, vec![note(0, 0, 3.0, 2.0)])),
+            Ok(2)
+        );
+        // Far out notes neither overflow nor make a score of empty bars
+        assert_eq!(
+            score_measures(&song(120.0, vec![note(0, 0, 1e300, 1.0)])),
+            Err(ScoreTooLong {
+                measures: u32::MAX.div_ceil(MEASURE)
+            })
+        );
+        let slices = track_slices([note(0, 0, 1e300, 1.0)].iter(), 1);
+        assert_eq!(slices.last().unwrap().end, u32::MAX);
+    }
+}
@@ -0,0 +1,146 @@
+//! Stems download: every track rendered on its own, in one ZIP archive.
+//!
+//! The archive holds a `manifest.json` naming the tracks and their colors, the
+//! song as `song.mid`, and one WAV per track under `stems/`. Stems all start
+//! at bar 1 and last as long as the whole song, so they line up when dropped
+//! into an audio editor at the song's BPM.
+
+use serde_json::json;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use super::{midi::write_midi, wav::write_wav, zip::ZipWriter};
+use crate::config::RenderConfig;
+use crate::render::{render_stem, sampler::SoundPack, song_frames};
+use the_song_model::{instrument, Song};
+
+/// Path of the stem of `track` in the archive, numbered from 1 like the editor
+fn stem_path(track: usize) -> String {
+    let name: String = instrument(track)
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("stems/{:02}-{}.wav", track + 1, name)
+}
+
+/// Write the stems archive of `song` to `out`, one track at a time
+pub fn write_stems<W: Write>(
+    out: W,
+    song: &Song,
+    colors: &[Option<String>],
+    config: &RenderConfig,
+    pack: &SoundPack,
+) -> io::Result<W> {
+    let mut zip = ZipWriter::new(out, SystemTime::now());
+
+    let frames = song_frames(song, config, pack);
+    let tracks: Vec<_> = (0..song.dimensions.tracks)
+        .map(|track| {
+            json!({
+                "index": track,
+                "name": instrument(track).name,
+                "color": colors.get(track).cloned().flatten(),
+                "notes": song.track_notes(track).count(),
+                "stem": stem_path(track),
+            })
+        })
+        .collect();
+    let manifest = json!({
+        "bpm": song.bpm,
+        "sample_rate": config.sample_rate,
+        "seconds": frames as f64 / config.sample_rate as f64,
+        "midi": "song.mid",
+        "tracks": tracks,
+    });
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+    zip.add("manifest.json", &manifest)?;
+    zip.add("song.mid", &write_midi(song)?)?;
+
+    for track in 0..song.dimensions.tracks {
+        let stem = render_stem(song, track, config, pack);
+        zip.add(&stem_path(track), &write_wav(&stem))?;
+    }
+    zip.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use the_song_model::{Note, SongDimensions};
+
+    /// Names and contents of the entries of a stored archive, in order
+    fn entries(bytes: &[u8]) -> Vec<(String, &[u8])> {
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while bytes[at..at + 4] == 0x0403_4b50u32.to_le_bytes() {
+            let size = u32::from_le_bytes(bytes[at + 18..at + 22].try_into().unwrap()) as usize;
+            let name_len = u16::from_le_bytes([bytes[at + 26], bytes[at + 27]]) as usize;
+            let name = String::from_utf8(bytes[at + 30..at + 30 + name_len].to_vec()).unwrap();
+            let data = at + 30 + name_len;
+            entries.push((name, &bytes[data..data + size]));
+            at = data + size;
+        }
+        entries
+    }
+
+    #[test]
+    fn test_write_stems() {
+        let song = Song {
+            bpm: 90.0,
+            dimensions: SongDimensions {
+                tracks: 2,
+                pitches: 12,
+            },
+            notes: vec![Note {
+                id: "a".to_string(),
+                pitch: 0,
+                start: 0.0,
+                duration: 1.0,
+                velocity: 100,
+                track: 1,
+                created_by: String::new(),
+                created_at: 0.0,
+            }],
+        };
+        let config = RenderConfig {
+            sample_rate: 8000,
+            max_seconds: 5,
+            ..RenderConfig::default()
+        };
+        let colors = vec![Some("#ff0000".to_string())];
+        let bytes =
+            write_stems(Vec::new(), &song, &colors, &config, &SoundPack::default()).unwrap();
+
+        let entries = entries(&bytes);
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "manifest.json",
+                "song.mid",
+                "stems/01-acoustic-piano.wav",
+                "stems/02-acoustic-guitar.wav"
+            ]
+        );
+
+        let manifest: serde_json::Value = serde_json::from_slice(entries[0].1).unwrap();
+        assert_eq!(manifest["bpm"], 90.0);
+        assert_eq!(manifest["tracks"][0]["color"], "#ff0000");
+        assert_eq!(manifest["tracks"][1]["color"], serde_json::Value::Null);
+        assert_eq!(manifest["tracks"][1]["notes"], 1);
+        assert_eq!(
+            manifest["tracks"][1]["stem"],
+            "stems/02-acoustic-guitar.wav"
+        );
+        assert_eq!(&entries[1].1[0..4], b"MThd");
+        // Both stems are the same length
+        assert_eq!(entries[2].1.len(), entries[3].1.len());
+    }
+}
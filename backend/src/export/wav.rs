@@ -0,0 +1,74 @@
+//! WAV export of rendered audio.
+//!
+//! A canonical RIFF file with one `fmt ` and one `data` chunk holding 16-bit
+//! PCM stereo samples.
+
+use crate::render::Audio;
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+/// Bytes of the RIFF, `fmt ` and `data` headers
+const HEADER_LEN: usize = 44;
+
+/// Encode `audio` as a 16-bit stereo WAV file
+pub fn write_wav(audio: &Audio) -> Vec<u8> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_len = audio.frames() * block_align as usize;
+    let mut out = Vec::with_capacity(HEADER_LEN + data_len);
+
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&((HEADER_LEN - 8 + data_len) as u32).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&CHANNELS.to_le_bytes());
+    out.extend_from_slice(&audio.sample_rate.to_le_bytes());
+    out.extend_from_slice(&(audio.sample_rate * block_align as u32).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&(data_len as u32).to_le_bytes());
+    for (left, right) in audio.left.iter().zip(&audio.right) {
+        out.extend_from_slice(&pcm(*left).to_le_bytes());
+        out.extend_from_slice(&pcm(*right).to_le_bytes());
+    }
+    out
+}
+
+fn pcm(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_wav() {
+        let mut audio = Audio::silence(44100, 3);
+        audio.left[1] = 1.0;
+        audio.right[2] = -2.0;
+        let bytes = write_wav(&audio);
+
+        assert_eq!(bytes.len(), HEADER_LEN + 3 * 4);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            bytes.len() as u32 - 8
+        );
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([bytes[22], bytes[23]]), 2);
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 44100);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 12);
+
+        let sample = |i: usize| i16::from_le_bytes([bytes[44 + 2 * i], bytes[45 + 2 * i]]);
+        assert_eq!(sample(0), 0);
+        assert_eq!(sample(2), i16::MAX);
+        assert_eq!(sample(5), -i16::MAX);
+    }
+}
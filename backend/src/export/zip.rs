@@ -0,0 +1,240 @@
+//! Minimal ZIP archive writer.
+//!
+//! Entries are stored without compression: audio and MIDI hardly compress,
+//! and storing lets an archive be written out entry by entry while it is
+//! being downloaded. Archives are limited to 4 GiB, there is no ZIP64.
+
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// CRC-32 as used by ZIP, the reflected 0xEDB88320 polynomial
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Version 2.0, the first with folders
+const VERSION: u16 = 20;
+/// File names are UTF-8
+const FLAG_UTF8: u16 = 1 << 11;
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a ZIP archive to `out` as entries are added
+pub struct ZipWriter<W: Write> {
+    out: W,
+    entries: Vec<Entry>,
+    offset: u64,
+    /// MS-DOS time and date every entry is stamped with
+    modified: (u16, u16),
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(out: W, modified: SystemTime) -> Self {
+        Self {
+            out,
+            entries: Vec::new(),
+            offset: 0,
+            modified: dos_time(modified),
+        }
+    }
+
+    /// Store `bytes` as the file `name`, folders are separated by `/`
+    pub fn add(&mut self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let entry = Entry {
+            name: name.to_string(),
+            crc: crc32(bytes),
+            size: too_large(bytes.len() as u64)?,
+            offset: too_large(self.offset)?,
+        };
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        self.common_fields(&mut header, &entry);
+        // No extra field
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+
+        self.write(&header)?;
+        self.write(bytes)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the central directory and hand back the output
+    pub fn finish(mut self) -> io::Result<W> {
+        let directory_offset = too_large(self.offset)?;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            // Made by and needed
+            directory.extend_from_slice(&VERSION.to_le_bytes());
+            directory.extend_from_slice(&VERSION.to_le_bytes());
+            self.common_fields(&mut directory, entry);
+            // No extra field, comment, disk number or attributes
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let count = u16::try_from(self.entries.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many ZIP entries"))?;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        // Single disk
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&directory_offset.to_le_bytes());
+        // No comment
+        end.extend_from_slice(&0u16.to_le_bytes());
+
+        self.write(&directory)?;
+        self.write(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Fields from the flags to the name length, shared by both headers
+    fn common_fields(&self, out: &mut Vec<u8>, entry: &Entry) {
+        out.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+        // Stored
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&self.modified.0.to_le_bytes());
+        out.extend_from_slice(&self.modified.1.to_le_bytes());
+        out.extend_from_slice(&entry.crc.to_le_bytes());
+        // Compressed and uncompressed sizes are the same
+        out.extend_from_slice(&entry.size.to_le_bytes());
+        out.extend_from_slice(&entry.size.to_le_bytes());
+        out.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+fn too_large(value: u64) -> io::Result<u32> {
+    u32::try_from(value)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "ZIP archive over 4 GiB"))
+}
+
+/// MS-DOS time and date of a UTC time, as ZIP stores them
+fn dos_time(time: SystemTime) -> (u16, u16) {
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (year, month, day) = civil_date((seconds / 86400) as i64);
+    if year < 1980 {
+        // Earliest date the format can hold, 1980-01-01
+        return (0, 0x21);
+    }
+    let of_day = seconds % 86400;
+    let time = ((of_day / 3600) << 11) | ((of_day % 3600 / 60) << 5) | (of_day % 60 / 2);
+    let date = (((year - 1980).min(127) as u64) << 9) | ((month as u64) << 5) | day as u64;
+    (time as u16, date as u16)
+}
+
+/// Year, month and day of a count of days since 1970-01-01
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's civil_from_days
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_dos_time() {
+        // 2024-02-29 13:45:30 UTC
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_214_330);
+        let (time, date) = dos_time(time);
+        assert_eq!(date, (44 << 9) | (2 << 5) | 29);
+        assert_eq!(time, (13 << 11) | (45 << 5) | 15);
+        assert_eq!(dos_time(SystemTime::UNIX_EPOCH), (0, 0x21));
+    }
+
+    #[test]
+    fn test_zip_layout() {
+        let mut zip = ZipWriter::new(Vec::new(), SystemTime::UNIX_EPOCH);
+        zip.add("a.txt", b"hello").unwrap();
+        zip.add("dir/b.txt", b"").unwrap();
+        let bytes = zip.finish().unwrap();
+
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), 0x0403_4b50);
+        assert_eq!(u32_at(14), crc32(b"hello"));
+        assert_eq!(u32_at(18), 5);
+        assert_eq!(&bytes[30..35], b"a.txt");
+        assert_eq!(&bytes[35..40], b"hello");
+        // Second entry right after the first
+        assert_eq!(u32_at(40), 0x0403_4b50);
+        assert_eq!(&bytes[70..79], b"dir/b.txt");
+
+        let end = bytes.len() - 22;
+        assert_eq!(u32_at(end), 0x0605_4b50);
+        assert_eq!(u16_at(end + 10), 2);
+        let directory = u32_at(end + 16) as usize;
+        assert_eq!(directory, 79);
+        assert_eq!(u32_at(directory), 0x0201_4b50);
+        assert_eq!(u32_at(directory + 42), 0);
+        let second = directory + 46 + 5;
+        assert_eq!(u32_at(second + 42), 40);
+        assert_eq!(&bytes[second + 46..second + 55], b"dir/b.txt");
+        assert_eq!(u32_at(end + 12) as usize, end - directory);
+    }
+}
@@ -1,3 +1,794 @@
-pub async fn root() -> &'static str {
-    "Hello, World!"
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::OwnedSemaphorePermit;
+use uuid::Uuid;
+
+use crate::{
+    export, import, metrics, render,
+    state::{
+        AppState, ForkError, RenderRefused, Room, RoomError, TimelapseEvent, UpdateError,
+        DEFAULT_ROOM_ID, MAX_BPM, MIN_BPM,
+    },
+};
+
+/// Author recorded on notes written through admin endpoints
+const ADMIN_USER: &str = "admin";
+
+/// Versions listed unless the request asks for fewer or more
+const DEFAULT_VERSION_LIMIT: usize = 100;
+/// Most versions listed in one response
+const MAX_VERSION_LIMIT: usize = 1000;
+
+/// Song seconds a timelapse stream plays back per second unless asked otherwise
+const DEFAULT_TIMELAPSE_SPEED: f64 = 60.0;
+/// Longest pause of a timelapse stream in seconds unless asked otherwise
+const DEFAULT_TIMELAPSE_MAX_GAP: f64 = 2.0;
+/// Longest pause a timelapse stream may ask for in seconds
+const MAX_TIMELAPSE_MAX_GAP: f64 = 60.0;
+
+/// Look up a room for an HTTP request without creating it
+async fn find_room(state: &AppState, room_id: &str) -> Result<Arc<Room>, Response> {
+    if !Room::is_valid_id(room_id) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid room ID").into_response());
+    }
+    match state.existing_room(room_id).await {
+        Ok(Some(room)) => Ok(room),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Room not found").into_response()),
+        Err(e) => {
+            tracing::error!("Failed to load room {}: {}", room_id, e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to load room").into_response())
+        }
+    }
+}
+
+/// Claim a render slot for the client, or the response turning it down
+fn start_render(
+    state: &AppState,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+) -> Result<OwnedSemaphorePermit, (StatusCode, &'static str)> {
+    state
+        .start_render(state.client_ip(addr, headers))
+        .map_err(|refused| match refused {
+            RenderRefused::Busy => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many songs are being rendered, try again shortly",
+            ),
+            RenderRefused::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "Too many renders"),
+        })
+}
+
+/// Reject requests without the admin bearer token
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if state.is_admin_token(token) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "Admin token required")),
+    }
+}
+
+/// Response serving `bytes` as a download named `filename`
+fn attachment(content_type: &'static str, filename: String, bytes: Vec<u8>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+/// Prometheus metrics in the text exposition format
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    let body = metrics::metrics().render(&state).await;
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+/// Standard MIDI File of the public room's song
+pub async fn song_midi(State(state): State<AppState>) -> Response {
+    room_midi(&state, DEFAULT_ROOM_ID).await
+}
+
+/// Standard MIDI File of a room's song
+pub async fn room_song_midi(
+    Path(room_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    room_midi(&state, &room_id).await
+}
+
+async fn room_midi(state: &AppState, room_id: &str) -> Response {
+    let room = match find_room(state, room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match export::midi::write_midi(&room.song().await) {
+        Ok(bytes) => attachment("audio/midi", format!("{}.mid", room_id), bytes),
+        Err(e) => {
+            tracing::error!("Failed to export room {} as MIDI: {}", room_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to export song").into_response()
+        }
+    }
+}
+
+/// MusicXML score of the public room's song
+pub async fn song_musicxml(State(state): State<AppState>) -> Response {
+    room_musicxml(&state, DEFAULT_ROOM_ID).await
+}
+
+/// MusicXML score of a room's song
+pub async fn room_song_musicxml(
+    Path(room_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    room_musicxml(&state, &room_id).await
+}
+
+async fn room_musicxml(state: &AppState, room_id: &str) -> Response {
+    let room = match find_room(state, room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match export::musicxml::write_musicxml(&room.song().await) {
+        Ok(score) => attachment(
+            "application/vnd.recordare.musicxml+xml",
+            format!("{}.musicxml", room_id),
+            score.into_bytes(),
+        ),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+    }
+}
+
+/// ABC notation of the public room's song
+pub async fn song_abc(State(state): State<AppState>) -> Response {
+    room_abc(&state, DEFAULT_ROOM_ID).await
+}
+
+/// ABC notation of a room's song
+pub async fn room_song_abc(Path(room_id): Path<String>, State(state): State<AppState>) -> Response {
+    room_abc(&state, &room_id).await
+}
+
+async fn room_abc(state: &AppState, room_id: &str) -> Response {
+    let room = match find_room(state, room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match export::abc::write_abc(&room.song().await) {
+        Ok(tune) => attachment(
+            "text/vnd.abc; charset=utf-8",
+            format!("{}.abc", room_id),
+            tune.into_bytes(),
+        ),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+    }
+}
+
+/// LilyPond score of the public room's song
+pub async fn song_lilypond(State(state): State<AppState>) -> Response {
+    room_lilypond(&state, DEFAULT_ROOM_ID).await
+}
+
+/// LilyPond score of a room's song
+pub async fn room_song_lilypond(
+    Path(room_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    room_lilypond(&state, &room_id).await
+}
+
+async fn room_lilypond(state: &AppState, room_id: &str) -> Response {
+    let room = match find_room(state, room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match export::lilypond::write_lilypond(&room.song().await) {
+        Ok(score) => attachment(
+            "text/x-lilypond; charset=utf-8",
+            format!("{}.ly", room_id),
+            score.into_bytes(),
+        ),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+    }
+}
+
+/// Stereo WAV rendering of the public room's song
+pub async fn song_wav(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    room_wav(&state, DEFAULT_ROOM_ID, addr, &headers).await
+}
+
+/// Stereo WAV rendering of a room's song
+pub async fn room_song_wav(
+    Path(room_id): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    room_wav(&state, &room_id, addr, &headers).await
+}
+
+async fn room_wav(
+    state: &AppState,
+    room_id: &str,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+) -> Response {
+    let room = match find_room(state, room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+    let permit = match start_render(state, addr, headers) {
+        Ok(permit) => permit,
+        Err(refused) => return refused.into_response(),
+    };
+
+    let song = room.song().await;
+    let config = state.config().render.clone();
+    let pack = state.sound_pack().clone();
+    let rendered = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        export::wav::write_wav(&render::render_song(&song, &config, &pack))
+    })
+    .await;
+    match rendered {
+        Ok(bytes) => attachment("audio/wav", format!("{}.wav", room_id), bytes),
+        Err(e) => {
+            tracing::error!("Failed to render room {}: {}", room_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render song").into_response()
+        }
+    }
+}
+
+/// ZIP of the public room's tracks rendered one by one, with the MIDI file and a manifest
+pub async fn song_stems(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    room_stems(&state, DEFAULT_ROOM_ID, addr, &headers).await
+}
+
+/// ZIP of a room's tracks rendered one by one, with the MIDI file and a manifest
+pub async fn room_song_stems(
+    Path(room_id): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    room_stems(&state, &room_id, addr, &headers).await
+}
+
+/// Blocking writer feeding a response body, fails once the client is gone
+struct BodyWriter(tokio::sync::mpsc::Sender<io::Result<Bytes>>);
+
+impl BodyWriter {
+    /// Largest chunk handed to the body at once
+    const CHUNK: usize = 64 * 1024;
+}
+
+impl io::Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk = &buf[..buf.len().min(Self::CHUNK)];
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "download cancelled"))?;
+        Ok(chunk.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn room_stems(
+    state: &AppState,
+    room_id: &str,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+) -> Response {
+    let room = match find_room(state, room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+    let permit = match start_render(state, addr, headers) {
+        Ok(permit) => permit,
+        Err(refused) => return refused.into_response(),
+    };
+
+    let song = room.song().await;
+    let colors = room.track_colors().await;
+    let config = state.config().render.clone();
+    let pack = state.sound_pack().clone();
+    // Stems are rendered one at a time and streamed out as they are done
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    let room_id = room_id.to_string();
+    let log_room_id = room_id.clone();
+    // The slot is held until the last stem is out or the client gave up
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let written =
+            export::stems::write_stems(BodyWriter(sender.clone()), &song, &colors, &config, &pack);
+        if let Err(e) = written {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                tracing::error!("Failed to render stems of room {}: {}", log_room_id, e);
+            }
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}-stems.zip\"", room_id),
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    /// Remove the room's notes before importing
+    #[serde(default)]
+    replace: bool,
+}
+
+/// Admin: write the notes of an uploaded MIDI file into a room's song
+pub async fn import_room_song_midi(
+    Path(room_id): Path<String>,
+    Query(query): Query<ImportQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection.into_response();
+    }
+    if !Room::is_valid_id(&room_id) {
+        return (StatusCode::BAD_REQUEST, "Invalid room ID").into_response();
+    }
+
+    // Notes are fitted to the room's song, or to the configured shape if it is created below
+    let dimensions = match state.existing_room(&room_id).await {
+        Ok(Some(room)) => room.song_dimensions(),
+        Ok(None) => state.config().song.dimensions(),
+        Err(e) => {
+            tracing::error!("Failed to load room {}: {}", room_id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load room").into_response();
+        }
+    };
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as f64;
+    let imported = match import::midi::read_midi(&body, dimensions, ADMIN_USER, created_at) {
+        Ok(imported) => imported,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid MIDI file: {}", e)).into_response();
+        }
+    };
+
+    // Seeding is the main use, so the room is created if it does not exist yet
+    let room = match state.room(&room_id).await {
+        Ok(room) => room,
+        Err(RoomError::TooManyRooms) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, "Room limit reached").into_response();
+        }
+        Err(e) => {
+            tracing::error!("Failed to load room {}: {}", room_id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load room").into_response();
+        }
+    };
+
+    let bpm = imported.bpm.map(|bpm| bpm.clamp(MIN_BPM, MAX_BPM));
+    let result = room
+        .edit_song(|doc| {
+            if query.replace {
+                the_song_model::clear_notes(doc)?;
+            }
+            if let Some(bpm) = bpm {
+                the_song_model::set_bpm(doc, bpm)?;
+            }
+            for note in &imported.notes {
+                the_song_model::insert_note(doc, note)?;
+            }
+            Ok(())
+        })
+        .await;
+
+    match result {
+        Ok(()) => {
+            tracing::info!(
+                "Imported {} notes from MIDI into room {}",
+                imported.notes.len(),
+                room_id
+            );
+            Json(serde_json::json!({
+                "imported": imported.notes.len(),
+                "transposed": imported.transposed,
+                "skipped": imported.skipped,
+                "bpm": bpm,
+            }))
+            .into_response()
+        }
+        // The file made notes the song does not allow, nothing was written
+        Err(UpdateError::Invalid(violation)) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("The song does not allow these notes: {}", violation),
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to import MIDI into room {}: {}", room_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to import song").into_response()
+        }
+    }
+}
+
+/// Admin: undo everything a user did to the notes of a room's song
+pub async fn revert_room_user(
+    Path((room_id, user_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection.into_response();
+    }
+    let Ok(user_id) = Uuid::parse_str(&user_id) else {
+        return (StatusCode::BAD_REQUEST, "Invalid user ID").into_response();
+    };
+    let room = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match room.revert_user(user_id).await {
+        Ok(summary) => Json(serde_json::json!({
+            "removed": summary.removed,
+            "restored": summary.restored,
+            "fields": summary.fields,
+        }))
+        .into_response(),
+        Err(e) => {
+            tracing::error!(
+                "Failed to revert user {} in room {}: {}",
+                user_id,
+                room_id,
+                e
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to revert user").into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VersionsQuery {
+    limit: Option<usize>,
+}
+
+/// Latest versions of a room's song, newest first
+pub async fn room_versions(
+    Path(room_id): Path<String>,
+    Query(query): Query<VersionsQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let room = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_VERSION_LIMIT)
+        .min(MAX_VERSION_LIMIT);
+    let versions: Vec<_> = room
+        .versions(limit)
+        .await
+        .into_iter()
+        .map(|version| {
+            serde_json::json!({
+                "version": version.id.to_string(),
+                "lamport": version.lamport,
+                "timestamp": version.timestamp,
+                "author": version.author,
+                "ops": version.ops,
+            })
+        })
+        .collect();
+    Json(serde_json::json!({ "versions": versions })).into_response()
+}
+
+/// Standard MIDI File of a room's song as it was at a version
+pub async fn room_version_midi(
+    Path((room_id, version)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let room = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+    let Some(song) = room.song_at(&version).await else {
+        return (StatusCode::NOT_FOUND, "Version not found").into_response();
+    };
+
+    match export::midi::write_midi(&song) {
+        Ok(bytes) => attachment(
+            "audio/midi",
+            format!("{}-{}.mid", room_id, version.replace('@', "-")),
+            bytes,
+        ),
+        Err(e) => {
+            tracing::error!(
+                "Failed to export room {} at {} as MIDI: {}",
+                room_id,
+                version,
+                e
+            );
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to export song").into_response()
+        }
+    }
+}
+
+/// Admin: bring a room's song back to a past version
+pub async fn restore_room_version(
+    Path((room_id, version)): Path<(String, String)>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = require_admin(&state, &headers) {
+        return rejection.into_response();
+    }
+    let room = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match room.restore_version(&version).await {
+        Ok(true) => Json(serde_json::json!({ "restored": version })).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Version not found").into_response(),
+        Err(e) => {
+            tracing::error!("Failed to restore room {} to {}: {}", room_id, version, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to restore version",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// One JSON document per line
+fn ndjson_line(event: &TimelapseEvent) -> String {
+    let mut line = serde_json::to_string(event).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// Note events of a room's whole history as a newline-delimited JSON download
+pub async fn room_timelapse_file(
+    Path(room_id): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    let room = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    let body: String = room.timelapse().await.iter().map(ndjson_line).collect();
+    attachment(
+        "application/x-ndjson",
+        format!("{}-timelapse.ndjson", room_id),
+        body.into_bytes(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct TimelapseQuery {
+    /// Song seconds played back per second
+    speed: Option<f64>,
+    /// Longest pause between two events in seconds
+    max_gap: Option<f64>,
+}
+
+impl TimelapseQuery {
+    /// Speed and longest pause, `None` if either is out of range
+    fn pace(&self) -> Option<(f64, f64)> {
+        let speed = self.speed.unwrap_or(DEFAULT_TIMELAPSE_SPEED);
+        let max_gap = self.max_gap.unwrap_or(DEFAULT_TIMELAPSE_MAX_GAP);
+        let valid =
+            speed.is_finite() && speed > 0.0 && (0.0..=MAX_TIMELAPSE_MAX_GAP).contains(&max_gap);
+        valid.then_some((speed, max_gap))
+    }
+}
+
+/// Pause of a timelapse stream between events at two timestamps in seconds
+fn timelapse_delay(previous: i64, timestamp: i64, speed: f64, max_gap: f64) -> Duration {
+    let gap = (timestamp.saturating_sub(previous) as f64 / speed).clamp(0.0, max_gap);
+    Duration::try_from_secs_f64(gap).unwrap_or(Duration::ZERO)
+}
+
+/// Note events of a room's whole history, streamed at the pace they happened
+pub async fn room_timelapse_stream(
+    Path(room_id): Path<String>,
+    Query(query): Query<TimelapseQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Some((speed, max_gap)) = query.pace() else {
+        return (StatusCode::BAD_REQUEST, "Invalid speed or max_gap").into_response();
+    };
+    let room = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    let events = room.timelapse().await;
+    let stream = futures::stream::unfold(
+        (events.into_iter(), None),
+        move |(mut events, previous): (_, Option<i64>)| async move {
+            let event = events.next()?;
+            if let (Some(previous), Some(timestamp)) = (previous, event.timestamp) {
+                let delay = timelapse_delay(previous, timestamp, speed, max_gap);
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+            }
+            let line = ndjson_line(&event);
+            Some((
+                Ok::<_, Infallible>(line),
+                (events, event.timestamp.or(previous)),
+            ))
+        },
+    )
+    // A stream can run for a long time, it must not hold up shutdown
+    .take_until(state.shutdown_token().cancelled_owned());
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ForkQuery {
+    /// Version to fork at, the latest if missing
+    version: Option<String>,
+    /// ID of the new room, a random one if missing
+    room: Option<String>,
+}
+
+/// Start a new room with a copy of a room's song
+pub async fn fork_room(
+    Path(room_id): Path<String>,
+    Query(query): Query<ForkQuery>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let fork_id = match query.room {
+        Some(fork_id) if Room::is_valid_id(&fork_id) => fork_id,
+        Some(_) => return (StatusCode::BAD_REQUEST, "Invalid room ID").into_response(),
+        None => Uuid::new_v4().simple().to_string()[..12].to_string(),
+    };
+    let source = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match state
+        .fork_room(
+            &source,
+            query.version.as_deref(),
+            &fork_id,
+            state.client_ip(addr, &headers),
+        )
+        .await
+    {
+        Ok(room) => {
+            let origin = room.origin().ok().flatten();
+            (
+                StatusCode::CREATED,
+                Json(serde_json::json!({
+                    "room": fork_id,
+                    "url": format!("/ws/{}", fork_id),
+                    "origin": origin,
+                })),
+            )
+                .into_response()
+        }
+        Err(ForkError::UnknownVersion) => {
+            (StatusCode::NOT_FOUND, "Version not found").into_response()
+        }
+        Err(ForkError::RoomExists) => (StatusCode::CONFLICT, "Room already exists").into_response(),
+        Err(ForkError::Room(RoomError::TooManyRooms)) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "Room limit reached").into_response()
+        }
+        Err(ForkError::Room(RoomError::RateLimited)) => {
+            (StatusCode::TOO_MANY_REQUESTS, "Too many new rooms").into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to fork room {} into {}: {}", room_id, fork_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fork room").into_response()
+        }
+    }
+}
+
+/// Where a room's song was forked from
+pub async fn room_origin(Path(room_id): Path<String>, State(state): State<AppState>) -> Response {
+    let room = match find_room(&state, &room_id).await {
+        Ok(room) => room,
+        Err(response) => return response,
+    };
+
+    match room.origin() {
+        Ok(origin) => Json(serde_json::json!({ "origin": origin })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read origin of room {}: {}", room_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read origin").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timelapse_pace() {
+        let query = |speed, max_gap| TimelapseQuery { speed, max_gap };
+        assert_eq!(query(None, None).pace(), Some((60.0, 2.0)));
+        assert_eq!(query(Some(1e-300), Some(1e300)).pace(), None);
+        assert_eq!(query(Some(0.0), None).pace(), None);
+        assert_eq!(query(None, Some(-1.0)).pace(), None);
+        assert_eq!(query(None, Some(f64::NAN)).pace(), None);
+
+        // The slowest pace allowed still waits at most the longest pause
+        let (speed, max_gap) = query(Some(1e-300), Some(60.0)).pace().unwrap();
+        assert_eq!(
+            timelapse_delay(0, i64::MAX, speed, max_gap),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            timelapse_delay(i64::MAX, i64::MIN, speed, max_gap),
+            Duration::ZERO
+        );
+        assert_eq!(timelapse_delay(0, 120, 60.0, 2.0), Duration::from_secs(2));
+        assert_eq!(
+            timelapse_delay(0, 30, 60.0, 2.0),
+            Duration::from_millis(500)
+        );
+    }
 }
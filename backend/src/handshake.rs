@@ -0,0 +1,252 @@
+//! Protocol handshake.
+//!
+//! A client opens with a `ClientHello` saying which protocol and song schema it
+//! speaks, the song shape it can display and the optional features it
+//! understands. Clients that cannot work with the room are refused, and so are
+//! clients that do not say which song shape they display: the server cannot
+//! tell a current editor from a stale one built for another shape.
+
+use std::fmt;
+
+use crate::dto::{ClientHello, SongShape};
+use the_song_model::SongDimensions;
+use the_song_protocol::{
+    CAPABILITY_DELTA_RESUME, CAPABILITY_SERVER_ERRORS, PROTOCOL_VERSION, SCHEMA_VERSION,
+};
+
+/// Optional features this server offers
+const SERVER_CAPABILITIES: [&str; 2] = [CAPABILITY_DELTA_RESUME, CAPABILITY_SERVER_ERRORS];
+
+/// What was agreed on with a client
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Version vector the client already has, empty for a fresh document
+    pub version_vector: Vec<u8>,
+    /// Capabilities both sides support
+    pub capabilities: Vec<String>,
+    /// Token from an earlier welcome, empty for a new user
+    pub resume_token: String,
+}
+
+impl Session {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Why a client was refused
+#[derive(Debug, PartialEq)]
+pub enum Incompatible {
+    ProtocolTooOld {
+        client: u32,
+        minimum: u32,
+    },
+    ProtocolTooNew {
+        client: u32,
+    },
+    Schema {
+        client: u32,
+    },
+    SongShape {
+        client: SongDimensions,
+        room: SongDimensions,
+    },
+    UnknownShape {
+        room: SongDimensions,
+    },
+}
+
+impl fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatible::ProtocolTooOld { client, minimum } => write!(
+                f,
+                "protocol version {} is no longer supported, need at least {}",
+                client, minimum
+            ),
+            Incompatible::ProtocolTooNew { client } => write!(
+                f,
+                "protocol version {} is newer than the server's {}",
+                client, PROTOCOL_VERSION
+            ),
+            Incompatible::Schema { client } => write!(
+                f,
+                "song schema version {} does not match the server's {}",
+                client, SCHEMA_VERSION
+            ),
+            Incompatible::SongShape { client, room } => write!(
+                f,
+                "client displays {} tracks of {} pitches, the room has {} tracks of {}",
+                client.tracks, client.pitches, room.tracks, room.pitches
+            ),
+            Incompatible::UnknownShape { room } => write!(
+                f,
+                "client did not say which song shape it displays, the room has {} tracks of {}",
+                room.tracks, room.pitches
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Incompatible {}
+
+pub fn song_shape(dimensions: SongDimensions) -> SongShape {
+    SongShape {
+        tracks: dimensions.tracks as u32,
+        pitches: dimensions.pitches as u32,
+    }
+}
+
+/// Decide whether a client can join a room whose song has `room_shape`.
+///
+/// `hello` is `None` for clients that started without one. Clients that do not
+/// state a protocol or schema version are assumed to predate them, but a
+/// client that does not state its song shape is refused: assuming the default
+/// shape would admit stale editors built for another one.
+pub fn negotiate(
+    hello: Option<ClientHello>,
+    room_shape: SongDimensions,
+    min_protocol_version: u32,
+) -> Result<Session, Incompatible> {
+    let hello = hello.unwrap_or_default();
+
+    if hello.protocol_version < min_protocol_version {
+        return Err(Incompatible::ProtocolTooOld {
+            client: hello.protocol_version,
+            minimum: min_protocol_version,
+        });
+    }
+    if hello.protocol_version > PROTOCOL_VERSION {
+        return Err(Incompatible::ProtocolTooNew {
+            client: hello.protocol_version,
+        });
+    }
+    if hello.schema_version != 0 && hello.schema_version != SCHEMA_VERSION {
+        return Err(Incompatible::Schema {
+            client: hello.schema_version,
+        });
+    }
+    let Some(shape) = hello.song_shape else {
+        return Err(Incompatible::UnknownShape { room: room_shape });
+    };
+    let client_shape = SongDimensions {
+        tracks: shape.tracks as usize,
+        pitches: shape.pitches as usize,
+    };
+    if client_shape != room_shape {
+        return Err(Incompatible::SongShape {
+            client: client_shape,
+            room: room_shape,
+        });
+    }
+
+    let capabilities = if hello.protocol_version > 0 {
+        hello
+            .capabilities
+            .into_iter()
+            .filter(|capability| SERVER_CAPABILITIES.contains(&capability.as_str()))
+            .collect()
+    } else {
+        // A hello without a version comes from clients that resumed and handled
+        // errors before capabilities were announced
+        SERVER_CAPABILITIES.map(String::from).to_vec()
+    };
+
+    Ok(Session {
+        version_vector: hello.version_vector,
+        capabilities,
+        resume_token: hello.resume_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(protocol_version: u32, shape: Option<SongShape>) -> ClientHello {
+        ClientHello {
+            version_vector: vec![1],
+            protocol_version,
+            schema_version: SCHEMA_VERSION,
+            song_shape: shape,
+            capabilities: vec![CAPABILITY_DELTA_RESUME.to_string(), "telepathy".to_string()],
+            resume_token: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_current_client() {
+        let shape = Some(song_shape(SongDimensions::default()));
+        let session = negotiate(
+            Some(hello(PROTOCOL_VERSION, shape)),
+            SongDimensions::default(),
+            0,
+        )
+        .unwrap();
+        assert_eq!(session.version_vector, vec![1]);
+        assert_eq!(session.capabilities, vec![CAPABILITY_DELTA_RESUME]);
+        assert!(!session.supports(CAPABILITY_SERVER_ERRORS));
+    }
+
+    #[test]
+    fn test_legacy_clients() {
+        let room = SongDimensions::default();
+        let shape = Some(song_shape(room));
+        let session = negotiate(Some(hello(0, shape)), room, 0).unwrap();
+        assert!(session.supports(CAPABILITY_DELTA_RESUME));
+
+        assert!(matches!(
+            negotiate(Some(hello(0, shape)), room, 1),
+            Err(Incompatible::ProtocolTooOld { .. })
+        ));
+        assert!(matches!(
+            negotiate(None, room, 1),
+            Err(Incompatible::ProtocolTooOld { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unknown_shape() {
+        let room = SongDimensions::default();
+        assert_eq!(
+            negotiate(None, room, 0).unwrap_err(),
+            Incompatible::UnknownShape { room }
+        );
+        assert_eq!(
+            negotiate(Some(hello(0, None)), room, 0).unwrap_err(),
+            Incompatible::UnknownShape { room }
+        );
+        assert_eq!(
+            negotiate(Some(hello(PROTOCOL_VERSION, None)), room, 0).unwrap_err(),
+            Incompatible::UnknownShape { room }
+        );
+    }
+
+    #[test]
+    fn test_incompatible_clients() {
+        let room = SongDimensions::default();
+        let shape = Some(song_shape(room));
+        assert_eq!(
+            negotiate(Some(hello(PROTOCOL_VERSION + 1, shape)), room, 0).unwrap_err(),
+            Incompatible::ProtocolTooNew {
+                client: PROTOCOL_VERSION + 1
+            }
+        );
+
+        let mut stale_schema = hello(PROTOCOL_VERSION, shape);
+        stale_schema.schema_version = SCHEMA_VERSION + 1;
+        assert!(matches!(
+            negotiate(Some(stale_schema), room, 0),
+            Err(Incompatible::Schema { .. })
+        ));
+
+        let narrow = Some(SongShape {
+            tracks: 16,
+            pitches: 48,
+        });
+        assert!(matches!(
+            negotiate(Some(hello(PROTOCOL_VERSION, narrow)), room, 0),
+            Err(Incompatible::SongShape { .. })
+        ));
+    }
+}
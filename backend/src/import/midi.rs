@@ -0,0 +1,281 @@
+//! Standard MIDI File import.
+//!
+//! Notes are assigned to tracks by MIDI track name first, so files written by
+//! our own exporter come back onto the tracks they left from. Other files are
+//! mapped by channel, mirroring the channels the exporter gives each track.
+//! Notes past the end of the song are left out.
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use std::collections::HashMap;
+
+use crate::export::midi::{track_channel, DRUM_CHANNEL};
+use crate::state::MAX_SONG_BEATS;
+use the_song_model::{instrument, Note, SongDimensions, BASE_MIDI_NOTE, DEFAULT_BPM};
+
+/// Notes read from a MIDI file
+pub struct ImportedMidi {
+    /// Tempo of the file, if it sets one
+    pub bpm: Option<f64>,
+    pub notes: Vec<Note>,
+    /// Notes moved by whole octaves to fit the song's pitch range
+    pub transposed: usize,
+    /// Notes starting after the end of the song, left out
+    pub skipped: usize,
+}
+
+/// Track of a song with `tracks` tracks that notes on `channel` land on when
+/// their MIDI track name does not match one
+pub fn channel_track(channel: u8, tracks: usize) -> usize {
+    if channel == DRUM_CHANNEL {
+        return (0..tracks)
+            .find(|track| instrument(*track).program.is_none())
+            .unwrap_or(0);
+    }
+    (0..tracks)
+        .find(|track| track_channel(*track) == channel)
+        .unwrap_or(channel as usize % tracks)
+}
+
+/// Fold a MIDI key into a range of `pitches` pitches by whole octaves.
+///
+/// Ranges under an octave wrap instead, there may be no octave of the key inside.
+fn fit_pitch(key: u8, pitches: usize) -> (usize, bool) {
+    let pitches = pitches as i32;
+    let mut pitch = key as i32 - BASE_MIDI_NOTE as i32;
+    let fits = (0..pitches).contains(&pitch);
+    if pitches < 12 {
+        return (pitch.rem_euclid(pitches) as usize, !fits);
+    }
+    while pitch < 0 {
+        pitch += 12;
+    }
+    while pitch >= pitches {
+        pitch -= 12;
+    }
+    (pitch as usize, !fits)
+}
+
+/// Parse a Standard MIDI File into notes.
+///
+/// Notes are fitted into a song of the given `dimensions`. Imported notes get
+/// IDs in the editor's `{userId}:{timestamp}:{n}` format.
+pub fn read_midi(
+    bytes: &[u8],
+    dimensions: SongDimensions,
+    created_by: &str,
+    created_at: f64,
+) -> Result<ImportedMidi, midly::Error> {
+    let smf = Smf::parse(bytes)?;
+
+    // The first tempo of the file is used for the whole song
+    let bpm = smf
+        .tracks
+        .iter()
+        .filter_map(|track| {
+            let mut tick = 0u64;
+            track.iter().find_map(|event| {
+                tick += event.delta.as_int() as u64;
+                match event.kind {
+                    TrackEventKind::Meta(MetaMessage::Tempo(micros)) if micros.as_int() > 0 => {
+                        let bpm = 60_000_000.0 / micros.as_int() as f64;
+                        // Tempos are stored in whole microseconds, undo the rounding
+                        Some((tick, (bpm * 100.0).round() / 100.0))
+                    }
+                    _ => None,
+                }
+            })
+        })
+        .min_by_key(|(tick, _)| *tick)
+        .map(|(_, bpm)| bpm);
+
+    let ticks_to_beats = |tick: u64| match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => tick as f64 / ticks_per_beat.as_int().max(1) as f64,
+        Timing::Timecode(fps, subframes) => {
+            let seconds = tick as f64 / (fps.as_f32() as f64 * subframes.max(1) as f64);
+            seconds * bpm.unwrap_or(DEFAULT_BPM) / 60.0
+        }
+    };
+
+    let mut notes = Vec::new();
+    let mut transposed = 0;
+    let mut skipped = 0;
+    for track in &smf.tracks {
+        let named_track = track.iter().find_map(|event| match event.kind {
+            TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                (0..dimensions.tracks).find(|track| instrument(*track).name.as_bytes() == name)
+            }
+            _ => None,
+        });
+
+        // Notes still sounding, by channel and key, oldest first
+        let mut sounding: HashMap<(u8, u8), Vec<(u64, u8)>> = HashMap::new();
+        let mut finished = Vec::new();
+        let mut tick = 0u64;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            let TrackEventKind::Midi { channel, message } = event.kind else {
+                continue;
+            };
+            let channel = channel.as_int();
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                    sounding
+                        .entry((channel, key.as_int()))
+                        .or_default()
+                        .push((tick, vel.as_int()));
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    if let Some(started) = sounding.get_mut(&(channel, key.as_int())) {
+                        if !started.is_empty() {
+                            let (start, vel) = started.remove(0);
+                            finished.push((channel, key.as_int(), start, tick, vel));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Notes never released end with the track
+        for ((channel, key), started) in sounding {
+            for (start, vel) in started {
+                finished.push((channel, key, start, tick, vel));
+            }
+        }
+
+        for (channel, key, start, end, velocity) in finished {
+            if end <= start {
+                continue;
+            }
+            let start = ticks_to_beats(start);
+            if start >= MAX_SONG_BEATS {
+                skipped += 1;
+                continue;
+            }
+            let (pitch, moved) = fit_pitch(key, dimensions.pitches);
+            if moved {
+                transposed += 1;
+            }
+            // Notes running past the end are cut short
+            let end = ticks_to_beats(end).min(MAX_SONG_BEATS);
+            notes.push(Note {
+                id: String::new(),
+                pitch,
+                start,
+                duration: end - start,
+                velocity,
+                track: named_track.unwrap_or_else(|| channel_track(channel, dimensions.tracks)),
+                created_by: created_by.to_string(),
+                created_at,
+            });
+        }
+    }
+
+    notes.sort_by(|a, b| {
+        a.start
+            .total_cmp(&b.start)
+            .then(a.track.cmp(&b.track))
+            .then(a.pitch.cmp(&b.pitch))
+    });
+    for (index, note) in notes.iter_mut().enumerate() {
+        note.id = format!("{}:{}:{}", created_by, created_at as u64, index);
+    }
+
+    Ok(ImportedMidi {
+        bpm,
+        notes,
+        transposed,
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::midi::write_midi;
+    use the_song_model::{Song, INSTRUMENTS};
+
+    fn note(pitch: usize, start: f64, duration: f64, track: usize) -> Note {
+        Note {
+            id: String::new(),
+            pitch,
+            start,
+            duration,
+            velocity: 90,
+            track,
+            created_by: "user".to_string(),
+            created_at: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_channel_track_inverts_track_channel() {
+        let tracks = INSTRUMENTS.len();
+        for (track, instrument) in INSTRUMENTS.iter().enumerate() {
+            if instrument.program.is_some() {
+                assert_eq!(channel_track(track_channel(track), tracks), track);
+            }
+        }
+        assert_eq!(channel_track(DRUM_CHANNEL, tracks), 3);
+        // Small songs without the channel's track still get a valid one
+        assert!(channel_track(12, 4) < 4);
+    }
+
+    #[test]
+    fn test_fit_pitch() {
+        assert_eq!(fit_pitch(36, 60), (0, false));
+        assert_eq!(fit_pitch(95, 60), (59, false));
+        assert_eq!(fit_pitch(24, 60), (0, true));
+        assert_eq!(fit_pitch(100, 60), (52, true));
+        assert_eq!(fit_pitch(100, 5), (4, true));
+    }
+
+    #[test]
+    fn test_export_roundtrip() {
+        let song = Song {
+            bpm: 90.0,
+            dimensions: SongDimensions::default(),
+            notes: vec![
+                note(0, 0.0, 1.0, 0),
+                note(7, 0.5, 0.25, 4),
+                note(30, 2.0, 3.0, 15),
+            ],
+        };
+        let bytes = write_midi(&song).unwrap();
+        let imported = read_midi(&bytes, song.dimensions, "admin", 1000.0).unwrap();
+
+        assert_eq!(imported.bpm, Some(90.0));
+        assert_eq!(imported.transposed, 0);
+        let summary: Vec<_> = imported
+            .notes
+            .iter()
+            .map(|n| (n.pitch, n.start, n.duration, n.velocity, n.track))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, 0.0, 1.0, 90, 0),
+                (7, 0.5, 0.25, 90, 4),
+                (30, 2.0, 3.0, 90, 15)
+            ]
+        );
+        assert_eq!(imported.notes[0].id, "admin:1000:0");
+    }
+
+    #[test]
+    fn test_notes_past_the_end_are_left_out() {
+        let song = Song {
+            bpm: 120.0,
+            dimensions: SongDimensions::default(),
+            notes: vec![
+                note(0, MAX_SONG_BEATS - 1.0, 4.0, 0),
+                note(0, MAX_SONG_BEATS + 8.0, 1.0, 0),
+            ],
+        };
+        let bytes = write_midi(&song).unwrap();
+        let imported = read_midi(&bytes, song.dimensions, "admin", 0.0).unwrap();
+
+        assert_eq!(imported.skipped, 1);
+        assert_eq!(imported.notes.len(), 1);
+        assert_eq!(imported.notes[0].end(), MAX_SONG_BEATS);
+    }
+}
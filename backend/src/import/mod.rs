@@ -0,0 +1,3 @@
+//! Converters from other file formats into the song.
+
+pub mod midi;
@@ -1,6 +1,14 @@
+mod config;
 mod dto;
+mod export;
 mod handlers;
+mod handshake;
+mod import;
+mod metrics;
+mod rate_limit;
+mod render;
 mod routes;
+mod shutdown;
 mod state;
 mod tasks;
 mod ws;
@@ -18,19 +26,69 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // Create shared app state
-    let app_state = state::AppState::new();
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Songs are kept on disk so restarts keep them
+    let storage = state::FileStorageProvider::new(&config.storage.data_dir);
+    tracing::info!("Song storage at {}", config.storage.data_dir.display());
+
+    if config.server.admin_token.is_none() {
+        tracing::info!("ADMIN_TOKEN not set, admin endpoints are disabled");
+    }
+
+    let bind_addr = config.server.bind_addr();
+    let shutdown_deadline = config.server.shutdown_timeout();
+
+    // Create shared app state and load the public room up front
+    let rooms = state::RoomManager::new(
+        Box::new(storage),
+        config.song.clone(),
+        config.abuse.clone(),
+        config.rooms.max_rooms,
+    )
+    .expect("Failed to count the stored rooms");
+    let sound_pack = render::sampler::SoundPack::load(&config.render.sound_pack_dir);
+    tracing::info!("Loaded {} sound pack samples", sound_pack.len());
+    let app_state = state::AppState::new(rooms, config, sound_pack);
+    app_state
+        .room(state::DEFAULT_ROOM_ID)
+        .await
+        .expect("Failed to load the public room");
 
     // Spawn global broadcast tasks
     tracing::info!("Starting global broadcast tasks");
-    tokio::spawn(tasks::global_stats_broadcast_task(app_state.clone()));
-    tokio::spawn(tasks::global_mouse_broadcast_task(app_state.clone()));
+    let tasks = vec![
+        tokio::spawn(tasks::global_stats_broadcast_task(app_state.clone())),
+        tokio::spawn(tasks::global_mouse_broadcast_task(app_state.clone())),
+        tokio::spawn(tasks::room_unload_task(app_state.clone())),
+    ];
 
     // Build the router
-    let app = routes::create_router(app_state);
+    let app = routes::create_router(app_state.clone());
 
     // Run the server
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(&bind_addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", bind_addr, e));
     tracing::info!("Server listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    let shutdown_token = app_state.shutdown_token();
+    let server = tokio::spawn(async move {
+        // Rate limits are kept per client address
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_token.cancelled_owned())
+        .await
+        .unwrap();
+    });
+
+    shutdown::wait_for_signal().await;
+    shutdown::shutdown(app_state, server, tasks, shutdown_deadline).await;
 }
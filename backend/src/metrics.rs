@@ -0,0 +1,248 @@
+//! Prometheus metrics.
+//!
+//! Counters and histograms are updated where things happen. Gauges about the
+//! loaded rooms and their connections are collected when `/metrics` is
+//! scraped. They are totals and maxima for the whole process: the endpoint is
+//! public, so room and user IDs never show up in labels.
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+use crate::state::AppState;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Process-wide metrics
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// Open WebSocket connections
+    pub connections: IntGauge,
+    /// Client messages received, by payload type
+    pub messages_in: IntCounterVec,
+    /// Server messages written to sockets, by payload type
+    pub messages_out: IntCounterVec,
+    /// Bytes written to sockets
+    pub bytes_sent: IntCounter,
+    /// Time to validate, import and log a synthesizer update
+    pub apply_update_seconds: Histogram,
+    /// Rejected synthesizer updates, by reason
+    pub apply_update_failures: IntCounterVec,
+    /// Updates turned down by the note quotas, by kind
+    pub quota_violations: IntCounterVec,
+    /// Time to queue a frame for every connection of a room
+    pub broadcast_seconds: Histogram,
+    /// Droppable frames skipped because a send queue was full
+    pub frames_dropped: IntCounter,
+    /// Connections closed because they fell too far behind
+    pub slow_consumer_disconnects: IntCounter,
+    /// Time of one mouse broadcast tick across all rooms
+    pub mouse_tick_seconds: Histogram,
+    /// Client messages dropped by the rate limits, by reason
+    pub rate_limited: IntCounterVec,
+    /// Connections closed because they kept going over the rate limits
+    pub rate_limit_disconnects: IntCounter,
+    loaded_rooms: IntGauge,
+    document_bytes: IntGauge,
+    document_bytes_max: IntGauge,
+    send_queue_depth: IntGauge,
+    send_queue_depth_max: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("the_song".to_string()), None)
+            .expect("Failed to create metrics registry");
+
+        let connections = IntGauge::new("connections", "Open WebSocket connections").unwrap();
+        let messages_in = IntCounterVec::new(
+            Opts::new("messages_in_total", "Client messages received"),
+            &["payload"],
+        )
+        .unwrap();
+        let messages_out = IntCounterVec::new(
+            Opts::new("messages_out_total", "Server messages written to sockets"),
+            &["payload"],
+        )
+        .unwrap();
+        let bytes_sent = IntCounter::new("bytes_sent_total", "Bytes written to sockets").unwrap();
+        let apply_update_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "apply_update_seconds",
+                "Time to validate, import and log a synthesizer update",
+            )
+            .buckets(prometheus::exponential_buckets(0.0001, 2.0, 16).unwrap()),
+        )
+        .unwrap();
+        let apply_update_failures = IntCounterVec::new(
+            Opts::new(
+                "apply_update_failures_total",
+                "Rejected synthesizer updates",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let quota_violations = IntCounterVec::new(
+            Opts::new(
+                "quota_violations_total",
+                "Updates turned down by the note quotas",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let broadcast_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "broadcast_seconds",
+                "Time to queue a frame for every connection of a room",
+            )
+            .buckets(prometheus::exponential_buckets(0.00001, 2.0, 16).unwrap()),
+        )
+        .unwrap();
+        let frames_dropped = IntCounter::new(
+            "frames_dropped_total",
+            "Droppable frames skipped because a send queue was full",
+        )
+        .unwrap();
+        let slow_consumer_disconnects = IntCounter::new(
+            "slow_consumer_disconnects_total",
+            "Connections closed because they fell too far behind",
+        )
+        .unwrap();
+        let mouse_tick_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "mouse_tick_seconds",
+                "Time of one mouse broadcast tick across all rooms",
+            )
+            .buckets(prometheus::exponential_buckets(0.00001, 2.0, 16).unwrap()),
+        )
+        .unwrap();
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "rate_limited_total",
+                "Client messages dropped by the rate limits",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let rate_limit_disconnects = IntCounter::new(
+            "rate_limit_disconnects_total",
+            "Connections closed because they kept going over the rate limits",
+        )
+        .unwrap();
+        let loaded_rooms = IntGauge::new("loaded_rooms", "Rooms loaded in memory").unwrap();
+        let document_bytes = IntGauge::new(
+            "document_bytes",
+            "Stored size of the songs of all loaded rooms, snapshots plus logged updates",
+        )
+        .unwrap();
+        let document_bytes_max = IntGauge::new(
+            "document_bytes_max",
+            "Stored size of the largest song of a loaded room",
+        )
+        .unwrap();
+        let send_queue_depth = IntGauge::new(
+            "send_queue_depth",
+            "Frames waiting in the send queues of all connections",
+        )
+        .unwrap();
+        let send_queue_depth_max = IntGauge::new(
+            "send_queue_depth_max",
+            "Frames waiting in the fullest send queue of a connection",
+        )
+        .unwrap();
+
+        registry.register(Box::new(connections.clone())).unwrap();
+        registry.register(Box::new(messages_in.clone())).unwrap();
+        registry.register(Box::new(messages_out.clone())).unwrap();
+        registry.register(Box::new(bytes_sent.clone())).unwrap();
+        registry
+            .register(Box::new(apply_update_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(apply_update_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(quota_violations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(frames_dropped.clone())).unwrap();
+        registry
+            .register(Box::new(slow_consumer_disconnects.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(mouse_tick_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry
+            .register(Box::new(rate_limit_disconnects.clone()))
+            .unwrap();
+        registry.register(Box::new(loaded_rooms.clone())).unwrap();
+        registry.register(Box::new(document_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(document_bytes_max.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(send_queue_depth.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(send_queue_depth_max.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            connections,
+            messages_in,
+            messages_out,
+            bytes_sent,
+            apply_update_seconds,
+            apply_update_failures,
+            quota_violations,
+            broadcast_seconds,
+            frames_dropped,
+            slow_consumer_disconnects,
+            mouse_tick_seconds,
+            rate_limited,
+            rate_limit_disconnects,
+            loaded_rooms,
+            document_bytes,
+            document_bytes_max,
+            send_queue_depth,
+            send_queue_depth_max,
+        }
+    }
+
+    /// Collect the room gauges and encode everything in the Prometheus text format
+    pub async fn render(&self, state: &AppState) -> String {
+        // Added up first and set once, so a concurrent scrape never sees a partial sum
+        let rooms = state.loaded_rooms().await;
+        let (mut bytes, mut bytes_max, mut depth, mut depth_max) = (0, 0, 0, 0);
+        for room in &rooms {
+            let size = room.document_size() as i64;
+            bytes += size;
+            bytes_max = bytes_max.max(size);
+            for queued in room.send_queue_depths().await {
+                depth += queued as i64;
+                depth_max = depth_max.max(queued as i64);
+            }
+        }
+        self.loaded_rooms.set(rooms.len() as i64);
+        self.document_bytes.set(bytes);
+        self.document_bytes_max.set(bytes_max);
+        self.send_queue_depth.set(depth);
+        self.send_queue_depth_max.set(depth_max);
+
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -0,0 +1,416 @@
+//! Flood protection for WebSocket traffic.
+//!
+//! Every connection gets token buckets for mouse and synthesizer messages, and
+//! so does every client IP across all of its connections. A message that finds
+//! a bucket empty, or a frame over the size limit, is dropped and counted as a
+//! violation. Connections that keep going past `max_violations` within the
+//! violation window are disconnected. Each IP also has buckets for the rooms
+//! it may create and the songs it may have rendered to audio.
+
+use axum::http::HeaderMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+/// How often idle per-IP buckets are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    Mouse,
+    Synthesizer,
+}
+
+/// Why a message was turned down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    FrameTooLarge { len: usize, max: usize },
+    TooFast(Traffic),
+}
+
+impl Violation {
+    /// Metric label
+    pub fn label(&self) -> &'static str {
+        match self {
+            Violation::FrameTooLarge { .. } => "frame_size",
+            Violation::TooFast(Traffic::Mouse) => "mouse",
+            Violation::TooFast(Traffic::Synthesizer) => "synthesizer",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::FrameTooLarge { len, max } => write!(
+                f,
+                "message of {} bytes is over the limit of {} bytes",
+                len, max
+            ),
+            Violation::TooFast(Traffic::Mouse) => write!(f, "too many mouse updates, slow down"),
+            Violation::TooFast(Traffic::Synthesizer) => {
+                write!(f, "too many song updates, slow down")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the message and warn the client
+    Throttle(Violation),
+    /// The client kept going after being warned
+    Disconnect(Violation),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket refilling `per_sec` tokens up to `burst`, it never runs dry if `per_sec` is 0
+    fn new(per_sec: f64, burst: u32, now: Instant) -> Self {
+        Self {
+            per_sec,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.updated = now;
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        if self.per_sec == 0.0 {
+            return true;
+        }
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+struct IpBuckets {
+    mouse: TokenBucket,
+    synthesizer: TokenBucket,
+    rooms: TokenBucket,
+    renders: TokenBucket,
+}
+
+struct IpTable {
+    buckets: HashMap<IpAddr, IpBuckets>,
+    last_sweep: Instant,
+}
+
+/// Limits shared by all connections from the same IP
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    ips: Mutex<IpTable>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            ips: Mutex::new(IpTable {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Limits for a new connection from `ip`
+    pub fn connection(self: &Arc<Self>, ip: IpAddr) -> ConnectionLimiter {
+        let now = Instant::now();
+        ConnectionLimiter {
+            shared: self.clone(),
+            ip,
+            mouse: TokenBucket::new(self.config.mouse_per_sec, self.config.mouse_burst, now),
+            synthesizer: TokenBucket::new(
+                self.config.synthesizer_per_sec,
+                self.config.synthesizer_burst,
+                now,
+            ),
+            violations: 0,
+            window_start: now,
+        }
+    }
+
+    /// Whether `ip` may create another room
+    pub fn take_room(&self, ip: IpAddr) -> bool {
+        self.with_ip(ip, Instant::now(), |buckets, now| {
+            buckets.rooms.try_take(now)
+        })
+    }
+
+    /// Whether `ip` may have another song rendered to audio
+    pub fn take_render(&self, ip: IpAddr) -> bool {
+        self.with_ip(ip, Instant::now(), |buckets, now| {
+            buckets.renders.try_take(now)
+        })
+    }
+
+    fn take_ip(&self, ip: IpAddr, traffic: Traffic, now: Instant) -> bool {
+        self.with_ip(ip, now, |buckets, now| match traffic {
+            Traffic::Mouse => buckets.mouse.try_take(now),
+            Traffic::Synthesizer => buckets.synthesizer.try_take(now),
+        })
+    }
+
+    fn with_ip<T>(
+        &self,
+        ip: IpAddr,
+        now: Instant,
+        take: impl FnOnce(&mut IpBuckets, Instant) -> T,
+    ) -> T {
+        let config = &self.config;
+        let mut table = self.ips.lock().unwrap();
+        if now.saturating_duration_since(table.last_sweep) >= SWEEP_INTERVAL {
+            // A full bucket holds nothing worth remembering
+            table.buckets.retain(|_, b| {
+                !(b.mouse.is_full(now)
+                    && b.synthesizer.is_full(now)
+                    && b.rooms.is_full(now)
+                    && b.renders.is_full(now))
+            });
+            table.last_sweep = now;
+        }
+        let buckets = table.buckets.entry(ip).or_insert_with(|| IpBuckets {
+            mouse: TokenBucket::new(config.ip_mouse_per_sec, config.ip_mouse_burst, now),
+            synthesizer: TokenBucket::new(
+                config.ip_synthesizer_per_sec,
+                config.ip_synthesizer_burst,
+                now,
+            ),
+            rooms: TokenBucket::new(config.ip_rooms_per_sec, config.ip_rooms_burst, now),
+            renders: TokenBucket::new(config.ip_renders_per_sec, config.ip_renders_burst, now),
+        });
+        take(buckets, now)
+    }
+}
+
+/// Limits of one connection
+pub struct ConnectionLimiter {
+    shared: Arc<RateLimiter>,
+    ip: IpAddr,
+    mouse: TokenBucket,
+    synthesizer: TokenBucket,
+    violations: u32,
+    window_start: Instant,
+}
+
+impl ConnectionLimiter {
+    /// Check the size of a frame before decoding it
+    pub fn check_size(&mut self, len: usize) -> Verdict {
+        self.check_size_at(len, Instant::now())
+    }
+
+    /// Check a decoded message against the connection's and the IP's buckets
+    pub fn check_rate(&mut self, traffic: Traffic) -> Verdict {
+        self.check_rate_at(traffic, Instant::now())
+    }
+
+    fn check_size_at(&mut self, len: usize, now: Instant) -> Verdict {
+        let max = self.shared.config.max_frame_bytes;
+        if len > max {
+            self.violation(Violation::FrameTooLarge { len, max }, now)
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn check_rate_at(&mut self, traffic: Traffic, now: Instant) -> Verdict {
+        let bucket = match traffic {
+            Traffic::Mouse => &mut self.mouse,
+            Traffic::Synthesizer => &mut self.synthesizer,
+        };
+        if bucket.try_take(now) && self.shared.take_ip(self.ip, traffic, now) {
+            Verdict::Allow
+        } else {
+            self.violation(Violation::TooFast(traffic), now)
+        }
+    }
+
+    fn violation(&mut self, violation: Violation, now: Instant) -> Verdict {
+        if now.saturating_duration_since(self.window_start) >= self.shared.config.violation_window()
+        {
+            self.window_start = now;
+            self.violations = 0;
+        }
+        self.violations += 1;
+        if self.violations > self.shared.config.max_violations {
+            Verdict::Disconnect(violation)
+        } else {
+            Verdict::Throttle(violation)
+        }
+    }
+}
+
+/// The address limits are keyed on, the last `X-Forwarded-For` hop when the
+/// server runs behind a trusted proxy
+pub fn client_ip(addr: SocketAddr, headers: &HeaderMap, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .next_back();
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    addr.ip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(config: RateLimitConfig) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(config))
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2, start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        assert!(bucket.try_take(start + Duration::from_millis(100)));
+        assert!(!bucket.try_take(start + Duration::from_millis(100)));
+
+        let mut unlimited = TokenBucket::new(0.0, 0, start);
+        assert!(unlimited.try_take(start));
+    }
+
+    #[test]
+    fn test_throttle_then_disconnect() {
+        let limiter = limiter(RateLimitConfig {
+            synthesizer_per_sec: 1.0,
+            synthesizer_burst: 1,
+            max_violations: 2,
+            ..RateLimitConfig::default()
+        });
+        let mut connection = limiter.connection(ip(1));
+        let now = Instant::now();
+        let too_fast = Violation::TooFast(Traffic::Synthesizer);
+
+        assert_eq!(
+            connection.check_rate_at(Traffic::Synthesizer, now),
+            Verdict::Allow
+        );
+        assert_eq!(
+            connection.check_rate_at(Traffic::Mouse, now),
+            Verdict::Allow
+        );
+        assert_eq!(
+            connection.check_rate_at(Traffic::Synthesizer, now),
+            Verdict::Throttle(too_fast)
+        );
+        assert_eq!(
+            connection.check_rate_at(Traffic::Synthesizer, now),
+            Verdict::Throttle(too_fast)
+        );
+        assert_eq!(
+            connection.check_rate_at(Traffic::Synthesizer, now),
+            Verdict::Disconnect(too_fast)
+        );
+
+        // Violations are forgiven once the window has passed
+        let later = now + limiter.config.violation_window();
+        assert_eq!(
+            connection.check_size_at(usize::MAX, later),
+            Verdict::Throttle(Violation::FrameTooLarge {
+                len: usize::MAX,
+                max: limiter.config.max_frame_bytes
+            })
+        );
+    }
+
+    #[test]
+    fn test_ip_limit_is_shared() {
+        let limiter = limiter(RateLimitConfig {
+            ip_mouse_per_sec: 1.0,
+            ip_mouse_burst: 2,
+            ..RateLimitConfig::default()
+        });
+        let now = Instant::now();
+        let mut first = limiter.connection(ip(1));
+        let mut second = limiter.connection(ip(1));
+        let mut other = limiter.connection(ip(2));
+
+        assert_eq!(first.check_rate_at(Traffic::Mouse, now), Verdict::Allow);
+        assert_eq!(second.check_rate_at(Traffic::Mouse, now), Verdict::Allow);
+        assert!(matches!(
+            first.check_rate_at(Traffic::Mouse, now),
+            Verdict::Throttle(_)
+        ));
+        assert_eq!(other.check_rate_at(Traffic::Mouse, now), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_room_limit() {
+        let limiter = limiter(RateLimitConfig {
+            ip_rooms_per_sec: 1.0,
+            ip_rooms_burst: 2,
+            ..RateLimitConfig::default()
+        });
+        assert!(limiter.take_room(ip(1)));
+        assert!(limiter.take_room(ip(1)));
+        assert!(!limiter.take_room(ip(1)));
+        assert!(limiter.take_room(ip(2)));
+    }
+
+    #[test]
+    fn test_render_limit() {
+        let limiter = limiter(RateLimitConfig {
+            ip_renders_per_sec: 1.0,
+            ip_renders_burst: 1,
+            ..RateLimitConfig::default()
+        });
+        assert!(limiter.take_render(ip(1)));
+        assert!(!limiter.take_render(ip(1)));
+        // Renders and rooms are counted apart
+        assert!(limiter.take_room(ip(1)));
+        assert!(limiter.take_render(ip(2)));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 5.6.7.8".parse().unwrap());
+        assert_eq!(client_ip(addr, &headers, false), ip(1));
+        assert_eq!(client_ip(addr, &headers, true), IpAddr::from([5, 6, 7, 8]));
+        assert_eq!(client_ip(addr, &HeaderMap::new(), true), ip(1));
+    }
+}
@@ -0,0 +1,265 @@
+//! Offline audio rendering of the song.
+//!
+//! Every note is played with the sample of its track's instrument, or with a
+//! synthesized voice when the sound pack has none, and mixed into a stereo
+//! buffer, tracks spread from left to right across the stereo field. Nothing
+//! here needs an audio device: the result is plain samples for an encoder.
+
+pub mod sampler;
+pub mod voice;
+
+use std::f32::consts::FRAC_PI_2;
+
+use crate::config::RenderConfig;
+use sampler::SoundPack;
+use the_song_model::{Note, Song};
+use voice::track_voice;
+
+/// Loudest sample a normalized render peaks at
+const PEAK: f32 = 0.9;
+/// Gain of a note at full velocity before normalizing, leaves room for chords
+const NOTE_GAIN: f32 = 0.25;
+/// Widest pan of the outer tracks, 1 is hard left or right
+const PAN_WIDTH: f32 = 0.6;
+
+/// Stereo audio with samples within -1..=1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Audio {
+    pub sample_rate: u32,
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+}
+
+impl Audio {
+    /// `frames` samples of silence per channel
+    pub fn silence(sample_rate: u32, frames: usize) -> Self {
+        Self {
+            sample_rate,
+            left: vec![0.0; frames],
+            right: vec![0.0; frames],
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.left.len()
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.left
+            .iter()
+            .chain(&self.right)
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    /// Scale down so the loudest sample is at most `PEAK`, quiet audio is left alone
+    pub fn normalize(&mut self) {
+        let peak = self.peak();
+        if peak > PEAK {
+            let gain = PEAK / peak;
+            for sample in self.left.iter_mut().chain(self.right.iter_mut()) {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+/// Left and right gain of `track`, equal power so panning keeps the loudness
+pub fn track_pan(track: usize, tracks: usize) -> (f32, f32) {
+    let pan = if tracks > 1 {
+        (track as f32 / (tracks - 1) as f32 * 2.0 - 1.0) * PAN_WIDTH
+    } else {
+        0.0
+    };
+    let angle = (pan + 1.0) * FRAC_PI_2 / 2.0;
+    (angle.cos(), angle.sin())
+}
+
+/// Seconds `note` keeps sounding when held for `held` seconds
+fn note_length(pack: &SoundPack, note: &Note, held: f32) -> f32 {
+    match pack.playback(note.track, note.midi_key()) {
+        Some(playback) => playback.length(held),
+        None => track_voice(note.track).length(note.midi_key(), held),
+    }
+}
+
+/// Frames needed to hold the whole song, at least one second and at most the configured limit
+pub fn song_frames(song: &Song, config: &RenderConfig, pack: &SoundPack) -> usize {
+    let seconds_per_beat = 60.0 / song.bpm as f32;
+    let end = song
+        .notes
+        .iter()
+        .map(|note| {
+            let start = note.start as f32 * seconds_per_beat;
+            let held = note.duration as f32 * seconds_per_beat;
+            start + note_length(pack, note, held)
+        })
+        .fold(1.0, f32::max)
+        .min(config.max_seconds as f32);
+    (end * config.sample_rate as f32).ceil() as usize
+}
+
+/// Add the notes of `track` to `audio` with a left and right gain, notes past its end are cut
+fn render_track(
+    song: &Song,
+    track: usize,
+    pack: &SoundPack,
+    (left_gain, right_gain): (f32, f32),
+    audio: &mut Audio,
+) {
+    let voice = track_voice(track);
+    let rate = audio.sample_rate as f32;
+    let seconds_per_beat = 60.0 / song.bpm as f32;
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+
+    for note in song.track_notes(track) {
+        let start = (note.start as f32 * seconds_per_beat * rate) as usize;
+        if start >= audio.frames() {
+            continue;
+        }
+        let held = note.duration as f32 * seconds_per_beat;
+        let length = (note_length(pack, note, held) * rate).ceil() as usize;
+        let length = length.min(audio.frames() - start);
+
+        left.clear();
+        left.resize(length, 0.0);
+        right.clear();
+        right.resize(length, 0.0);
+        let velocity = note.velocity as f32 / 127.0 * NOTE_GAIN;
+        match pack.playback(track, note.midi_key()) {
+            Some(playback) => {
+                playback.render(velocity, held, audio.sample_rate, &mut left, &mut right)
+            }
+            None => {
+                voice.render(
+                    note.midi_key(),
+                    velocity,
+                    held,
+                    audio.sample_rate,
+                    &mut left,
+                );
+                right.copy_from_slice(&left);
+            }
+        }
+        for i in 0..length {
+            audio.left[start + i] += left[i] * left_gain;
+            audio.right[start + i] += right[i] * right_gain;
+        }
+    }
+}
+
+/// Render the whole song as a normalized stereo mix
+pub fn render_song(song: &Song, config: &RenderConfig, pack: &SoundPack) -> Audio {
+    let mut audio = Audio::silence(config.sample_rate, song_frames(song, config, pack));
+    for track in 0..song.dimensions.tracks {
+        let pan = track_pan(track, song.dimensions.tracks);
+        render_track(song, track, pack, pan, &mut audio);
+    }
+    audio.normalize();
+    audio
+}
+
+/// Render one track alone, unpanned and as long as the whole song so stems line up.
+///
+/// A stem is only turned down when it would clip.
+pub fn render_stem(song: &Song, track: usize, config: &RenderConfig, pack: &SoundPack) -> Audio {
+    let mut audio = Audio::silence(config.sample_rate, song_frames(song, config, pack));
+    render_track(song, track, pack, (1.0, 1.0), &mut audio);
+    audio.normalize();
+    audio
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use the_song_model::SongDimensions;
+
+    fn song(notes: Vec<Note>) -> Song {
+        Song {
+            bpm: 120.0,
+            dimensions: SongDimensions::default(),
+            notes,
+        }
+    }
+
+    fn note(track: usize, start: f64, velocity: u8) -> Note {
+        Note {
+            id: format!("{}-{}", track, start),
+            pitch: 24,
+            start,
+            duration: 1.0,
+            velocity,
+            track,
+            created_by: String::new(),
+            created_at: 0.0,
+        }
+    }
+
+    fn config() -> RenderConfig {
+        RenderConfig {
+            sample_rate: 8000,
+            max_seconds: 10,
+            ..RenderConfig::default()
+        }
+    }
+
+    /// Render with synthesized voices only
+    fn render(notes: Vec<Note>) -> Audio {
+        render_song(&song(notes), &config(), &SoundPack::default())
+    }
+
+    /// Loudest sample of a channel between two times in seconds
+    fn peak_between(samples: &[f32], from: f32, to: f32) -> f32 {
+        samples[(from * 8000.0) as usize..(to * 8000.0) as usize]
+            .iter()
+            .fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn test_empty_song_is_one_second_of_silence() {
+        let audio = render(vec![]);
+        assert_eq!(audio.frames(), 8000);
+        assert_eq!(audio.peak(), 0.0);
+    }
+
+    #[test]
+    fn test_notes_play_at_their_time() {
+        // Beat 4 at 120 BPM is two seconds in
+        let audio = render(vec![note(0, 4.0, 100)]);
+        assert!(audio.frames() >= 8000 * 2);
+        assert_eq!(peak_between(&audio.left, 0.0, 1.9), 0.0);
+        assert!(peak_between(&audio.left, 2.0, 2.5) > 0.01);
+        // The first track leans left
+        assert!(peak_between(&audio.left, 2.0, 2.5) > peak_between(&audio.right, 2.0, 2.5));
+    }
+
+    #[test]
+    fn test_velocity_scales_and_mix_is_normalized() {
+        let loud = render(vec![note(0, 0.0, 120)]);
+        let quiet = render(vec![note(0, 0.0, 30)]);
+        assert!(quiet.peak() < loud.peak() / 2.0);
+
+        let chord: Vec<_> = (0..16).map(|track| note(track, 0.0, 127)).collect();
+        assert!(render(chord).peak() <= PEAK + 1e-6);
+    }
+
+    #[test]
+    fn test_stems_line_up() {
+        let notes = vec![note(0, 0.0, 100), note(1, 8.0, 100)];
+        let first = render_stem(&song(notes.clone()), 0, &config(), &SoundPack::default());
+        let second = render_stem(&song(notes), 1, &config(), &SoundPack::default());
+        assert_eq!(first.frames(), second.frames());
+        assert!(peak_between(&first.left, 0.0, 0.5) > 0.01);
+        assert_eq!(peak_between(&first.left, 4.0, 4.5), 0.0);
+        assert_eq!(peak_between(&second.left, 0.0, 3.9), 0.0);
+        // Stems are not panned
+        assert_eq!(first.left, first.right);
+    }
+
+    #[test]
+    fn test_length_is_capped() {
+        let audio = render(vec![note(0, 1000.0, 100)]);
+        assert_eq!(audio.frames(), 80000);
+        assert_eq!(audio.peak(), 0.0);
+    }
+}
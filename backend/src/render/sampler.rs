@@ -0,0 +1,330 @@
+//! Sample playback from the sound pack the editor ships.
+//!
+//! Melodic tracks play a one-shot recorded at a known key, resampled to the
+//! pitch of each note. Drum kits play one sample per drum of the General MIDI
+//! percussion map, the samples the editor's drum kit uses. Instruments the pack
+//! has no sample for are left to the synthesized voices.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use super::voice::Drum;
+use super::Audio;
+use the_song_model::instrument;
+
+/// Seconds a pitched sample fades out for once its note is released
+const RELEASE: f32 = 0.5;
+
+/// One-shot samples of melodic instruments: General MIDI programs, file and
+/// the MIDI key the sample was recorded at
+const PATCHES: [(RangeInclusive<u8>, &str, u8); 9] = [
+    // Acoustic pianos
+    (0..=3, "one-shots/grandmother-keys-one-shot-c.wav", 72),
+    // Electric pianos and keys
+    (4..=7, "one-shots/frozen-keys-one-shot-c.wav", 72),
+    // Chromatic percussion
+    (8..=15, "one-shots/cartoon-bell-one-shot-c.wav", 84),
+    (24..=31, "one-shots/lofi-guitar-one-shot-c.wav", 60),
+    // Acoustic and electric basses
+    (32..=37, "bass/deluxe-lofi-bass-guitar-a.wav", 33),
+    (38..=39, "bass/dreams-synth-bass-e.wav", 28),
+    // Strings and choirs
+    (48..=55, "one-shots/subtle-pad-one-shot-c.wav", 60),
+    // Synth pads
+    (88..=95, "one-shots/subtle-pad-one-shot-c.wav", 60),
+    // Ethnic instruments
+    (104..=111, "one-shots/kalimba-tape-one-shot-c.wav", 72),
+];
+
+/// Samples of the drum kit, as in `ui/src/lib/midi-player/sound.ts`
+const KIT: [(Drum, &str); 9] = [
+    (Drum::Kick, "kicks/dreams-lofi-kick-1-c.wav"),
+    (Drum::Rim, "snares-rimshots/dreams-rimshot.wav"),
+    (Drum::Snare, "snares-rimshots/dreams-lofi-snare-1.wav"),
+    (Drum::Clap, "claps/deluxe-lofi-clap.wav"),
+    (Drum::Tom, "percussion/lofi-percussion.wav"),
+    (Drum::ClosedHat, "hihats-closed/lofi-closed-hihat.wav"),
+    (Drum::OpenHat, "hihats-open/leaves-open-hihat.wav"),
+    (Drum::Cymbal, "hihats-open/eternity-open-hihat-1.wav"),
+    (Drum::Click, "percussion/mini-perc.wav"),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum SampleError {
+    NotWav,
+    Truncated,
+    Unsupported(String),
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SampleError::NotWav => write!(f, "not a WAV file"),
+            SampleError::Truncated => write!(f, "file is truncated"),
+            SampleError::Unsupported(format) => write!(f, "unsupported format: {}", format),
+        }
+    }
+}
+
+impl std::error::Error for SampleError {}
+
+/// Decode a PCM or float WAV file, mono files are played on both channels
+pub fn read_wav(bytes: &[u8]) -> Result<Audio, SampleError> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(SampleError::NotWav);
+    }
+
+    let mut format = None;
+    let mut rest = &bytes[12..];
+    while rest.len() >= 8 {
+        let id = &rest[0..4];
+        let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let body = rest.get(8..8 + len).ok_or(SampleError::Truncated)?;
+        match id {
+            b"fmt " => {
+                if body.len() < 16 {
+                    return Err(SampleError::Truncated);
+                }
+                let mut tag = u16::from_le_bytes([body[0], body[1]]);
+                // WAVE_FORMAT_EXTENSIBLE keeps the real tag in its sub-format
+                if tag == 0xfffe && body.len() >= 26 {
+                    tag = u16::from_le_bytes([body[24], body[25]]);
+                }
+                let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+                let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                format = Some((tag, channels, sample_rate, bits));
+            }
+            b"data" => {
+                let (tag, channels, sample_rate, bits) = format.ok_or(SampleError::NotWav)?;
+                return decode_samples(body, tag, channels, sample_rate, bits);
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        rest = rest.get(8 + len + (len & 1)..).unwrap_or_default();
+    }
+    Err(SampleError::NotWav)
+}
+
+fn decode_samples(
+    data: &[u8],
+    tag: u16,
+    channels: usize,
+    sample_rate: u32,
+    bits: u16,
+) -> Result<Audio, SampleError> {
+    let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+        (1, 8) => |b| (b[0] as f32 - 128.0) / 128.0,
+        (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+        (1, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0,
+        (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0,
+        (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        _ => {
+            return Err(SampleError::Unsupported(format!(
+                "format {} with {} bits",
+                tag, bits
+            )))
+        }
+    };
+    if channels == 0 || sample_rate == 0 {
+        return Err(SampleError::Unsupported(format!(
+            "{} channels at {} Hz",
+            channels, sample_rate
+        )));
+    }
+
+    let width = bits as usize / 8;
+    let frames = data.chunks_exact(width * channels);
+    let mut audio = Audio::silence(sample_rate, frames.len());
+    for (i, frame) in frames.enumerate() {
+        audio.left[i] = decode(&frame[..width]);
+        // Channels past the second are dropped
+        audio.right[i] = match channels {
+            1 => audio.left[i],
+            _ => decode(&frame[width..2 * width]),
+        };
+    }
+    Ok(audio)
+}
+
+/// Samples of the sound pack, by file name within the pack
+#[derive(Debug, Default)]
+pub struct SoundPack {
+    samples: HashMap<&'static str, Audio>,
+}
+
+impl SoundPack {
+    /// Load the samples the instruments use from `dir`, skipping the ones that fail
+    pub fn load(dir: &Path) -> Self {
+        let mut pack = Self::default();
+        if !dir.is_dir() {
+            tracing::warn!(
+                "Sound pack {} not found, songs render with synthesized voices",
+                dir.display()
+            );
+            return pack;
+        }
+
+        let files = PATCHES
+            .iter()
+            .map(|(_, file, _)| *file)
+            .chain(KIT.iter().map(|(_, file)| *file));
+        for file in files {
+            if pack.samples.contains_key(file) {
+                continue;
+            }
+            let sample = std::fs::read(dir.join(file))
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| read_wav(&bytes).map_err(|e| e.to_string()));
+            match sample {
+                Ok(sample) => {
+                    pack.samples.insert(file, sample);
+                }
+                Err(e) => tracing::warn!("Failed to load sample {}: {}", file, e),
+            }
+        }
+        pack
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// How a note of `key` on `track` is played, `None` without a sample for it
+    pub fn playback(&self, track: usize, key: u8) -> Option<Playback<'_>> {
+        match instrument(track).program {
+            Some(program) => {
+                let (_, file, root_key) = PATCHES
+                    .iter()
+                    .find(|(programs, _, _)| programs.contains(&program))?;
+                Some(Playback {
+                    sample: self.samples.get(file)?,
+                    pitch: 2f32.powf((key as f32 - *root_key as f32) / 12.0),
+                    one_shot: false,
+                })
+            }
+            None => {
+                let drum = Drum::of_key(key);
+                let (_, file) = KIT.iter().find(|(kit_drum, _)| *kit_drum == drum)?;
+                Some(Playback {
+                    sample: self.samples.get(file)?,
+                    pitch: 1.0,
+                    one_shot: true,
+                })
+            }
+        }
+    }
+}
+
+/// A sample about to be played for one note
+pub struct Playback<'a> {
+    sample: &'a Audio,
+    /// Speed relative to the recording, 2 plays an octave up
+    pitch: f32,
+    /// Drum hits play out whole however long the note is
+    one_shot: bool,
+}
+
+impl Playback<'_> {
+    /// Seconds the note keeps sounding when held for `held` seconds
+    pub fn length(&self, held: f32) -> f32 {
+        let sample = self.sample.frames() as f32 / self.sample.sample_rate as f32 / self.pitch;
+        if self.one_shot {
+            sample
+        } else {
+            sample.min(held + RELEASE)
+        }
+    }
+
+    /// Add the note to `left` and `right`, starting at their first frame
+    pub fn render(
+        &self,
+        velocity: f32,
+        held: f32,
+        sample_rate: u32,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        let rate = sample_rate as f32;
+        let step = self.pitch * self.sample.sample_rate as f32 / rate;
+        let last = self.sample.frames().saturating_sub(1);
+        for i in 0..left.len().min(right.len()) {
+            let position = i as f32 * step;
+            let index = position as usize;
+            if index >= last {
+                break;
+            }
+            let t = i as f32 / rate;
+            let gain = if self.one_shot || t < held {
+                velocity
+            } else {
+                velocity * (1.0 - (t - held) / RELEASE).max(0.0)
+            };
+            // Linear interpolation between the two closest recorded frames
+            let fraction = position - index as f32;
+            let at =
+                |channel: &[f32]| channel[index] + (channel[index + 1] - channel[index]) * fraction;
+            left[i] += at(&self.sample.left) * gain;
+            right[i] += at(&self.sample.right) * gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::wav::write_wav;
+
+    #[test]
+    fn test_read_wav() {
+        let mut audio = Audio::silence(22050, 4);
+        audio.left = vec![0.0, 0.5, -0.5, 1.0];
+        audio.right = vec![0.25, 0.0, 0.0, -1.0];
+        let decoded = read_wav(&write_wav(&audio)).unwrap();
+        assert_eq!(decoded.sample_rate, 22050);
+        for (decoded, original) in decoded.left.iter().zip(&audio.left) {
+            assert!((decoded - original).abs() < 1e-3);
+        }
+        assert!((decoded.right[0] - 0.25).abs() < 1e-3);
+
+        // Mono 24-bit PCM with an odd-sized chunk before the format
+        let mut bytes = b"RIFF\0\0\0\0WAVEJUNK\x01\0\0\0\0\0fmt ".to_vec();
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 1, 0]);
+        bytes.extend_from_slice(&48000u32.to_le_bytes());
+        bytes.extend_from_slice(&(48000u32 * 3).to_le_bytes());
+        bytes.extend_from_slice(&[3, 0, 24, 0]);
+        bytes.extend_from_slice(b"data\x06\0\0\0");
+        bytes.extend_from_slice(&[0, 0, 0x40, 0, 0, 0xc0]);
+        let decoded = read_wav(&bytes).unwrap();
+        assert_eq!(decoded.sample_rate, 48000);
+        assert_eq!(decoded.left, vec![0.5, -0.5]);
+        assert_eq!(decoded.right, decoded.left);
+
+        assert_eq!(read_wav(b"MThd"), Err(SampleError::NotWav));
+    }
+
+    #[test]
+    fn test_playback_follows_the_note() {
+        let mut pack = SoundPack::default();
+        pack.samples
+            .insert(PATCHES[0].1, Audio::silence(1000, 1000));
+        pack.samples.insert(KIT[0].1, Audio::silence(1000, 500));
+
+        // The piano sample is recorded at key 72, an octave up plays twice as fast
+        let root = pack.playback(0, 72).unwrap();
+        let octave_up = pack.playback(0, 84).unwrap();
+        assert_eq!(root.length(10.0), 1.0);
+        assert_eq!(octave_up.length(10.0), 0.5);
+        assert_eq!(root.length(0.1), 0.1 + RELEASE);
+
+        // Drum hits play out whatever the note length
+        assert_eq!(pack.playback(3, 36).unwrap().length(0.01), 0.5);
+        // No guitar sample loaded, no snare either
+        assert!(pack.playback(1, 60).is_none());
+        assert!(pack.playback(3, 38).is_none());
+    }
+}
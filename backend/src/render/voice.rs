@@ -0,0 +1,279 @@
+//! Oscillator voices the renderer plays notes with.
+//!
+//! Melodic tracks get a waveform and envelope picked from the General MIDI
+//! family of their instrument, drum kits get a few synthesized drums chosen by
+//! key the way the General MIDI percussion map lays them out.
+
+use std::f32::consts::TAU;
+
+use the_song_model::instrument;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    Saw,
+}
+
+impl Waveform {
+    /// Value at `phase`, counted in cycles, within -1..=1
+    fn sample(self, phase: f32) -> f32 {
+        let phase = phase.fract();
+        match self {
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Saw => 2.0 * phase - 1.0,
+        }
+    }
+}
+
+/// Attack, decay, sustain, release envelope, times in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    /// Level held after the decay, within 0..=1
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Envelope {
+    const fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        Self {
+            attack,
+            decay,
+            sustain,
+            release,
+        }
+    }
+
+    /// Level `t` seconds into a note held for `held` seconds
+    pub fn level(&self, t: f32, held: f32) -> f32 {
+        if t < held {
+            self.holding(t)
+        } else if self.release > 0.0 {
+            self.holding(held) * (1.0 - (t - held) / self.release).max(0.0)
+        } else {
+            0.0
+        }
+    }
+
+    fn holding(&self, t: f32) -> f32 {
+        if t < self.attack {
+            t / self.attack
+        } else if t < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Voice {
+    /// A pitched oscillator
+    Tone {
+        waveform: Waveform,
+        envelope: Envelope,
+        gain: f32,
+    },
+    /// Synthesized drums, the key picks the drum
+    Drums,
+}
+
+/// Voice the notes of `track` are played with
+pub fn track_voice(track: usize) -> Voice {
+    let Some(program) = instrument(track).program else {
+        return Voice::Drums;
+    };
+    let (waveform, envelope, gain) = match program {
+        // Pianos
+        0..=7 => (Waveform::Triangle, Envelope::new(0.005, 0.8, 0.3, 0.3), 1.0),
+        // Guitars
+        24..=31 => (Waveform::Triangle, Envelope::new(0.002, 0.5, 0.1, 0.2), 1.0),
+        // Basses
+        32..=39 => (Waveform::Square, Envelope::new(0.005, 0.3, 0.6, 0.1), 0.5),
+        // Strings and choirs
+        48..=55 => (Waveform::Saw, Envelope::new(0.15, 0.2, 0.8, 0.4), 0.4),
+        // Brass
+        56..=63 => (Waveform::Saw, Envelope::new(0.03, 0.1, 0.8, 0.15), 0.5),
+        // Pipes
+        72..=79 => (Waveform::Sine, Envelope::new(0.05, 0.1, 0.9, 0.15), 1.0),
+        // Synth leads
+        80..=87 => (Waveform::Square, Envelope::new(0.01, 0.1, 0.7, 0.1), 0.4),
+        // Synth pads
+        88..=95 => (Waveform::Saw, Envelope::new(0.2, 0.3, 0.7, 0.6), 0.4),
+        _ => (Waveform::Sine, Envelope::new(0.01, 0.2, 0.7, 0.2), 1.0),
+    };
+    Voice::Tone {
+        waveform,
+        envelope,
+        gain,
+    }
+}
+
+/// Frequency of a MIDI key in Hz
+pub fn key_frequency(key: u8) -> f32 {
+    440.0 * 2f32.powf((key as f32 - 69.0) / 12.0)
+}
+
+/// Drums of the General MIDI percussion map, close keys share a drum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Drum {
+    Kick,
+    Rim,
+    Snare,
+    Clap,
+    Tom,
+    ClosedHat,
+    OpenHat,
+    Cymbal,
+    Click,
+}
+
+impl Drum {
+    pub fn of_key(key: u8) -> Self {
+        match key {
+            0..=36 => Drum::Kick,
+            37 => Drum::Rim,
+            38 | 40 => Drum::Snare,
+            39 => Drum::Clap,
+            41 | 43 | 45 | 47 | 48 | 50 => Drum::Tom,
+            42 | 44 => Drum::ClosedHat,
+            46 => Drum::OpenHat,
+            49 | 51..=53 | 55 | 57 | 59 => Drum::Cymbal,
+            _ => Drum::Click,
+        }
+    }
+
+    /// Seconds until the hit has died away
+    fn length(self) -> f32 {
+        match self {
+            Drum::Kick => 0.5,
+            Drum::Rim => 0.1,
+            Drum::Snare => 0.3,
+            Drum::Clap => 0.3,
+            Drum::Tom => 0.5,
+            Drum::ClosedHat => 0.1,
+            Drum::OpenHat => 0.5,
+            Drum::Cymbal => 1.2,
+            Drum::Click => 0.2,
+        }
+    }
+}
+
+/// Small deterministic noise source, renders come out the same every time
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+impl Voice {
+    /// Seconds a note of `key` held for `held` seconds keeps sounding
+    pub fn length(&self, key: u8, held: f32) -> f32 {
+        match self {
+            Voice::Tone { envelope, .. } => held + envelope.release,
+            Voice::Drums => Drum::of_key(key).length(),
+        }
+    }
+
+    /// Add a note of `key` held for `held` seconds to `out`, starting at its first sample
+    pub fn render(&self, key: u8, velocity: f32, held: f32, sample_rate: u32, out: &mut [f32]) {
+        let rate = sample_rate as f32;
+        match *self {
+            Voice::Tone {
+                waveform,
+                envelope,
+                gain,
+            } => {
+                let frequency = key_frequency(key);
+                for (i, sample) in out.iter_mut().enumerate() {
+                    let t = i as f32 / rate;
+                    *sample +=
+                        waveform.sample(frequency * t) * envelope.level(t, held) * velocity * gain;
+                }
+            }
+            Voice::Drums => render_drum(Drum::of_key(key), key, velocity, rate, out),
+        }
+    }
+}
+
+fn render_drum(drum: Drum, key: u8, velocity: f32, rate: f32, out: &mut [f32]) {
+    let mut noise = Noise(0x9e37_79b9 ^ key as u32);
+    let mut previous_noise = 0.0;
+    let mut phase = 0.0;
+    for (i, sample) in out.iter_mut().enumerate() {
+        let t = i as f32 / rate;
+        let white = noise.next();
+        // Difference of two noise samples, a crude high-pass for metallic sounds
+        let bright = white - previous_noise;
+        previous_noise = white;
+        let value = match drum {
+            Drum::Kick => {
+                phase += (50.0 + 100.0 * (-t * 30.0).exp()) / rate;
+                (phase * TAU).sin() * (-t * 8.0).exp()
+            }
+            Drum::Snare => {
+                phase += 180.0 / rate;
+                (0.6 * white + 0.4 * (phase * TAU).sin()) * (-t * 18.0).exp()
+            }
+            Drum::Rim => {
+                phase += 450.0 / rate;
+                (0.3 * bright + 0.7 * (phase * TAU).sin()) * (-t * 60.0).exp()
+            }
+            Drum::Clap => 0.7 * white * (-t * 25.0).exp(),
+            Drum::Tom => {
+                let frequency = key_frequency(key) * (1.0 + 0.5 * (-t * 20.0).exp());
+                phase += frequency / rate;
+                (phase * TAU).sin() * (-t * 9.0).exp()
+            }
+            Drum::ClosedHat => 0.5 * bright * (-t * 60.0).exp(),
+            Drum::OpenHat => 0.5 * bright * (-t * 9.0).exp(),
+            Drum::Cymbal => 0.4 * bright * (-t * 4.0).exp(),
+            Drum::Click => {
+                phase += key_frequency(key) / rate;
+                (0.5 * white + 0.5 * (phase * TAU).sin()) * (-t * 30.0).exp()
+            }
+        };
+        *sample += value * velocity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let envelope = Envelope::new(0.1, 0.1, 0.5, 0.2);
+        assert_eq!(envelope.level(0.0, 1.0), 0.0);
+        assert!((envelope.level(0.05, 1.0) - 0.5).abs() < 1e-6);
+        assert!((envelope.level(0.1, 1.0) - 1.0).abs() < 1e-6);
+        assert!((envelope.level(0.5, 1.0) - 0.5).abs() < 1e-6);
+        // Released halfway through the release time
+        assert!((envelope.level(1.1, 1.0) - 0.25).abs() < 1e-6);
+        assert_eq!(envelope.level(2.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_track_voices() {
+        assert!(matches!(track_voice(0), Voice::Tone { .. }));
+        assert_eq!(track_voice(3), Voice::Drums);
+        assert!((key_frequency(69) - 440.0).abs() < 1e-3);
+        assert!((key_frequency(57) - 220.0).abs() < 1e-3);
+    }
+}
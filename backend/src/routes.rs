@@ -4,11 +4,82 @@ use tower_http::{
     trace::TraceLayer,
 };
 
-use crate::{state::AppState, ws};
+use crate::{handlers, state::AppState, ws};
 
 pub fn create_router(state: AppState) -> Router {
     Router::new()
         .route("/ws", axum::routing::get(ws::ws_handler))
+        .route("/ws/{room_id}", axum::routing::get(ws::room_ws_handler))
+        .route("/metrics", axum::routing::get(handlers::metrics))
+        .route("/song.mid", axum::routing::get(handlers::song_midi))
+        .route(
+            "/rooms/{room_id}/song.mid",
+            axum::routing::get(handlers::room_song_midi),
+        )
+        .route(
+            "/song.musicxml",
+            axum::routing::get(handlers::song_musicxml),
+        )
+        .route(
+            "/rooms/{room_id}/song.musicxml",
+            axum::routing::get(handlers::room_song_musicxml),
+        )
+        .route("/song.abc", axum::routing::get(handlers::song_abc))
+        .route(
+            "/rooms/{room_id}/song.abc",
+            axum::routing::get(handlers::room_song_abc),
+        )
+        .route("/song.ly", axum::routing::get(handlers::song_lilypond))
+        .route(
+            "/rooms/{room_id}/song.ly",
+            axum::routing::get(handlers::room_song_lilypond),
+        )
+        .route("/song.wav", axum::routing::get(handlers::song_wav))
+        .route(
+            "/rooms/{room_id}/song.wav",
+            axum::routing::get(handlers::room_song_wav),
+        )
+        .route("/stems.zip", axum::routing::get(handlers::song_stems))
+        .route(
+            "/rooms/{room_id}/stems.zip",
+            axum::routing::get(handlers::room_song_stems),
+        )
+        .route(
+            "/rooms/{room_id}/versions",
+            axum::routing::get(handlers::room_versions),
+        )
+        .route(
+            "/rooms/{room_id}/versions/{version}/song.mid",
+            axum::routing::get(handlers::room_version_midi),
+        )
+        .route(
+            "/rooms/{room_id}/fork",
+            axum::routing::post(handlers::fork_room),
+        )
+        .route(
+            "/rooms/{room_id}/origin",
+            axum::routing::get(handlers::room_origin),
+        )
+        .route(
+            "/rooms/{room_id}/timelapse",
+            axum::routing::get(handlers::room_timelapse_stream),
+        )
+        .route(
+            "/rooms/{room_id}/timelapse.ndjson",
+            axum::routing::get(handlers::room_timelapse_file),
+        )
+        .route(
+            "/admin/rooms/{room_id}/song.mid",
+            axum::routing::post(handlers::import_room_song_midi),
+        )
+        .route(
+            "/admin/rooms/{room_id}/users/{user_id}/revert",
+            axum::routing::post(handlers::revert_room_user),
+        )
+        .route(
+            "/admin/rooms/{room_id}/versions/{version}/restore",
+            axum::routing::post(handlers::restore_room_version),
+        )
         .with_state(state)
         .layer(TraceLayer::new_for_http())
         .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any))
@@ -0,0 +1,95 @@
+//! Coordinated graceful shutdown.
+//!
+//! On SIGTERM or Ctrl+C the server stops accepting WebSocket upgrades, tells
+//! every client it is going away, waits for connections to drain and writes
+//! the song to storage before exiting. Connections still open at the deadline
+//! are cut off first, so nothing changes a song while it is being written.
+
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+use crate::state::AppState;
+
+/// How long connections cut off at the deadline get to finish what they are doing
+const ABORT_GRACE: Duration = Duration::from_secs(1);
+
+/// Wait for Ctrl+C or SIGTERM
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received Ctrl+C"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// Run the shutdown sequence, giving up on stragglers once `deadline` has passed.
+///
+/// `server` is the task running `axum::serve` with `AppState::shutdown_token`
+/// as its graceful shutdown signal, `tasks` are the global background tasks.
+pub async fn shutdown(
+    state: AppState,
+    mut server: tokio::task::JoinHandle<()>,
+    mut tasks: Vec<tokio::task::JoinHandle<()>>,
+    deadline: Duration,
+) {
+    tracing::info!("Shutting down, deadline {:?}", deadline);
+    state.begin_shutdown().await;
+
+    let drain = async {
+        let _ = (&mut server).await;
+        for task in &mut tasks {
+            let _ = task.await;
+        }
+        wait_for_connections(&state).await;
+    };
+
+    if timeout(deadline, drain).await.is_err() {
+        tracing::warn!(
+            "Shutdown deadline exceeded with {} connections still open, closing them",
+            state.connection_count().await
+        );
+        server.abort();
+        for task in &tasks {
+            task.abort();
+        }
+        state.abort_connections();
+        if timeout(ABORT_GRACE, wait_for_connections(&state))
+            .await
+            .is_err()
+        {
+            tracing::error!(
+                "{} connections did not close, persisting anyway",
+                state.connection_count().await
+            );
+        }
+    }
+
+    match state.persist_rooms().await {
+        Ok(()) => tracing::info!("Songs persisted"),
+        Err(e) => tracing::error!("Failed to persist songs on shutdown: {}", e),
+    }
+    // Updates logged after a failed snapshot still have to reach the disk
+    state.flush_rooms().await;
+}
+
+async fn wait_for_connections(state: &AppState) {
+    while state.connection_count().await > 0 {
+        sleep(Duration::from_millis(50)).await;
+    }
+}
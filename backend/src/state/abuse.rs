@@ -0,0 +1,302 @@
+//! Abuse guard for the notes of a song.
+//!
+//! Every client update is compared with the live document while it is staged.
+//! Notes added and removed are counted per user over a sliding window, and an
+//! update is turned down when it pushes its author past a quota or when the
+//! author has removed too large a share of other people's notes.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+use super::identity::NoteAuthors;
+use crate::config::AbuseConfig;
+
+/// How an update changes the `notes` map
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoteChanges {
+    pub added: usize,
+    pub removed: usize,
+    /// Removed notes created by someone else
+    pub removed_others: usize,
+    /// Notes created by someone else before the update
+    pub others_before: usize,
+}
+
+/// Compare the notes before and after an update from the point of view of `user`
+pub fn note_changes(before: &NoteAuthors, after: &NoteAuthors, user: &str) -> NoteChanges {
+    let mut changes = NoteChanges {
+        added: after.keys().filter(|id| !before.contains_key(*id)).count(),
+        ..NoteChanges::default()
+    };
+    for (id, author) in before {
+        let by_others = author != user;
+        if by_others {
+            changes.others_before += 1;
+        }
+        if !after.contains_key(id) {
+            changes.removed += 1;
+            if by_others {
+                changes.removed_others += 1;
+            }
+        }
+    }
+    changes
+}
+
+/// Why an update was turned down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuotaViolation {
+    TooManyAdded { limit: usize },
+    TooManyRemoved { limit: usize },
+    MassDeletion { removed: usize, of: usize },
+}
+
+impl QuotaViolation {
+    /// Short label for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QuotaViolation::TooManyAdded { .. } => "added",
+            QuotaViolation::TooManyRemoved { .. } => "removed",
+            QuotaViolation::MassDeletion { .. } => "mass_deletion",
+        }
+    }
+}
+
+impl fmt::Display for QuotaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaViolation::TooManyAdded { limit } => {
+                write!(f, "more than {} notes added in a short time", limit)
+            }
+            QuotaViolation::TooManyRemoved { limit } => {
+                write!(f, "more than {} notes removed in a short time", limit)
+            }
+            QuotaViolation::MassDeletion { removed, of } => write!(
+                f,
+                "removing {} of {} notes by other users in a short time",
+                removed, of
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QuotaViolation {}
+
+struct Activity {
+    at: Instant,
+    added: usize,
+    removed: usize,
+    removed_others: usize,
+}
+
+struct ActivityTable {
+    users: HashMap<Uuid, VecDeque<Activity>>,
+    last_sweep: Instant,
+}
+
+/// Note quotas of the users of one room.
+///
+/// A user's activity is kept until it leaves the window, whether or not they
+/// are still connected, so reconnecting does not reset their quotas.
+pub struct AbuseGuard {
+    config: AbuseConfig,
+    activity: Mutex<ActivityTable>,
+}
+
+impl AbuseGuard {
+    pub fn new(config: AbuseConfig) -> Self {
+        Self {
+            config,
+            activity: Mutex::new(ActivityTable {
+                users: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Check `changes` made by `user_id` against the quotas and count them if they pass
+    pub fn check(&self, user_id: Uuid, changes: NoteChanges) -> Result<(), QuotaViolation> {
+        self.check_at(user_id, changes, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        user_id: Uuid,
+        changes: NoteChanges,
+        now: Instant,
+    ) -> Result<(), QuotaViolation> {
+        let config = &self.config;
+        let mut activity = self.activity.lock().unwrap();
+        if now.saturating_duration_since(activity.last_sweep) >= config.window() {
+            // Users whose last change left the window have nothing left to count
+            activity.users.retain(|_, window| {
+                window
+                    .back()
+                    .is_some_and(|a| now.saturating_duration_since(a.at) < config.window())
+            });
+            activity.last_sweep = now;
+        }
+        let window = activity.users.entry(user_id).or_default();
+        while window
+            .front()
+            .is_some_and(|a| now.saturating_duration_since(a.at) >= config.window())
+        {
+            window.pop_front();
+        }
+
+        let added = window.iter().map(|a| a.added).sum::<usize>() + changes.added;
+        let removed = window.iter().map(|a| a.removed).sum::<usize>() + changes.removed;
+        let earlier_removed_others = window.iter().map(|a| a.removed_others).sum::<usize>();
+        let removed_others = earlier_removed_others + changes.removed_others;
+
+        if config.max_notes_added > 0 && changes.added > 0 && added > config.max_notes_added {
+            return Err(QuotaViolation::TooManyAdded {
+                limit: config.max_notes_added,
+            });
+        }
+        if config.max_notes_removed > 0 && changes.removed > 0 && removed > config.max_notes_removed
+        {
+            return Err(QuotaViolation::TooManyRemoved {
+                limit: config.max_notes_removed,
+            });
+        }
+        // Measured against the other users' notes as they were when the window started
+        let others = changes.others_before + earlier_removed_others;
+        if config.mass_delete_min_notes > 0
+            && changes.removed_others > 0
+            && removed_others >= config.mass_delete_min_notes
+            && removed_others as f64 > config.mass_delete_share * others as f64
+        {
+            return Err(QuotaViolation::MassDeletion {
+                removed: removed_others,
+                of: others,
+            });
+        }
+
+        if changes != NoteChanges::default() {
+            window.push_back(Activity {
+                at: now,
+                added: changes.added,
+                removed: changes.removed,
+                removed_others: changes.removed_others,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::identity::fixtures::authors;
+    use super::*;
+    use std::time::Duration;
+
+    fn changes(
+        added: usize,
+        removed: usize,
+        removed_others: usize,
+        others_before: usize,
+    ) -> NoteChanges {
+        NoteChanges {
+            added,
+            removed,
+            removed_others,
+            others_before,
+        }
+    }
+
+    #[test]
+    fn test_note_changes() {
+        let before = authors(&[("mine", "me"), ("theirs", "them"), ("kept", "them")]);
+        let after = authors(&[("kept", "them"), ("new", "me")]);
+        assert_eq!(note_changes(&before, &after, "me"), changes(1, 2, 1, 2));
+    }
+
+    #[test]
+    fn test_quotas_over_window() {
+        let guard = AbuseGuard::new(AbuseConfig {
+            window_secs: 10,
+            max_notes_added: 5,
+            ..AbuseConfig::default()
+        });
+        let user = Uuid::now_v7();
+        let now = Instant::now();
+
+        assert!(guard.check_at(user, changes(3, 0, 0, 0), now).is_ok());
+        assert_eq!(
+            guard.check_at(user, changes(3, 0, 0, 0), now),
+            Err(QuotaViolation::TooManyAdded { limit: 5 })
+        );
+        // Removing is still fine while adding is over the quota
+        assert!(guard.check_at(user, changes(0, 1, 0, 0), now).is_ok());
+        // Someone else has their own quota
+        assert!(guard
+            .check_at(Uuid::now_v7(), changes(5, 0, 0, 0), now)
+            .is_ok());
+
+        let later = now + Duration::from_secs(10);
+        assert!(guard.check_at(user, changes(5, 0, 0, 0), later).is_ok());
+    }
+
+    #[test]
+    fn test_quotas_outlive_the_connection() {
+        let guard = AbuseGuard::new(AbuseConfig {
+            window_secs: 10,
+            max_notes_added: 5,
+            ..AbuseConfig::default()
+        });
+        let user = Uuid::now_v7();
+        let now = Instant::now();
+
+        let start = now + Duration::from_secs(8);
+        assert!(guard.check_at(user, changes(3, 0, 0, 0), start).is_ok());
+        // Another user's update sweeps the table, the first one reconnects
+        // and still has the same quota
+        let soon = now + Duration::from_secs(12);
+        assert!(guard
+            .check_at(Uuid::now_v7(), changes(1, 0, 0, 0), soon)
+            .is_ok());
+        assert_eq!(
+            guard.check_at(user, changes(3, 0, 0, 0), soon),
+            Err(QuotaViolation::TooManyAdded { limit: 5 })
+        );
+
+        // Once the window is over the sweep forgets idle users
+        let later = now + Duration::from_secs(30);
+        assert!(guard
+            .check_at(Uuid::now_v7(), changes(1, 0, 0, 0), later)
+            .is_ok());
+        assert!(!guard.activity.lock().unwrap().users.contains_key(&user));
+    }
+
+    #[test]
+    fn test_mass_deletion() {
+        let guard = AbuseGuard::new(AbuseConfig {
+            mass_delete_min_notes: 10,
+            mass_delete_share: 0.25,
+            ..AbuseConfig::default()
+        });
+        let user = Uuid::now_v7();
+        let now = Instant::now();
+
+        // Clearing your own notes is never a mass deletion
+        assert!(guard.check_at(user, changes(0, 50, 0, 100), now).is_ok());
+        // A few notes of others in a small song are fine
+        assert!(guard.check_at(user, changes(0, 8, 8, 20), now).is_ok());
+        // Chipping away in small steps adds up
+        assert_eq!(
+            guard.check_at(user, changes(0, 4, 4, 12), now),
+            Err(QuotaViolation::MassDeletion {
+                removed: 12,
+                of: 20
+            })
+        );
+        // The same share of a large song is still fine
+        assert!(guard
+            .check_at(Uuid::now_v7(), changes(0, 12, 12, 100), now)
+            .is_ok());
+    }
+}
@@ -0,0 +1,93 @@
+//! Version history of a song.
+//!
+//! The live document and the stored snapshot keep every change, only the
+//! snapshot sent to clients is shallow. A version is named after the last op
+//! of a change, `counter@peer`, and stands for the song as the author of that
+//! change saw it: the change and everything it builds on.
+
+use loro::{Frontiers, Lamport, LoroDoc, PeerID, ID};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use uuid::Uuid;
+
+/// One change in the history of a song
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub id: ID,
+    pub lamport: Lamport,
+    /// Unix seconds, `None` when the author did not record it
+    pub timestamp: Option<i64>,
+    /// User the change's peer belongs to, `None` for the server's own edits
+    pub author: Option<Uuid>,
+    pub ops: usize,
+}
+
+/// The latest `limit` versions of `doc`, newest first
+pub fn versions(doc: &LoroDoc, owners: &HashMap<PeerID, Uuid>, limit: usize) -> Vec<Version> {
+    let mut versions = Vec::new();
+    if limit == 0 {
+        return versions;
+    }
+    let heads = doc.oplog_frontiers().to_vec();
+    let result = doc.travel_change_ancestors(&heads, &mut |change| {
+        versions.push(Version {
+            id: ID::new(change.id.peer, change.id.counter + change.len as i32 - 1),
+            lamport: change.lamport,
+            timestamp: (change.timestamp > 0).then_some(change.timestamp),
+            author: owners.get(&change.id.peer).copied(),
+            ops: change.len,
+        });
+        if versions.len() < limit {
+            ControlFlow::Continue(())
+        } else {
+            ControlFlow::Break(())
+        }
+    });
+    if let Err(e) = result {
+        tracing::debug!("Stopped walking the song history: {}", e);
+    }
+    versions
+}
+
+/// Frontiers of a version named `counter@peer`, `None` if the song does not have it
+pub fn parse_version(doc: &LoroDoc, version: &str) -> Option<Frontiers> {
+    let id = ID::try_from(version).ok()?;
+    doc.oplog_vv()
+        .includes_id(id)
+        .then(|| Frontiers::from(vec![id]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use the_song_model::schema;
+
+    #[test]
+    fn test_versions() {
+        let doc = LoroDoc::new();
+        doc.set_peer_id(1).unwrap();
+        doc.set_record_timestamp(true);
+        schema::notes_map(&doc).insert("a", 1).unwrap();
+        doc.commit();
+        let first = doc.oplog_frontiers();
+        doc.set_peer_id(2).unwrap();
+        schema::notes_map(&doc).insert("b", 2).unwrap();
+        schema::notes_map(&doc).insert("c", 3).unwrap();
+        doc.commit();
+
+        let user = Uuid::now_v7();
+        let owners = HashMap::from([(2, user)]);
+        let listed = versions(&doc, &owners, 10);
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].id, ID::new(2, 1));
+        assert_eq!((listed[0].author, listed[0].ops), (Some(user), 2));
+        assert!(listed[0].timestamp.is_some());
+        assert_eq!(listed[1].id, ID::new(1, 0));
+        assert_eq!(listed[1].author, None);
+        assert_eq!(versions(&doc, &owners, 1).len(), 1);
+
+        assert_eq!(parse_version(&doc, "0@1"), Some(first));
+        assert_eq!(parse_version(&doc, "5@1"), None);
+        assert_eq!(parse_version(&doc, "latest"), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! Who wrote what.
+//!
+//! Every connection is a user with a server-assigned ID. The first update that
+//! uses a Loro peer without ops in the song binds the peer to its sender, and
+//! from then on only that user may send ops as the peer. Notes a user adds
+//! must name them in `createdBy`, and nobody may change the author of an
+//! existing note. Reconnecting clients present the resume token of their last
+//! welcome to stay the same user and keep their peers.
+
+use loro::{LoroDoc, PeerID};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use the_song_model::{note_author, schema};
+
+/// How long a resume token stays valid
+const RESUME_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `createdBy` of every note by note ID
+pub type NoteAuthors = HashMap<String, String>;
+
+/// Why an update was refused as not the sender's own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthorshipViolation {
+    /// The update has ops of a peer that belongs to someone else, or to nobody
+    ForeignPeer(PeerID),
+    /// The update adds a note in someone else's name or changes a note's author
+    ForgedAuthor(String),
+}
+
+impl fmt::Display for AuthorshipViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthorshipViolation::ForeignPeer(peer) => {
+                write!(f, "Loro peer {} belongs to another user", peer)
+            }
+            AuthorshipViolation::ForgedAuthor(note) => {
+                write!(f, "createdBy of note {} is not the sender", note)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthorshipViolation {}
+
+/// Loro peers that have ops in an encoded update
+pub fn update_peers(update: &[u8]) -> Result<Vec<PeerID>, loro::LoroError> {
+    let meta = LoroDoc::decode_import_blob_meta(update, false)?;
+    Ok(meta
+        .partial_end_vv
+        .iter()
+        .filter(|(peer, end)| **end > meta.partial_start_vv.get(peer).copied().unwrap_or(0))
+        .map(|(peer, _)| *peer)
+        .collect())
+}
+
+/// Of `peers`, those `user_id` claims for the first time.
+///
+/// `known` is the version vector of the song, a peer with ops in it that is
+/// not in `owners` was used by the server or before peers were tracked.
+pub fn unclaimed_peers(
+    peers: &[PeerID],
+    user_id: Uuid,
+    owners: &HashMap<PeerID, Uuid>,
+    known: &loro::VersionVector,
+) -> Result<Vec<PeerID>, AuthorshipViolation> {
+    let mut unclaimed = Vec::new();
+    for &peer in peers {
+        match owners.get(&peer) {
+            Some(owner) if *owner == user_id => {}
+            Some(_) => return Err(AuthorshipViolation::ForeignPeer(peer)),
+            None if known.get(&peer).is_some() => {
+                return Err(AuthorshipViolation::ForeignPeer(peer))
+            }
+            None => unclaimed.push(peer),
+        }
+    }
+    Ok(unclaimed)
+}
+
+/// `createdBy` of every note in the song, empty when missing
+pub fn note_authors(doc: &LoroDoc) -> NoteAuthors {
+    let mut authors = HashMap::new();
+    schema::notes_map(doc).for_each(|id, note| {
+        authors.insert(id.to_string(), note_author(&note));
+    });
+    authors
+}
+
+/// Check that `user` added notes only in their own name and left other authors alone
+pub fn check_authors(
+    before: &NoteAuthors,
+    after: &NoteAuthors,
+    user: &str,
+) -> Result<(), AuthorshipViolation> {
+    for (note, author) in after {
+        let forged = match before.get(note) {
+            Some(previous) => previous != author,
+            None => author != user,
+        };
+        if forged {
+            return Err(AuthorshipViolation::ForgedAuthor(note.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Tokens that let a reconnecting client stay the same user
+#[derive(Default)]
+pub struct ResumeTokens {
+    tokens: Mutex<HashMap<Uuid, (Uuid, Instant)>>,
+}
+
+impl ResumeTokens {
+    /// A fresh token for `user_id`
+    pub fn issue(&self, user_id: Uuid) -> Uuid {
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, (_, issued)| now.duration_since(*issued) < RESUME_TOKEN_TTL);
+        let token = Uuid::new_v4();
+        tokens.insert(token, (user_id, now));
+        token
+    }
+
+    /// The user a token was issued to, each token works once
+    pub fn redeem(&self, token: &str) -> Option<Uuid> {
+        let token = Uuid::parse_str(token).ok()?;
+        let (user_id, issued) = self.tokens.lock().unwrap().remove(&token)?;
+        (issued.elapsed() < RESUME_TOKEN_TTL).then_some(user_id)
+    }
+}
+
+/// Note authors for the tests of the modules that compare them
+#[cfg(test)]
+pub(super) mod fixtures {
+    use super::NoteAuthors;
+
+    pub fn authors(notes: &[(&str, &str)]) -> NoteAuthors {
+        notes
+            .iter()
+            .map(|(note, author)| (note.to_string(), author.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::authors;
+    use super::*;
+
+    #[test]
+    fn test_update_peers() {
+        let doc = LoroDoc::new();
+        doc.set_peer_id(7).unwrap();
+        schema::notes_map(&doc).insert("a", 1).unwrap();
+        doc.commit();
+        let before = doc.oplog_vv();
+        schema::notes_map(&doc).insert("b", 2).unwrap();
+        doc.commit();
+
+        let update = doc.export(loro::ExportMode::updates(&before)).unwrap();
+        assert_eq!(update_peers(&update).unwrap(), vec![7]);
+        assert!(update_peers(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_unclaimed_peers() {
+        let me = Uuid::now_v7();
+        let owners = HashMap::from([(1, me), (2, Uuid::now_v7())]);
+        let mut known = loro::VersionVector::new();
+        known.insert(1, 5);
+        known.insert(2, 5);
+        known.insert(3, 5);
+
+        assert_eq!(unclaimed_peers(&[1, 4], me, &owners, &known), Ok(vec![4]));
+        assert_eq!(
+            unclaimed_peers(&[2], me, &owners, &known),
+            Err(AuthorshipViolation::ForeignPeer(2))
+        );
+        // Ops nobody owns, like the server's own edits, cannot be taken over
+        assert_eq!(
+            unclaimed_peers(&[3], me, &owners, &known),
+            Err(AuthorshipViolation::ForeignPeer(3))
+        );
+    }
+
+    #[test]
+    fn test_check_authors() {
+        let before = authors(&[("theirs", "them")]);
+        assert!(check_authors(
+            &before,
+            &authors(&[("theirs", "them"), ("new", "me")]),
+            "me"
+        )
+        .is_ok());
+        // Removing notes is a matter for the abuse guard
+        assert!(check_authors(&before, &authors(&[]), "me").is_ok());
+        assert_eq!(
+            check_authors(&before, &authors(&[("theirs", "me")]), "me"),
+            Err(AuthorshipViolation::ForgedAuthor("theirs".to_string()))
+        );
+        assert_eq!(
+            check_authors(
+                &before,
+                &authors(&[("theirs", "them"), ("new", "them")]),
+                "me"
+            ),
+            Err(AuthorshipViolation::ForgedAuthor("new".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resume_tokens() {
+        let tokens = ResumeTokens::default();
+        let user_id = Uuid::now_v7();
+        let token = tokens.issue(user_id).to_string();
+
+        assert_eq!(tokens.redeem(&token), Some(user_id));
+        assert_eq!(tokens.redeem(&token), None);
+        assert_eq!(tokens.redeem("not a token"), None);
+    }
+}
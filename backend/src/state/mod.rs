@@ -1,13 +1,46 @@
-use axum::extract::ws::Message;
+mod abuse;
+mod history;
+mod identity;
+mod moderation;
+mod room;
+mod storage;
+mod timelapse;
+mod validation;
+
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use axum::http::HeaderMap;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Arc, Mutex,
 };
-use tokio::sync::{mpsc::UnboundedSender, RwLock};
+use std::time::Duration;
+use tokio::sync::{
+    mpsc::{error::TrySendError, Sender},
+    OwnedSemaphorePermit, RwLock, Semaphore,
+};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+use crate::config::{Config, SongConfig};
+use crate::metrics::metrics;
+use crate::rate_limit::{self, RateLimiter};
+use crate::render::sampler::SoundPack;
+use the_song_model::{SongDimensions, TrackConfig};
+
+pub use abuse::QuotaViolation;
+pub use history::Version;
+pub use identity::AuthorshipViolation;
+pub use room::{ForkError, Room, RoomError, RoomManager, DEFAULT_ROOM_ID};
+pub use storage::{
+    FileStorageProvider, ForkOrigin, SongStorage, StorageError, StorageProvider, StorageWriter,
+};
+pub use timelapse::TimelapseEvent;
+pub use validation::{SchemaViolation, MAX_BPM, MAX_SONG_BEATS, MIN_BPM};
+
 pub struct ServerStats {
     online_users: AtomicU32,
 }
@@ -23,8 +56,40 @@ pub struct MouseTracker {
     positions: RwLock<HashMap<Uuid, MousePosition>>,
 }
 
+/// How a broadcast treats a client whose send queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Skip the client, a fresher frame follows soon (mouse positions, stats)
+    Droppable,
+    /// The client must not miss it, disconnect the client instead so it resyncs
+    Reliable,
+}
+
+/// Send side of a client connection
+pub struct ClientSender {
+    queue: Sender<Message>,
+    // Cancelled when the client fell too far behind and must be disconnected
+    lagged: CancellationToken,
+    // Cancelled when a newer connection resumed the same user
+    superseded: CancellationToken,
+}
+
+impl ClientSender {
+    pub fn new(
+        queue: Sender<Message>,
+        lagged: CancellationToken,
+        superseded: CancellationToken,
+    ) -> Self {
+        Self {
+            queue,
+            lagged,
+            superseded,
+        }
+    }
+}
+
 pub struct ConnectionRegistry {
-    connections: RwLock<HashMap<Uuid, UnboundedSender<Message>>>,
+    connections: RwLock<HashMap<Uuid, ClientSender>>,
 }
 
 impl ServerStats {
@@ -41,14 +106,6 @@ impl ServerStats {
     }
 }
 
-#[derive(Clone)]
-pub struct AppState {
-    stats: Arc<ServerStats>,
-    mouse_tracker: Arc<MouseTracker>,
-    synthesizer: Arc<SynthesizerState>,
-    connections: Arc<ConnectionRegistry>,
-}
-
 impl MouseTracker {
     pub fn new() -> Self {
         Self {
@@ -96,9 +153,11 @@ impl ConnectionRegistry {
         }
     }
 
-    pub async fn register(&self, user_id: Uuid, sender: UnboundedSender<Message>) {
+    pub async fn register(&self, user_id: Uuid, sender: ClientSender) {
         let mut connections = self.connections.write().await;
-        connections.insert(user_id, sender);
+        if let Some(previous) = connections.insert(user_id, sender) {
+            previous.superseded.cancel();
+        }
         tracing::debug!(
             "Registered connection {}, total: {}",
             user_id,
@@ -106,36 +165,74 @@ impl ConnectionRegistry {
         );
     }
 
-    pub async fn unregister(&self, user_id: &Uuid) {
+    /// Remove the connection of `user_id` if it is the one sending to `queue`.
+    ///
+    /// Returns `false` if a newer connection of the user took its place.
+    pub async fn unregister(&self, user_id: &Uuid, queue: &Sender<Message>) -> bool {
         let mut connections = self.connections.write().await;
+        if !connections
+            .get(user_id)
+            .is_some_and(|sender| sender.queue.same_channel(queue))
+        {
+            return false;
+        }
         connections.remove(user_id);
         tracing::debug!(
             "Unregistered connection {}, total: {}",
             user_id,
             connections.len()
         );
+        true
+    }
+
+    /// Disconnect the connection of `user_id` so a resuming one can take over
+    pub async fn evict(&self, user_id: &Uuid) {
+        if let Some(sender) = self.connections.write().await.remove(user_id) {
+            sender.superseded.cancel();
+            tracing::debug!("Evicted connection {}, resumed elsewhere", user_id);
+        }
     }
 
-    pub async fn broadcast(&self, message: Message) {
+    pub async fn broadcast(&self, message: Message, delivery: Delivery) {
+        let _timer = metrics().broadcast_seconds.start_timer();
         let mut dead_connections = Vec::new();
+        let mut lagging_connections = Vec::new();
+        let mut dropped = 0;
 
         // Scope the read lock
         {
             let connections = self.connections.read().await;
             for (user_id, sender) in connections.iter() {
-                if sender.send(message.clone()).is_err() {
-                    dead_connections.push(*user_id);
+                match sender.queue.try_send(message.clone()) {
+                    Ok(()) => {}
+                    Err(TrySendError::Closed(_)) => dead_connections.push(*user_id),
+                    Err(TrySendError::Full(_)) => match delivery {
+                        Delivery::Droppable => dropped += 1,
+                        Delivery::Reliable => lagging_connections.push(*user_id),
+                    },
                 }
             }
         } // Read lock is dropped here
 
-        // Clean up dead connections if any were found
-        if !dead_connections.is_empty() {
+        if dropped > 0 {
+            metrics().frames_dropped.inc_by(dropped);
+            tracing::trace!("Dropped a frame for {} slow connections", dropped);
+        }
+
+        // Clean up dead and lagging connections if any were found
+        if !dead_connections.is_empty() || !lagging_connections.is_empty() {
             let mut connections = self.connections.write().await;
             for user_id in dead_connections {
                 connections.remove(&user_id);
                 tracing::debug!("Removed dead connection {} during broadcast", user_id);
             }
+            for user_id in lagging_connections {
+                if let Some(sender) = connections.remove(&user_id) {
+                    sender.lagged.cancel();
+                    metrics().slow_consumer_disconnects.inc();
+                    tracing::warn!("Disconnecting {}, its send queue is full", user_id);
+                }
+            }
         }
     }
 
@@ -143,150 +240,339 @@ impl ConnectionRegistry {
         let connections = self.connections.read().await;
         connections.len()
     }
+
+    /// Frames waiting in each connection's send queue
+    pub async fn queue_depths(&self) -> Vec<usize> {
+        let connections = self.connections.read().await;
+        connections
+            .values()
+            .map(|sender| sender.queue.max_capacity() - sender.queue.capacity())
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    rooms: Arc<RoomManager>,
+    shutdown: CancellationToken,
+    // Cancelled when the connections left after the shutdown deadline are cut off
+    abort: CancellationToken,
+    config: Arc<Config>,
+    rate_limiter: Arc<RateLimiter>,
+    sound_pack: Arc<SoundPack>,
+    renders: Arc<Semaphore>,
+}
+
+/// Why an audio render was not started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderRefused {
+    /// `render.max_concurrent` renders are already running
+    Busy,
+    /// The client asked for too many renders
+    RateLimited,
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    pub fn new(rooms: RoomManager, config: Config, sound_pack: SoundPack) -> Self {
         Self {
-            stats: Arc::new(ServerStats::new()),
-            mouse_tracker: Arc::new(MouseTracker::new()),
-            synthesizer: Arc::new(SynthesizerState::new()),
-            connections: Arc::new(ConnectionRegistry::new()),
+            rooms: Arc::new(rooms),
+            shutdown: CancellationToken::new(),
+            abort: CancellationToken::new(),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits.clone())),
+            renders: Arc::new(Semaphore::new(config.render.max_concurrent)),
+            config: Arc::new(config),
+            sound_pack: Arc::new(sound_pack),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Samples songs are rendered with
+    pub fn sound_pack(&self) -> &Arc<SoundPack> {
+        &self.sound_pack
+    }
+
+    /// Limits shared by the connections of each client IP
+    pub fn rate_limiter(&self) -> &Arc<RateLimiter> {
+        &self.rate_limiter
+    }
+
+    /// Claim a render slot for `ip`, the render may run while the permit is held
+    pub fn start_render(&self, ip: IpAddr) -> Result<OwnedSemaphorePermit, RenderRefused> {
+        let permit = self
+            .renders
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| RenderRefused::Busy)?;
+        if !self.rate_limiter.take_render(ip) {
+            return Err(RenderRefused::RateLimited);
         }
+        Ok(permit)
     }
 
-    pub fn increment_users(&self) {
-        self.stats.online_users.fetch_add(1, Ordering::SeqCst);
+    /// IP a request counts against in the rate limits
+    pub fn client_ip(&self, addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let trust_forwarded_for = self.config.rate_limits.trust_forwarded_for;
+        rate_limit::client_ip(addr, headers, trust_forwarded_for)
     }
 
-    pub fn decrement_users(&self) {
-        self.stats.online_users.fetch_sub(1, Ordering::SeqCst);
+    /// Check a bearer token against the admin token in constant time.
+    ///
+    /// Admin endpoints are disabled without a configured token.
+    pub fn is_admin_token(&self, token: &str) -> bool {
+        let Some(admin_token) = &self.config.server.admin_token else {
+            return false;
+        };
+        admin_token.len() == token.len()
+            && admin_token
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
     }
 
-    pub fn get_server_stats(&self) -> the_song_protocol::ServerStats {
-        self.stats.get_snapshot()
+    /// Token cancelled once the server starts shutting down
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
     }
 
-    pub async fn get_synthesizer_snapshot(&self) -> Result<Vec<u8>, loro::LoroEncodeError> {
-        self.synthesizer.get_snapshot().await
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
     }
 
-    pub async fn apply_synthesizer_update(&self, update: Vec<u8>) {
-        if let Err(e) = self.synthesizer.apply_update(update.clone()).await {
-            tracing::error!("Failed to apply synthesizer update: {}", e);
-            return;
+    /// Cancelled once the connections still open at the shutdown deadline must close
+    pub fn abort_token(&self) -> CancellationToken {
+        self.abort.clone()
+    }
+
+    /// Close every connection without waiting for its client
+    pub fn abort_connections(&self) {
+        self.abort.cancel();
+    }
+
+    /// Stop accepting connections and tell every client the server is going away
+    pub async fn begin_shutdown(&self) {
+        self.shutdown.cancel();
+        let message = Message::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: "Server is shutting down".into(),
+        }));
+        for room in self.rooms.loaded_rooms().await {
+            room.broadcast(message.clone(), Delivery::Reliable).await;
         }
+    }
 
-        // Broadcast update to all connected clients (binary format)
-        let msg = crate::dto::create_synthesizer_update_message(update);
-        let bytes = crate::dto::encode_server_message(&msg);
-        self.broadcast(Message::Binary(bytes.into())).await;
+    /// Get a room, loading it from storage or creating it on first use
+    pub async fn room(&self, room_id: &str) -> Result<Arc<Room>, RoomError> {
+        self.rooms.get_or_create(room_id, || true).await
     }
 
-    pub async fn update_mouse(&self, user_id: Uuid, x: f32, y: f32, vx: f32, vy: f32) {
-        self.mouse_tracker
-            .update_position(user_id, x, y, vx, vy)
-            .await;
+    /// Like `room`, but a new room counts against the room creations of `ip`
+    pub async fn room_for(&self, room_id: &str, ip: IpAddr) -> Result<Arc<Room>, RoomError> {
+        let rate_limiter = self.rate_limiter.clone();
+        self.rooms
+            .get_or_create(room_id, move || rate_limiter.take_room(ip))
+            .await
     }
 
-    pub async fn remove_mouse(&self, user_id: &Uuid) {
-        self.mouse_tracker.remove_user(user_id).await;
+    /// Like `room`, but never creates a room that does not exist yet
+    pub async fn existing_room(&self, room_id: &str) -> Result<Option<Arc<Room>>, RoomError> {
+        self.rooms.get_existing(room_id).await
     }
 
-    pub async fn get_dirty_mouse_positions(&self) -> HashMap<Uuid, MousePosition> {
-        self.mouse_tracker.get_dirty_positions().await
+    /// Start a new room with a copy of the song of `source`, counted against
+    /// the room creations of `ip`
+    pub async fn fork_room(
+        &self,
+        source: &Room,
+        version: Option<&str>,
+        room_id: &str,
+        ip: IpAddr,
+    ) -> Result<Arc<Room>, ForkError> {
+        let rate_limiter = self.rate_limiter.clone();
+        self.rooms
+            .fork(source, version, room_id, move || rate_limiter.take_room(ip))
+            .await
     }
 
-    pub async fn register_connection(&self, user_id: Uuid, sender: UnboundedSender<Message>) {
-        self.connections.register(user_id, sender).await;
+    pub async fn loaded_rooms(&self) -> Vec<Arc<Room>> {
+        self.rooms.loaded_rooms().await
     }
 
-    pub async fn unregister_connection(&self, user_id: &Uuid) {
-        self.connections.unregister(user_id).await;
+    pub async fn unload_idle_rooms(&self, idle_timeout: Duration) {
+        self.rooms.unload_idle(idle_timeout).await;
     }
 
-    pub async fn broadcast(&self, message: Message) {
-        self.connections.broadcast(message).await;
+    /// Persist every loaded room, carrying on past rooms that fail
+    pub async fn persist_rooms(&self) -> Result<(), StorageError> {
+        let mut result = Ok(());
+        for room in self.rooms.loaded_rooms().await {
+            if let Err(e) = room.persist_synthesizer().await {
+                tracing::error!("Failed to persist room {}: {}", room.id(), e);
+                result = Err(e);
+            }
+        }
+        result
     }
 
+    /// Wait until the writes queued for every loaded room are in storage
+    pub async fn flush_rooms(&self) {
+        for room in self.rooms.loaded_rooms().await {
+            room.flush_synthesizer().await;
+        }
+    }
+
+    /// Total number of connections across all rooms
     pub async fn connection_count(&self) -> usize {
-        self.connections.connection_count().await
+        let mut count = 0;
+        for room in self.rooms.loaded_rooms().await {
+            count += room.connection_count().await;
+        }
+        count
     }
 }
 
 pub struct SynthesizerState {
     docs: RwLock<loro::LoroDoc>,
+    storage: Arc<dyn SongStorage>,
+    writer: StorageWriter,
+    dimensions: SongDimensions,
+    // Updates queued for the log since the last snapshot
+    logged_updates: AtomicUsize,
+    // Copy of the live document that updates are staged on, kept in step with
+    // it so staging an update does not copy the whole song
+    staged: Mutex<loro::LoroDoc>,
+    // User each Loro peer belongs to
+    peers: Mutex<HashMap<loro::PeerID, Uuid>>,
 }
 
-// Total number of pitches (5 octaves * 12 notes per octave)
-const TOTAL_PITCHES: usize = 60;
-// Number of tracks in a song
-const NUM_TRACKS: usize = 16;
-
-// Default accent colors for tracks (16 distinct colors)
-const DEFAULT_ACCENT_COLORS: [&str; 16] = [
-    "#00ff88", // Mint green
-    "#ff6b6b", // Coral red
-    "#4ecdc4", // Turquoise
-    "#ffe66d", // Yellow
-    "#a8dadc", // Light blue
-    "#ff69b4", // Hot pink
-    "#98d8c8", // Seafoam
-    "#f7b731", // Orange
-    "#a29bfe", // Lavender
-    "#fd79a8", // Pink
-    "#74b9ff", // Sky blue
-    "#55efc4", // Aqua
-    "#fdcb6e", // Gold
-    "#e17055", // Terra cotta
-    "#81ecec", // Cyan
-    "#a29bfe", // Purple
-];
+#[derive(Debug)]
+pub enum UpdateError {
+    Import(loro::LoroError),
+    Export(loro::LoroEncodeError),
+    Invalid(SchemaViolation),
+    Quota(QuotaViolation),
+    Forged(AuthorshipViolation),
+}
 
-impl SynthesizerState {
-    pub fn new() -> Self {
-        let docs = loro::LoroDoc::new();
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::Import(e) => write!(f, "failed to import update: {}", e),
+            UpdateError::Export(e) => write!(f, "failed to export update: {}", e),
+            UpdateError::Invalid(v) => write!(f, "update breaks the song schema: {}", v),
+            UpdateError::Quota(v) => write!(f, "update is over the note quota: {}", v),
+            UpdateError::Forged(v) => write!(f, "update is not the sender's own: {}", v),
+        }
+    }
+}
 
-        // Initialize BPM counter
-        let counter = docs.get_counter("bpm");
-        counter
-            .increment(120.0)
-            .expect("Failed to increment counter");
-
-        // Initialize notes map (will be empty initially)
-        let _notes = docs.get_map("notes");
-
-        // Initialize 16 tracks, each with 48 pitch lists
-        let tracks = docs.get_list("tracks");
-        for track_index in 0..NUM_TRACKS {
-            let track_list = tracks
-                .insert_container(track_index, loro::LoroList::new())
-                .expect("Failed to create track list");
-            // Initialize empty lists for each pitch
-            for pitch in 0..TOTAL_PITCHES {
-                track_list
-                    .insert_container(pitch, loro::LoroList::new())
-                    .expect("Failed to create pitch list");
-            }
+impl std::error::Error for UpdateError {}
+
+impl UpdateError {
+    /// Short label for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UpdateError::Import(_) => "import",
+            UpdateError::Export(_) => "export",
+            UpdateError::Invalid(_) => "invalid",
+            UpdateError::Quota(_) => "quota",
+            UpdateError::Forged(_) => "forged",
         }
+    }
+}
+
+impl From<loro::LoroError> for UpdateError {
+    fn from(e: loro::LoroError) -> Self {
+        UpdateError::Import(e)
+    }
+}
+
+impl From<loro::LoroEncodeError> for UpdateError {
+    fn from(e: loro::LoroEncodeError) -> Self {
+        UpdateError::Export(e)
+    }
+}
 
-        // Initialize 16 track configs with default accent colors
-        let track_configs = docs.get_list("trackConfigs");
-        for track_index in 0..NUM_TRACKS {
-            let config_map = track_configs
-                .insert_container(track_index, loro::LoroMap::new())
-                .expect("Failed to create track config map");
-            config_map
-                .insert("accentColor", DEFAULT_ACCENT_COLORS[track_index])
-                .expect("Failed to set accent color");
+/// A copy of a song to start a new room from
+pub struct ForkedSong {
+    pub snapshot: Vec<u8>,
+    /// Owners of the peers that have ops in the copy
+    pub peers: HashMap<loro::PeerID, Uuid>,
+    /// Frontiers the copy was taken at, as `counter@peer`
+    pub version: Vec<String>,
+}
+
+// Number of logged updates after which the log is compacted into a snapshot
+const COMPACT_AFTER_UPDATES: usize = 500;
+
+impl SynthesizerState {
+    /// Load the song from storage, or create and persist a fresh one shaped by `config`
+    pub fn load(storage: Box<dyn SongStorage>, config: &SongConfig) -> Result<Self, StorageError> {
+        let (docs, stored_bytes) = match storage.load()? {
+            Some(stored) => {
+                let stored_bytes =
+                    stored.snapshot.len() + stored.updates.iter().map(Vec::len).sum::<usize>();
+                let docs = loro::LoroDoc::new();
+                docs.import(&stored.snapshot)?;
+                for update in &stored.updates {
+                    docs.import(update)?;
+                }
+                tracing::info!(
+                    "Loaded song from storage ({} logged updates replayed)",
+                    stored.updates.len()
+                );
+                (docs, stored_bytes)
+            }
+            None => {
+                let docs = Self::new_song_doc(config);
+                let snapshot = docs.export(loro::ExportMode::Snapshot)?;
+                storage.write_snapshot(&snapshot)?;
+                tracing::info!("Created a new song");
+                (docs, snapshot.len())
+            }
+        };
+
+        // Stored songs keep the shape they were created with
+        let dimensions = SongDimensions::of(&docs).unwrap_or_else(|| config.dimensions());
+        let violations = validation::validate(&docs, dimensions);
+        if !violations.is_empty() {
+            tracing::warn!("Stored song has {} schema violations", violations.len());
         }
 
-        // Commit the initial state
-        docs.commit();
+        let peers = storage.load_peers()?;
+        let storage: Arc<dyn SongStorage> = Arc::from(storage);
 
-        Self {
+        Ok(Self {
+            staged: Mutex::new(docs.fork()),
             docs: RwLock::new(docs),
-        }
+            peers: Mutex::new(peers),
+            writer: StorageWriter::spawn(storage.clone(), stored_bytes as u64),
+            storage,
+            dimensions,
+            logged_updates: AtomicUsize::new(0),
+        })
+    }
+
+    fn new_song_doc(config: &SongConfig) -> loro::LoroDoc {
+        let docs = loro::LoroDoc::new();
+
+        // Every track starts out with its default accent color
+        the_song_model::init_song(
+            &docs,
+            config.default_bpm,
+            config.dimensions(),
+            |track_index| TrackConfig {
+                accent_color: Some(config.accent_color(track_index).to_string()),
+            },
+        )
+        .expect("Failed to lay out the song");
+
+        // Commit the initial state
+        docs.commit();
+        docs
     }
 
     pub async fn get_snapshot(&self) -> Result<Vec<u8>, loro::LoroEncodeError> {
@@ -295,11 +581,321 @@ impl SynthesizerState {
         docs.export(loro::ExportMode::ShallowSnapshot(Cow::Borrowed(&frontiers)))
     }
 
+    /// Updates a client at `version_vector` is missing.
+    ///
+    /// Returns `None` when the version vector cannot be decoded or is empty, a
+    /// fresh client is better served by the shallow snapshot.
+    pub async fn get_updates_since(
+        &self,
+        version_vector: &[u8],
+    ) -> Result<Option<Vec<u8>>, loro::LoroEncodeError> {
+        let version_vector = match loro::VersionVector::decode(version_vector) {
+            Ok(version_vector) if !version_vector.is_empty() => version_vector,
+            _ => return Ok(None),
+        };
+        let docs = self.docs.read().await;
+        docs.export(loro::ExportMode::updates_owned(version_vector))
+            .map(Some)
+    }
+
+    /// Read the current song out of the document
+    pub async fn song(&self) -> the_song_model::Song {
+        let docs = self.docs.read().await;
+        the_song_model::Song::from_doc(&docs)
+    }
+
+    /// Accent color of each track, as the editor shows them
+    pub async fn track_colors(&self) -> Vec<Option<String>> {
+        let docs = self.docs.read().await;
+        the_song_model::track_configs(&docs)
+            .into_iter()
+            .map(|config| config.accent_color)
+            .collect()
+    }
+
+    /// The latest `limit` versions of the song, newest first
+    pub async fn versions(&self, limit: usize) -> Vec<Version> {
+        let docs = self.docs.read().await;
+        let owners = self.peers.lock().unwrap();
+        history::versions(&docs, &owners, limit)
+    }
+
+    /// The song as it was at `version`, `None` if there is no such version
+    pub async fn song_at(&self, version: &str) -> Option<the_song_model::Song> {
+        let docs = self.docs.read().await;
+        let frontiers = history::parse_version(&docs, version)?;
+        Some(the_song_model::Song::from_doc(&docs.fork_at(&frontiers)))
+    }
+
+    /// Frontiers of `version`, `None` if there is no such version
+    pub async fn find_version(&self, version: &str) -> Option<loro::Frontiers> {
+        let docs = self.docs.read().await;
+        history::parse_version(&docs, version)
+    }
+
+    /// Note events of the whole history of the song, oldest first.
+    ///
+    /// The history is replayed on a copy so updates are not held up meanwhile.
+    pub async fn timelapse(&self) -> Vec<TimelapseEvent> {
+        let (doc, owners) = {
+            let docs = self.docs.read().await;
+            (docs.fork(), self.peers.lock().unwrap().clone())
+        };
+        tokio::task::spawn_blocking(move || timelapse::events(&doc, &owners))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("Timelapse task failed: {}", e);
+                Vec::new()
+            })
+    }
+
+    /// A copy of the song with its history up to `version`, or up to now.
+    ///
+    /// Returns `None` if there is no such version.
+    pub async fn fork(&self, version: Option<&str>) -> Result<Option<ForkedSong>, StorageError> {
+        let docs = self.docs.read().await;
+        let frontiers = match version {
+            Some(version) => match history::parse_version(&docs, version) {
+                Some(frontiers) => frontiers,
+                None => return Ok(None),
+            },
+            None => docs.oplog_frontiers(),
+        };
+        let copy = docs.fork_at(&frontiers);
+        let snapshot = copy.export(loro::ExportMode::Snapshot)?;
+        let included = copy.oplog_vv();
+        let peers = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(peer, _)| included.get(peer).is_some())
+            .map(|(peer, owner)| (*peer, *owner))
+            .collect();
+        Ok(Some(ForkedSong {
+            snapshot,
+            peers,
+            version: frontiers.iter().map(|id| id.to_string()).collect(),
+        }))
+    }
+
+    /// Where the song was forked from, `None` if it was not
+    pub fn origin(&self) -> Result<Option<ForkOrigin>, StorageError> {
+        self.storage.load_origin()
+    }
+
+    /// Import an update sent by `user_id`.
+    ///
+    /// The update may only carry ops of Loro peers that belong to the user or
+    /// are new, new ones are bound to the user once it is accepted. `inspect`
+    /// sees the live document and the update staged on a copy of it, and can
+    /// turn the update down before it is imported.
     pub async fn apply_update(
         &self,
+        user_id: Uuid,
         update: Vec<u8>,
-    ) -> Result<loro::ImportStatus, loro::LoroError> {
+        inspect: impl FnOnce(&loro::LoroDoc, &loro::LoroDoc) -> Result<(), UpdateError>,
+    ) -> Result<loro::ImportStatus, UpdateError> {
+        let peers = identity::update_peers(&update)?;
+        let docs = self.docs.write().await;
+        let unclaimed = {
+            let owners = self.peers.lock().unwrap();
+            identity::unclaimed_peers(&peers, user_id, &owners, &docs.oplog_vv())
+                .map_err(UpdateError::Forged)?
+        };
+
+        let status = self.import_checked(&docs, &update, inspect)?;
+
+        let mut owners = self.peers.lock().unwrap();
+        for peer in unclaimed {
+            self.writer.append_peer(peer, user_id);
+            owners.insert(peer, user_id);
+        }
+        Ok(status)
+    }
+
+    /// Loro peers bound to `user_id`
+    pub fn peers_of(&self, user_id: Uuid) -> HashSet<loro::PeerID> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, owner)| **owner == user_id)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Make a server-side change to the song.
+    ///
+    /// The change goes through the same validation and update log as client
+    /// updates. Returns the result of `edit` and the update to broadcast.
+    pub async fn edit<T>(
+        &self,
+        edit: impl FnOnce(&loro::LoroDoc) -> loro::LoroResult<T>,
+    ) -> Result<(T, Vec<u8>), UpdateError> {
         let docs = self.docs.write().await;
-        docs.import(update.as_slice())
+
+        let staged = docs.fork();
+        staged.set_record_timestamp(true);
+        let before = staged.oplog_vv();
+        let result = edit(&staged)?;
+        staged.commit();
+        let update = staged.export(loro::ExportMode::updates(&before))?;
+
+        self.import_checked(&docs, &update, |_, _| Ok(()))?;
+        Ok((result, update))
+    }
+
+    /// Import an update into the locked document if it keeps the song valid, then log it
+    fn import_checked(
+        &self,
+        docs: &loro::LoroDoc,
+        update: &[u8],
+        inspect: impl FnOnce(&loro::LoroDoc, &loro::LoroDoc) -> Result<(), UpdateError>,
+    ) -> Result<loro::ImportStatus, UpdateError> {
+        let mut staged = self.staged.lock().unwrap();
+        let imported = self
+            .stage(docs, &staged, update)
+            .and_then(|()| inspect(docs, &staged))
+            .and_then(|()| Ok(docs.import(update)?));
+        let status = match imported {
+            Ok(status) => status,
+            Err(e) => {
+                // The staged copy may have the update now, start over from the live document
+                *staged = docs.fork();
+                return Err(e);
+            }
+        };
+        drop(staged);
+
+        // Queue while still holding the lock so the log order matches the import order
+        self.writer.append_update(update.to_vec());
+        if self.logged_updates.fetch_add(1, Ordering::SeqCst) + 1 >= COMPACT_AFTER_UPDATES {
+            // The writer reports a failed compaction itself
+            if let Err(e) = self.queue_snapshot(docs) {
+                tracing::error!("Failed to compact synthesizer update log: {}", e);
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Import an update into the staged copy and reject it if it introduces a schema violation
+    fn stage(
+        &self,
+        docs: &loro::LoroDoc,
+        staged: &loro::LoroDoc,
+        update: &[u8],
+    ) -> Result<(), UpdateError> {
+        let before = staged.oplog_vv();
+        staged.import(update)?;
+        let touched = validation::Touched::since(staged, &before);
+        match validation::introduced(docs, staged, self.dimensions, touched) {
+            Some(violation) => Err(UpdateError::Invalid(violation)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn dimensions(&self) -> SongDimensions {
+        self.dimensions
+    }
+
+    /// Bytes the song takes in storage, snapshot plus logged updates
+    pub fn stored_size(&self) -> u64 {
+        self.writer.stored_bytes()
+    }
+
+    /// Wait until every write queued for the song so far is in storage
+    pub async fn flush(&self) {
+        self.writer.flush().await
+    }
+
+    /// Write a full snapshot of the song and truncate the update log
+    pub async fn persist(&self) -> Result<(), StorageError> {
+        let written = {
+            let docs = self.docs.read().await;
+            self.queue_snapshot(&docs)?
+        };
+        written.await
+    }
+
+    /// Queue a snapshot of the locked document behind the updates queued so far
+    fn queue_snapshot(
+        &self,
+        docs: &loro::LoroDoc,
+    ) -> Result<impl std::future::Future<Output = Result<(), StorageError>>, StorageError> {
+        let snapshot = docs.export(loro::ExportMode::Snapshot)?;
+        self.logged_updates.store(0, Ordering::SeqCst);
+        Ok(self.writer.write_snapshot(snapshot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_broadcast_to_full_queue() {
+        let registry = ConnectionRegistry::new();
+        let (queue, _rx) = tokio::sync::mpsc::channel(1);
+        let lagged = CancellationToken::new();
+        let user_id = Uuid::now_v7();
+        registry
+            .register(
+                user_id,
+                ClientSender::new(queue, lagged.clone(), CancellationToken::new()),
+            )
+            .await;
+
+        registry
+            .broadcast(Message::Binary(vec![1].into()), Delivery::Reliable)
+            .await;
+        // The queue is full now, a droppable frame is skipped
+        registry
+            .broadcast(Message::Binary(vec![2].into()), Delivery::Droppable)
+            .await;
+        assert_eq!(registry.connection_count().await, 1);
+        assert!(!lagged.is_cancelled());
+
+        // A reliable frame that does not fit disconnects the client
+        registry
+            .broadcast(Message::Binary(vec![3].into()), Delivery::Reliable)
+            .await;
+        assert_eq!(registry.connection_count().await, 0);
+        assert!(lagged.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_resumed_connection_takes_over() {
+        let registry = ConnectionRegistry::new();
+        let user_id = Uuid::now_v7();
+        let (stale_queue, _stale_rx) = tokio::sync::mpsc::channel(1);
+        let stale = CancellationToken::new();
+        registry
+            .register(
+                user_id,
+                ClientSender::new(stale_queue.clone(), CancellationToken::new(), stale.clone()),
+            )
+            .await;
+
+        registry.evict(&user_id).await;
+        assert!(stale.is_cancelled());
+        let (queue, _rx) = tokio::sync::mpsc::channel(1);
+        registry
+            .register(
+                user_id,
+                ClientSender::new(
+                    queue.clone(),
+                    CancellationToken::new(),
+                    CancellationToken::new(),
+                ),
+            )
+            .await;
+
+        // The stale connection going away leaves the new one registered
+        assert!(!registry.unregister(&user_id, &stale_queue).await);
+        assert_eq!(registry.connection_count().await, 1);
+        assert!(registry.unregister(&user_id, &queue).await);
+        assert_eq!(registry.connection_count().await, 0);
     }
 }
@@ -0,0 +1,448 @@
+//! Moderation rollback.
+//!
+//! Reverting a user walks every op their Loro peers contributed and writes a
+//! new change that undoes what those ops did to the `notes` map and the pitch
+//! lists. Notes they added are removed, notes they removed come back as they
+//! were before the user first touched them, and note fields they changed go
+//! back to their earlier values. Anything someone else has written since is
+//! kept.
+
+use loro::{
+    ContainerID, ContainerTrait, Frontiers, IdSpan, JsonListOp, JsonMapOp, JsonOpContent, Lamport,
+    LoroDoc, LoroMap, LoroResult, LoroValue, PeerID, ID,
+};
+use std::collections::{HashMap, HashSet};
+
+use the_song_model::{note_value, parse_note, pitch_list, schema, SongDimensions};
+
+/// What a revert changed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RevertSummary {
+    /// Notes the user added that were removed
+    pub removed: usize,
+    /// Notes the user removed or replaced that were put back
+    pub restored: usize,
+    /// Fields of other notes set back to their earlier value
+    pub fields: usize,
+}
+
+/// The version right before one of the user's changes
+#[derive(Clone)]
+struct Before {
+    lamport: Lamport,
+    deps: Vec<ID>,
+}
+
+/// Keep whichever of the two versions came first
+fn keep_earliest(slot: &mut Option<Before>, before: &Before) {
+    if slot
+        .as_ref()
+        .is_none_or(|kept| before.lamport < kept.lamport)
+    {
+        *slot = Some(before.clone());
+    }
+}
+
+/// How the user's ops touched one note
+#[derive(Default)]
+struct Touched {
+    /// First change to the note's entry in the `notes` map
+    entry: Option<Before>,
+    /// First change to each field of the note
+    fields: HashMap<String, Option<Before>>,
+}
+
+/// Old versions of the song, checked out once each
+struct History<'a> {
+    doc: &'a LoroDoc,
+    versions: HashMap<Vec<ID>, LoroDoc>,
+}
+
+impl History<'_> {
+    /// Deep value of a note right before `before`
+    fn note(&mut self, before: &Before, id: &str) -> Option<LoroValue> {
+        let doc = self
+            .versions
+            .entry(before.deps.clone())
+            .or_insert_with(|| self.doc.fork_at(&Frontiers::from(before.deps.clone())));
+        note_value(doc, id)
+    }
+}
+
+/// Undo what the ops of `peers` did to the notes and pitch lists of `doc`
+pub fn revert_peers(doc: &LoroDoc, peers: &HashSet<PeerID>) -> LoroResult<RevertSummary> {
+    let notes = schema::notes_map(doc);
+    let notes_id = notes.id();
+    let dimensions = SongDimensions::of(doc).unwrap_or_default();
+    let pitch_lists: HashSet<ContainerID> = pitch_lists(doc, dimensions)
+        .into_iter()
+        .map(|(_, _, list)| list.id())
+        .collect();
+
+    let mut touched: HashMap<String, Touched> = HashMap::new();
+    // Notes whose pitch list entries the user added or removed
+    let mut listed: HashSet<String> = HashSet::new();
+    let mut note_keys: HashMap<ContainerID, Option<String>> = HashMap::new();
+
+    let version = doc.oplog_vv();
+    for &peer in peers {
+        let Some(&end) = version.get(&peer) else {
+            continue;
+        };
+        for change in doc.export_json_in_id_span(IdSpan::new(peer, 0, end)) {
+            let before = Before {
+                lamport: change.lamport,
+                deps: change.deps,
+            };
+            for op in change.ops {
+                match op.content {
+                    JsonOpContent::Map(map_op) if op.container == notes_id => {
+                        let key = match map_op {
+                            JsonMapOp::Insert { key, .. } | JsonMapOp::Delete { key } => key,
+                        };
+                        keep_earliest(&mut touched.entry(key).or_default().entry, &before);
+                    }
+                    JsonOpContent::Map(map_op) => {
+                        let note = note_keys
+                            .entry(op.container.clone())
+                            .or_insert_with(|| note_key(doc, &notes_id, &op.container));
+                        if let Some(note) = note {
+                            let field = match map_op {
+                                JsonMapOp::Insert { key, .. } | JsonMapOp::Delete { key } => key,
+                            };
+                            let touched = touched.entry(note.clone()).or_default();
+                            keep_earliest(touched.fields.entry(field).or_default(), &before);
+                        }
+                    }
+                    JsonOpContent::List(list_op) if pitch_lists.contains(&op.container) => {
+                        match list_op {
+                            JsonListOp::Insert { value, .. } => listed.extend(strings(&value)),
+                            JsonListOp::Delete { len, start_id, .. } => {
+                                listed.extend(inserted_strings(doc, start_id, len))
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let mut summary = RevertSummary::default();
+    let mut history = History {
+        doc,
+        versions: HashMap::new(),
+    };
+    let is_theirs = |editor: Option<PeerID>| editor.is_some_and(|peer| peers.contains(&peer));
+
+    for (id, touched) in &touched {
+        // The entry itself is the user's doing: put back what was there before
+        if let Some(before) = &touched.entry {
+            if is_theirs(notes.get_last_editor(id)) {
+                match history.note(before, id) {
+                    Some(LoroValue::Map(fields)) => {
+                        let note = notes.insert_container(id, LoroMap::new())?;
+                        for (field, value) in fields.iter() {
+                            note.insert(field, value.clone())?;
+                        }
+                        summary.restored += 1;
+                    }
+                    Some(value) => {
+                        notes.insert(id, value)?;
+                        summary.restored += 1;
+                    }
+                    None if notes.get(id).is_some() => {
+                        notes.delete(id)?;
+                        summary.removed += 1;
+                    }
+                    None => {}
+                }
+                continue;
+            }
+        }
+
+        // Otherwise only the fields the user was the last to write
+        let Some(loro::ValueOrContainer::Container(loro::Container::Map(note))) = notes.get(id)
+        else {
+            continue;
+        };
+        for (field, before) in &touched.fields {
+            let Some(before) = before else { continue };
+            if !is_theirs(note.get_last_editor(field)) {
+                continue;
+            }
+            let old = match history.note(before, id) {
+                Some(LoroValue::Map(fields)) => fields.get(field).cloned(),
+                _ => None,
+            };
+            match old {
+                Some(value) => note.insert(field, value)?,
+                None => note.delete(field)?,
+            }
+            summary.fields += 1;
+        }
+    }
+
+    listed.extend(touched.into_keys());
+    fix_pitch_lists(doc, dimensions, &listed)?;
+    Ok(summary)
+}
+
+/// Key of the note a note map was created for, from the op that created it
+fn note_key(doc: &LoroDoc, notes_id: &ContainerID, container: &ContainerID) -> Option<String> {
+    let ContainerID::Normal { peer, counter, .. } = container else {
+        return None;
+    };
+    doc.export_json_in_id_span(IdSpan::new(*peer, *counter, counter + 1))
+        .into_iter()
+        .flat_map(|change| change.ops)
+        .find_map(|op| match op.content {
+            JsonOpContent::Map(JsonMapOp::Insert {
+                key,
+                value: LoroValue::Container(created),
+            }) if op.container == *notes_id && created == *container => Some(key),
+            _ => None,
+        })
+}
+
+fn strings(values: &[LoroValue]) -> impl Iterator<Item = String> + '_ {
+    values.iter().filter_map(|value| match value {
+        LoroValue::String(s) => Some(s.to_string()),
+        _ => None,
+    })
+}
+
+/// Strings a list delete removed, read from the inserts of the deleted elements
+fn inserted_strings(doc: &LoroDoc, start: ID, len: i32) -> Vec<String> {
+    let (from, to) = if len >= 0 {
+        (start.counter, start.counter + len)
+    } else {
+        (start.counter + len + 1, start.counter + 1)
+    };
+    doc.export_json_in_id_span(IdSpan::new(start.peer, from.max(0), to))
+        .into_iter()
+        .flat_map(|change| change.ops)
+        .filter_map(|op| match op.content {
+            JsonOpContent::List(JsonListOp::Insert { value, .. }) => Some(value),
+            _ => None,
+        })
+        .flat_map(|value| strings(&value).collect::<Vec<_>>())
+        .collect()
+}
+
+fn pitch_lists(doc: &LoroDoc, dimensions: SongDimensions) -> Vec<(usize, usize, loro::LoroList)> {
+    let mut lists = Vec::new();
+    for track in 0..dimensions.tracks {
+        for pitch in 0..dimensions.pitches {
+            if let Some(list) = pitch_list(doc, track, pitch) {
+                lists.push((track, pitch, list));
+            }
+        }
+    }
+    lists
+}
+
+/// Leave each of `ids` listed exactly once, on the pitch list of its note
+fn fix_pitch_lists(
+    doc: &LoroDoc,
+    dimensions: SongDimensions,
+    ids: &HashSet<String>,
+) -> LoroResult<()> {
+    let notes = match schema::notes_map(doc).get_deep_value() {
+        LoroValue::Map(notes) => notes,
+        _ => Default::default(),
+    };
+    let wanted = |id: &str| {
+        let note = parse_note(id, notes.get(id)?, dimensions)?;
+        Some((note.track, note.pitch))
+    };
+
+    let mut found: HashSet<&str> = HashSet::new();
+    for (track, pitch, list) in pitch_lists(doc, dimensions) {
+        let LoroValue::List(values) = list.get_value() else {
+            continue;
+        };
+        // From the back so the positions left to delete stay put
+        for (pos, value) in values.iter().enumerate().rev() {
+            let LoroValue::String(id) = value else {
+                continue;
+            };
+            let Some(id) = ids.get(id.as_str()) else {
+                continue;
+            };
+            let keep = wanted(id) == Some((track, pitch))
+                && !values[..pos]
+                    .iter()
+                    .any(|earlier| matches!(earlier, LoroValue::String(s) if s.as_str() == id));
+            if keep {
+                found.insert(id);
+            } else {
+                list.delete(pos, 1)?;
+            }
+        }
+    }
+
+    for id in ids {
+        if found.contains(id.as_str()) {
+            continue;
+        }
+        if let Some((track, pitch)) = wanted(id) {
+            if let Some(list) = pitch_list(doc, track, pitch) {
+                list.push(id.as_str())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use the_song_model::{
+        delete_note, init_song, insert_note, update_note, Note, NoteUpdate, Song, TrackConfig,
+        DEFAULT_BPM,
+    };
+
+    fn song_doc(peer: PeerID) -> LoroDoc {
+        let doc = LoroDoc::new();
+        doc.set_peer_id(peer).unwrap();
+        let dimensions = SongDimensions {
+            tracks: 2,
+            pitches: 3,
+        };
+        init_song(&doc, DEFAULT_BPM, dimensions, |_| TrackConfig::default()).unwrap();
+        doc.commit();
+        doc
+    }
+
+    fn note(id: &str, by: &str, pitch: usize) -> Note {
+        Note {
+            id: id.to_string(),
+            pitch,
+            start: 0.0,
+            duration: 1.0,
+            velocity: 100,
+            track: 0,
+            created_by: by.to_string(),
+            created_at: 0.0,
+        }
+    }
+
+    fn sync(from: &LoroDoc, to: &LoroDoc) {
+        to.import(
+            &from
+                .export(loro::ExportMode::updates(&to.oplog_vv()))
+                .unwrap(),
+        )
+        .unwrap();
+    }
+
+    fn listed(doc: &LoroDoc, pitch: usize) -> Vec<String> {
+        pitch_list(doc, 0, pitch)
+            .unwrap()
+            .get_value()
+            .into_list()
+            .unwrap()
+            .iter()
+            .map(|value| value.as_string().unwrap().to_string())
+            .collect()
+    }
+
+    fn ids(doc: &LoroDoc) -> Vec<String> {
+        Song::from_doc(doc)
+            .notes
+            .into_iter()
+            .map(|note| note.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_revert_user() {
+        let server = song_doc(1);
+        let alice = server.fork();
+        alice.set_peer_id(2).unwrap();
+        insert_note(&alice, &note("a1", "alice", 0)).unwrap();
+        insert_note(&alice, &note("a2", "alice", 1)).unwrap();
+        alice.commit();
+        sync(&alice, &server);
+
+        // The griefer adds a note, deletes one and moves another to a new pitch
+        let griefer = server.fork();
+        griefer.set_peer_id(3).unwrap();
+        insert_note(&griefer, &note("g1", "griefer", 2)).unwrap();
+        delete_note(&griefer, "a1").unwrap();
+        let moved = NoteUpdate {
+            pitch: Some(2),
+            ..NoteUpdate::default()
+        };
+        update_note(&griefer, "a2", &moved).unwrap();
+        griefer.commit();
+        sync(&griefer, &server);
+
+        // Alice edits her moved note afterwards
+        sync(&server, &alice);
+        let longer = NoteUpdate {
+            duration: Some(2.0),
+            ..NoteUpdate::default()
+        };
+        update_note(&alice, "a2", &longer).unwrap();
+        alice.commit();
+        sync(&alice, &server);
+
+        let summary = revert_peers(&server, &HashSet::from([3])).unwrap();
+        server.commit();
+        assert_eq!(
+            summary,
+            RevertSummary {
+                removed: 1,
+                restored: 1,
+                fields: 1
+            }
+        );
+        assert_eq!(ids(&server), vec!["a1", "a2"]);
+        assert_eq!(listed(&server, 0), vec!["a1"]);
+        assert_eq!(listed(&server, 1), vec!["a2"]);
+        assert!(listed(&server, 2).is_empty());
+        let a2 = Song::from_doc(&server).notes.remove(1);
+        assert_eq!((a2.pitch, a2.duration), (1, 2.0));
+    }
+
+    #[test]
+    fn test_later_writes_are_kept() {
+        let server = song_doc(1);
+        let alice = server.fork();
+        alice.set_peer_id(2).unwrap();
+        insert_note(&alice, &note("a1", "alice", 0)).unwrap();
+        alice.commit();
+        sync(&alice, &server);
+
+        let griefer = server.fork();
+        griefer.set_peer_id(3).unwrap();
+        let quiet = NoteUpdate {
+            velocity: Some(1),
+            ..NoteUpdate::default()
+        };
+        update_note(&griefer, "a1", &quiet).unwrap();
+        griefer.commit();
+        sync(&griefer, &server);
+
+        // Alice sets the velocity again herself, so it stays hers
+        sync(&server, &alice);
+        let louder = NoteUpdate {
+            velocity: Some(50),
+            ..NoteUpdate::default()
+        };
+        update_note(&alice, "a1", &louder).unwrap();
+        alice.commit();
+        sync(&alice, &server);
+
+        let summary = revert_peers(&server, &HashSet::from([3])).unwrap();
+        assert_eq!(summary, RevertSummary::default());
+        assert_eq!(Song::from_doc(&server).notes[0].velocity, 50);
+        // Nobody else's peers, nothing to revert
+        assert_eq!(
+            revert_peers(&server, &HashSet::from([9])).unwrap(),
+            RevertSummary::default()
+        );
+    }
+}
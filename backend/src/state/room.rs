@@ -0,0 +1,796 @@
+//! Rooms: independent songs, each with its own document, presence and
+//! broadcast set.
+//!
+//! Rooms are loaded from storage (or created) the first time someone asks for
+//! them and unloaded again once they have been idle for a while. Loading and
+//! persisting run on the blocking pool without holding the map of rooms, so a
+//! slow disk only holds up the room it is working on.
+
+use axum::extract::ws::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc::Sender, OnceCell, RwLock};
+use uuid::Uuid;
+
+use crate::config::{AbuseConfig, SongConfig};
+use crate::metrics::metrics;
+use the_song_model::SongDimensions;
+
+use super::abuse::{self, AbuseGuard};
+use super::identity::{self, ResumeTokens};
+use super::moderation::{self, RevertSummary};
+use super::{
+    ClientSender, ConnectionRegistry, Delivery, ForkOrigin, MousePosition, MouseTracker,
+    ServerStats, StorageError, StorageProvider, SynthesizerState, TimelapseEvent, UpdateError,
+    Version,
+};
+
+/// Room served on the plain `/ws` route
+pub const DEFAULT_ROOM_ID: &str = "public";
+
+const MAX_ROOM_ID_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum RoomError {
+    /// The server holds `max_rooms` rooms already
+    TooManyRooms,
+    /// The client created too many rooms lately
+    RateLimited,
+    Storage(StorageError),
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::TooManyRooms => write!(f, "room limit reached"),
+            RoomError::RateLimited => write!(f, "too many new rooms, try again later"),
+            RoomError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+impl From<StorageError> for RoomError {
+    fn from(e: StorageError) -> Self {
+        RoomError::Storage(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum ForkError {
+    /// The source song has no such version
+    UnknownVersion,
+    /// The new room's ID is taken
+    RoomExists,
+    Room(RoomError),
+}
+
+impl fmt::Display for ForkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForkError::UnknownVersion => write!(f, "no such version"),
+            ForkError::RoomExists => write!(f, "room already exists"),
+            ForkError::Room(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ForkError {}
+
+impl From<RoomError> for ForkError {
+    fn from(e: RoomError) -> Self {
+        ForkError::Room(e)
+    }
+}
+
+impl From<StorageError> for ForkError {
+    fn from(e: StorageError) -> Self {
+        ForkError::Room(RoomError::Storage(e))
+    }
+}
+
+/// Why loading a room came up empty
+enum LoadError {
+    /// The room was never stored and may not be created
+    Missing,
+    Fork(ForkError),
+}
+
+impl From<ForkError> for LoadError {
+    fn from(e: ForkError) -> Self {
+        LoadError::Fork(e)
+    }
+}
+
+impl From<RoomError> for LoadError {
+    fn from(e: RoomError) -> Self {
+        LoadError::Fork(e.into())
+    }
+}
+
+impl From<StorageError> for LoadError {
+    fn from(e: StorageError) -> Self {
+        LoadError::Fork(e.into())
+    }
+}
+
+pub struct Room {
+    id: String,
+    stats: ServerStats,
+    mouse_tracker: MouseTracker,
+    synthesizer: SynthesizerState,
+    connections: ConnectionRegistry,
+    abuse_guard: AbuseGuard,
+    resume_tokens: ResumeTokens,
+    last_active: Mutex<Instant>,
+}
+
+/// Slot of a room in the `RoomManager`, empty while the room is being loaded
+type RoomCell = Arc<OnceCell<Arc<Room>>>;
+
+pub struct RoomManager {
+    rooms: RwLock<HashMap<String, RoomCell>>,
+    storage: Arc<dyn StorageProvider>,
+    // Shape of the songs of new rooms
+    song_config: SongConfig,
+    abuse_config: AbuseConfig,
+    // Rooms that may be stored, 0 for no limit
+    max_rooms: usize,
+    // Rooms stored so far, counting those being created
+    stored_rooms: Arc<AtomicUsize>,
+}
+
+impl Room {
+    pub fn new(id: String, synthesizer: SynthesizerState, abuse_config: AbuseConfig) -> Self {
+        Self {
+            id,
+            stats: ServerStats::new(),
+            mouse_tracker: MouseTracker::new(),
+            synthesizer,
+            connections: ConnectionRegistry::new(),
+            abuse_guard: AbuseGuard::new(abuse_config),
+            resume_tokens: ResumeTokens::default(),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Room IDs double as directory names, so keep them short and plain
+    pub fn is_valid_id(room_id: &str) -> bool {
+        !room_id.is_empty()
+            && room_id.len() <= MAX_ROOM_ID_LEN
+            && room_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Mark the room as used, an idle room is only unloaded if nothing touched
+    /// it while it was persisted
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+
+    pub fn increment_users(&self) {
+        self.stats.online_users.fetch_add(1, Ordering::SeqCst);
+        metrics().connections.inc();
+    }
+
+    pub fn decrement_users(&self) {
+        self.stats.online_users.fetch_sub(1, Ordering::SeqCst);
+        metrics().connections.dec();
+    }
+
+    pub fn get_server_stats(&self) -> the_song_protocol::ServerStats {
+        self.stats.get_snapshot()
+    }
+
+    pub async fn get_synthesizer_snapshot(&self) -> Result<Vec<u8>, loro::LoroEncodeError> {
+        self.synthesizer.get_snapshot().await
+    }
+
+    pub async fn get_synthesizer_updates_since(
+        &self,
+        version_vector: &[u8],
+    ) -> Result<Option<Vec<u8>>, loro::LoroEncodeError> {
+        self.synthesizer.get_updates_since(version_vector).await
+    }
+
+    pub async fn song(&self) -> the_song_model::Song {
+        self.synthesizer.song().await
+    }
+
+    pub async fn track_colors(&self) -> Vec<Option<String>> {
+        self.synthesizer.track_colors().await
+    }
+
+    /// Apply an update from `user_id` and broadcast it to the room if it was accepted
+    pub async fn apply_synthesizer_update(
+        &self,
+        user_id: Uuid,
+        update: Vec<u8>,
+    ) -> Result<(), UpdateError> {
+        self.touch();
+        let timer = metrics().apply_update_seconds.start_timer();
+        let result = self
+            .synthesizer
+            .apply_update(user_id, update.clone(), |before, after| {
+                let user = user_id.to_string();
+                let before = identity::note_authors(before);
+                let after = identity::note_authors(after);
+                identity::check_authors(&before, &after, &user).map_err(UpdateError::Forged)?;
+                let changes = abuse::note_changes(&before, &after, &user);
+                self.abuse_guard
+                    .check(user_id, changes)
+                    .map_err(UpdateError::Quota)
+            })
+            .await;
+        timer.observe_duration();
+        if let Err(e) = result {
+            if let UpdateError::Quota(violation) = &e {
+                metrics()
+                    .quota_violations
+                    .with_label_values(&[violation.kind()])
+                    .inc();
+                tracing::warn!(
+                    "Flagged user {} in room {}: {}",
+                    user_id,
+                    self.id,
+                    violation
+                );
+            }
+            metrics()
+                .apply_update_failures
+                .with_label_values(&[e.kind()])
+                .inc();
+            tracing::warn!("Rejected synthesizer update in room {}: {}", self.id, e);
+            return Err(e);
+        }
+
+        self.broadcast_synthesizer_update(update).await;
+        Ok(())
+    }
+
+    /// Make a server-side change to the song and broadcast it to the room
+    pub async fn edit_song<T>(
+        &self,
+        edit: impl FnOnce(&loro::LoroDoc) -> loro::LoroResult<T>,
+    ) -> Result<T, UpdateError> {
+        self.touch();
+        let (result, update) = self.synthesizer.edit(edit).await?;
+        self.broadcast_synthesizer_update(update).await;
+        Ok(result)
+    }
+
+    pub async fn versions(&self, limit: usize) -> Vec<Version> {
+        self.synthesizer.versions(limit).await
+    }
+
+    pub async fn song_at(&self, version: &str) -> Option<the_song_model::Song> {
+        self.synthesizer.song_at(version).await
+    }
+
+    pub async fn timelapse(&self) -> Vec<TimelapseEvent> {
+        self.synthesizer.timelapse().await
+    }
+
+    /// Bring the song back to how it was at `version` with a new change on top
+    /// of the current one, `false` if there is no such version
+    pub async fn restore_version(&self, version: &str) -> Result<bool, UpdateError> {
+        let Some(frontiers) = self.synthesizer.find_version(version).await else {
+            return Ok(false);
+        };
+        self.edit_song(|doc| doc.revert_to(&frontiers)).await?;
+        tracing::info!("Restored room {} to version {}", self.id, version);
+        Ok(true)
+    }
+
+    /// Undo what `user_id` did to the notes and broadcast the result to the room
+    pub async fn revert_user(&self, user_id: Uuid) -> Result<RevertSummary, UpdateError> {
+        let peers = self.synthesizer.peers_of(user_id);
+        let summary = self
+            .edit_song(|doc| moderation::revert_peers(doc, &peers))
+            .await?;
+        tracing::info!(
+            "Reverted user {} in room {}: {} notes removed, {} restored, {} fields reset",
+            user_id,
+            self.id,
+            summary.removed,
+            summary.restored,
+            summary.fields
+        );
+        Ok(summary)
+    }
+
+    async fn broadcast_synthesizer_update(&self, update: Vec<u8>) {
+        // Broadcast update to all clients in the room (binary format)
+        let msg = crate::dto::create_synthesizer_update_message(update);
+        let bytes = crate::dto::encode_server_message(&msg);
+        self.broadcast(Message::Binary(bytes.into()), Delivery::Reliable)
+            .await;
+    }
+
+    pub async fn persist_synthesizer(&self) -> Result<(), StorageError> {
+        self.synthesizer.persist().await
+    }
+
+    pub async fn flush_synthesizer(&self) {
+        self.synthesizer.flush().await
+    }
+
+    pub async fn update_mouse(&self, user_id: Uuid, x: f32, y: f32, vx: f32, vy: f32) {
+        self.mouse_tracker
+            .update_position(user_id, x, y, vx, vy)
+            .await;
+    }
+
+    pub async fn remove_mouse(&self, user_id: &Uuid) {
+        self.mouse_tracker.remove_user(user_id).await;
+    }
+
+    pub async fn get_dirty_mouse_positions(&self) -> HashMap<Uuid, MousePosition> {
+        self.mouse_tracker.get_dirty_positions().await
+    }
+
+    /// The user a resume token was issued to.
+    ///
+    /// A client can reconnect before its old socket is found dead, that
+    /// connection is closed so the new one takes over the user.
+    pub async fn resume_user(&self, token: &str) -> Option<Uuid> {
+        let user_id = self.resume_tokens.redeem(token)?;
+        self.connections.evict(&user_id).await;
+        Some(user_id)
+    }
+
+    /// A token the client can reconnect with to stay `user_id`
+    pub fn issue_resume_token(&self, user_id: Uuid) -> Uuid {
+        self.resume_tokens.issue(user_id)
+    }
+
+    pub async fn register_connection(&self, user_id: Uuid, sender: ClientSender) {
+        self.touch();
+        self.connections.register(user_id, sender).await;
+    }
+
+    /// Remove the connection sending to `queue`, `false` if the user resumed on another one
+    pub async fn unregister_connection(&self, user_id: &Uuid, queue: &Sender<Message>) -> bool {
+        self.touch();
+        self.connections.unregister(user_id, queue).await
+    }
+
+    pub async fn broadcast(&self, message: Message, delivery: Delivery) {
+        self.connections.broadcast(message, delivery).await;
+    }
+
+    pub async fn connection_count(&self) -> usize {
+        self.connections.connection_count().await
+    }
+
+    /// Frames waiting in the send queue of each connection
+    pub async fn send_queue_depths(&self) -> Vec<usize> {
+        self.connections.queue_depths().await
+    }
+
+    /// Where the room's song was forked from, `None` if it was not
+    pub fn origin(&self) -> Result<Option<ForkOrigin>, StorageError> {
+        self.synthesizer.origin()
+    }
+
+    pub fn song_dimensions(&self) -> SongDimensions {
+        self.synthesizer.dimensions()
+    }
+
+    /// Bytes the room's song takes in storage
+    pub fn document_size(&self) -> u64 {
+        self.synthesizer.stored_size()
+    }
+}
+
+impl RoomManager {
+    pub fn new(
+        storage: Box<dyn StorageProvider>,
+        song_config: SongConfig,
+        abuse_config: AbuseConfig,
+        max_rooms: usize,
+    ) -> Result<Self, StorageError> {
+        let stored_rooms = storage.count()?;
+        Ok(Self {
+            rooms: RwLock::new(HashMap::new()),
+            storage: Arc::from(storage),
+            song_config,
+            abuse_config,
+            max_rooms,
+            stored_rooms: Arc::new(AtomicUsize::new(stored_rooms)),
+        })
+    }
+
+    /// Get a room, loading it from storage or creating it if `may_create` allows
+    pub async fn get_or_create(
+        &self,
+        room_id: &str,
+        may_create: impl FnOnce() -> bool + Send + 'static,
+    ) -> Result<Arc<Room>, RoomError> {
+        match self.load(room_id, Some(may_create)).await {
+            Ok(room) => Ok(room),
+            // Only a new room that `may_create` turned down is missing here
+            Err(LoadError::Missing) => Err(RoomError::RateLimited),
+            Err(LoadError::Fork(ForkError::Room(e))) => Err(e),
+            Err(LoadError::Fork(e)) => unreachable!("not forking: {}", e),
+        }
+    }
+
+    /// Get a room that is loaded or has been stored before
+    pub async fn get_existing(&self, room_id: &str) -> Result<Option<Arc<Room>>, RoomError> {
+        match self.load(room_id, None::<fn() -> bool>).await {
+            Ok(room) => Ok(Some(room)),
+            Err(LoadError::Missing) => Ok(None),
+            Err(LoadError::Fork(ForkError::Room(e))) => Err(e),
+            Err(LoadError::Fork(e)) => unreachable!("not forking: {}", e),
+        }
+    }
+
+    /// The cell of `room_id`, added empty if the room is not loaded
+    async fn cell(&self, room_id: &str) -> RoomCell {
+        if let Some(cell) = self.rooms.read().await.get(room_id) {
+            return cell.clone();
+        }
+        self.rooms
+            .write()
+            .await
+            .entry(room_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Drop the cell of `room_id` if loading it failed and nobody else is
+    /// about to try again
+    async fn forget_empty(&self, room_id: &str) {
+        let mut rooms = self.rooms.write().await;
+        if rooms
+            .get(room_id)
+            .is_some_and(|cell| cell.get().is_none() && Arc::strong_count(cell) == 1)
+        {
+            rooms.remove(room_id);
+        }
+    }
+
+    /// Claim room for a new song, `TooManyRooms` when the server is full
+    fn reserve(stored_rooms: &AtomicUsize, max_rooms: usize) -> Result<(), RoomError> {
+        stored_rooms
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |stored| {
+                (max_rooms == 0 || stored < max_rooms).then_some(stored + 1)
+            })
+            .map(|_| ())
+            .map_err(|_| RoomError::TooManyRooms)
+    }
+
+    /// Load a room into its cell, or wait for the task already loading it.
+    ///
+    /// A room that was never stored is created if `may_create` is given and
+    /// allows it.
+    async fn load(
+        &self,
+        room_id: &str,
+        may_create: Option<impl FnOnce() -> bool + Send + 'static>,
+    ) -> Result<Arc<Room>, LoadError> {
+        let cell = self.cell(room_id).await;
+        let result = cell
+            .get_or_try_init(|| async {
+                let storage = self.storage.clone();
+                let song_config = self.song_config.clone();
+                let stored_rooms = self.stored_rooms.clone();
+                let max_rooms = self.max_rooms;
+                let id = room_id.to_string();
+                let synthesizer = tokio::task::spawn_blocking(move || {
+                    if !storage.exists(&id) {
+                        if !may_create.is_some_and(|may_create| may_create()) {
+                            return Err(LoadError::Missing);
+                        }
+                        Self::reserve(&stored_rooms, max_rooms)?;
+                        let created = storage
+                            .open(&id)
+                            .and_then(|storage| SynthesizerState::load(storage, &song_config));
+                        if created.is_err() {
+                            stored_rooms.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        return Ok(created?);
+                    }
+                    Ok(SynthesizerState::load(storage.open(&id)?, &song_config)?)
+                })
+                .await
+                .map_err(|e| StorageError::Io(std::io::Error::other(e)))??;
+                Ok(Arc::new(Room::new(
+                    room_id.to_string(),
+                    synthesizer,
+                    self.abuse_config.clone(),
+                )))
+            })
+            .await
+            .cloned();
+        drop(cell);
+
+        match &result {
+            Ok(_) => tracing::info!("Loaded room {}", room_id),
+            Err(_) => self.forget_empty(room_id).await,
+        }
+        result
+    }
+
+    /// Start a new room `room_id` with a copy of the song of `source` at
+    /// `version`, or as it is now, and record where it came from.
+    ///
+    /// `may_create` is asked once the version and the new ID have checked out,
+    /// right before the room is created.
+    pub async fn fork(
+        &self,
+        source: &Room,
+        version: Option<&str>,
+        room_id: &str,
+        may_create: impl FnOnce() -> bool + Send + 'static,
+    ) -> Result<Arc<Room>, ForkError> {
+        let forked = source
+            .synthesizer
+            .fork(version)
+            .await?
+            .ok_or(ForkError::UnknownVersion)?;
+
+        // Claim an empty cell so joiners wait for the fork instead of creating the room
+        let cell: RoomCell = {
+            let mut rooms = self.rooms.write().await;
+            if rooms.contains_key(room_id) {
+                return Err(ForkError::RoomExists);
+            }
+            let cell = RoomCell::default();
+            rooms.insert(room_id.to_string(), cell.clone());
+            cell
+        };
+
+        let result = cell
+            .get_or_try_init(|| async {
+                let storage = self.storage.clone();
+                let song_config = self.song_config.clone();
+                let stored_rooms = self.stored_rooms.clone();
+                let max_rooms = self.max_rooms;
+                let origin = ForkOrigin {
+                    room: source.id.clone(),
+                    version: forked.version,
+                    forked_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                };
+                let id = room_id.to_string();
+                let synthesizer = tokio::task::spawn_blocking(move || {
+                    if storage.exists(&id) {
+                        return Err(ForkError::RoomExists);
+                    }
+                    if !may_create() {
+                        return Err(RoomError::RateLimited.into());
+                    }
+                    Self::reserve(&stored_rooms, max_rooms)?;
+                    let written = (|| {
+                        let storage = storage.open(&id)?;
+                        for (peer, user_id) in &forked.peers {
+                            storage.append_peer(*peer, *user_id)?;
+                        }
+                        storage.write_origin(&origin)?;
+                        // Written last, the room exists once it has a snapshot
+                        storage.write_snapshot(&forked.snapshot)?;
+                        SynthesizerState::load(storage, &song_config)
+                    })();
+                    if written.is_err() {
+                        stored_rooms.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    Ok(written?)
+                })
+                .await
+                .map_err(|e| StorageError::Io(std::io::Error::other(e)))??;
+                Ok(Arc::new(Room::new(
+                    room_id.to_string(),
+                    synthesizer,
+                    self.abuse_config.clone(),
+                )))
+            })
+            .await
+            .cloned();
+        drop(cell);
+
+        match &result {
+            Ok(_) => tracing::info!("Forked room {} into {}", source.id, room_id),
+            Err(_) => self.forget_empty(room_id).await,
+        }
+        result
+    }
+
+    pub async fn loaded_rooms(&self) -> Vec<Arc<Room>> {
+        self.rooms
+            .read()
+            .await
+            .values()
+            .filter_map(|cell| cell.get().cloned())
+            .collect()
+    }
+
+    /// Persist and drop rooms nobody has used for `idle_timeout`
+    pub async fn unload_idle(&self, idle_timeout: Duration) {
+        let idle: Vec<Arc<Room>> = self
+            .loaded_rooms()
+            .await
+            .into_iter()
+            .filter(|room| room.idle_for() >= idle_timeout)
+            .collect();
+
+        for room in idle {
+            // Connections and in-flight handlers hold their own reference to the room
+            if Arc::strong_count(&room) > 2 {
+                continue;
+            }
+            if let Err(e) = room.persist_synthesizer().await {
+                tracing::error!(
+                    "Failed to persist idle room {}, keeping it loaded: {}",
+                    room.id,
+                    e
+                );
+                continue;
+            }
+
+            let mut rooms = self.rooms.write().await;
+            // A room used since it was persisted has changes the snapshot may lack
+            let unused = rooms.get(&room.id).is_some_and(|cell| {
+                Arc::strong_count(cell) == 1
+                    && cell.get().is_some_and(|loaded| Arc::ptr_eq(loaded, &room))
+                    && Arc::strong_count(&room) == 2
+                    && room.idle_for() >= idle_timeout
+            });
+            if unused {
+                rooms.remove(&room.id);
+                tracing::info!(
+                    "Unloaded idle room {}, {} rooms loaded",
+                    room.id,
+                    rooms.len()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::FileStorageProvider;
+    use the_song_model::{insert_note, Note};
+
+    fn manager(max_rooms: usize) -> RoomManager {
+        let dir = std::env::temp_dir().join(format!("the-song-fork-{}", Uuid::now_v7()));
+        RoomManager::new(
+            Box::new(FileStorageProvider::new(dir)),
+            SongConfig::default(),
+            AbuseConfig::default(),
+            max_rooms,
+        )
+        .unwrap()
+    }
+
+    fn note(id: &str) -> Note {
+        Note {
+            id: id.to_string(),
+            pitch: 0,
+            start: 0.0,
+            duration: 1.0,
+            velocity: 100,
+            track: 0,
+            created_by: "admin".to_string(),
+            created_at: 0.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fork_room() {
+        let rooms = manager(0);
+        let source = rooms.get_or_create("source", || true).await.unwrap();
+        source
+            .edit_song(|doc| insert_note(doc, &note("a")))
+            .await
+            .unwrap();
+        let version = source.versions(1).await[0].id.to_string();
+        source
+            .edit_song(|doc| insert_note(doc, &note("b")))
+            .await
+            .unwrap();
+
+        let latest = rooms.fork(&source, None, "latest", || true).await.unwrap();
+        assert_eq!(latest.song().await.notes.len(), 2);
+        assert_eq!(latest.origin().unwrap().unwrap().room, "source");
+
+        let earlier = rooms
+            .fork(&source, Some(&version), "earlier", || true)
+            .await
+            .unwrap();
+        assert_eq!(earlier.song().await.notes.len(), 1);
+        assert_eq!(earlier.origin().unwrap().unwrap().version, vec![version]);
+        assert_eq!(source.origin().unwrap(), None);
+
+        // The rooms are independent from here on
+        earlier
+            .edit_song(|doc| insert_note(doc, &note("c")))
+            .await
+            .unwrap();
+        assert_eq!(source.song().await.notes.len(), 2);
+
+        // Forks that fail for other reasons never ask to create a room
+        assert!(matches!(
+            rooms.fork(&source, None, "earlier", || false).await,
+            Err(ForkError::RoomExists)
+        ));
+        assert!(matches!(
+            rooms.fork(&source, Some("1@1"), "other", || false).await,
+            Err(ForkError::UnknownVersion)
+        ));
+        assert!(matches!(
+            rooms.fork(&source, None, "other", || false).await,
+            Err(ForkError::Room(RoomError::RateLimited))
+        ));
+        assert!(rooms.get_existing("other").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_room_limit() {
+        let rooms = manager(1);
+        rooms.get_or_create("first", || true).await.unwrap();
+        rooms.get_or_create("first", || false).await.unwrap();
+        assert!(matches!(
+            rooms.get_or_create("second", || true).await,
+            Err(RoomError::TooManyRooms)
+        ));
+        assert!(rooms.get_existing("second").await.unwrap().is_none());
+
+        let rooms = manager(0);
+        assert!(matches!(
+            rooms.get_or_create("refused", || false).await,
+            Err(RoomError::RateLimited)
+        ));
+        assert!(rooms.loaded_rooms().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_loads_share_the_room() {
+        let rooms = manager(0);
+        let (first, second) = tokio::join!(
+            rooms.get_or_create("shared", || true),
+            rooms.get_or_create("shared", || true),
+        );
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+        assert_eq!(rooms.stored_rooms.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unload_idle_room() {
+        let rooms = manager(0);
+        let room = rooms.get_or_create("idle", || true).await.unwrap();
+        room.edit_song(|doc| insert_note(doc, &note("a")))
+            .await
+            .unwrap();
+
+        // Still in use
+        rooms.unload_idle(Duration::ZERO).await;
+        assert_eq!(rooms.loaded_rooms().await.len(), 1);
+
+        drop(room);
+        rooms.unload_idle(Duration::ZERO).await;
+        assert!(rooms.loaded_rooms().await.is_empty());
+        let room = rooms.get_existing("idle").await.unwrap().unwrap();
+        assert_eq!(room.song().await.notes.len(), 1);
+    }
+}
@@ -0,0 +1,574 @@
+//! Durable storage for the synthesizer document.
+//!
+//! A song is stored as the latest full Loro snapshot plus an append-only log
+//! of the updates accepted since that snapshot. On startup the snapshot is
+//! imported and the log replayed; every so often the log is compacted into a
+//! fresh snapshot.
+//!
+//! Next to the song, the user each Loro peer belongs to is kept in a log of its
+//! own that compaction leaves alone, and a forked song records where it came
+//! from.
+//!
+//! Writes of a loaded song go through a `StorageWriter`, which does the file
+//! I/O on the blocking pool in the order the writes were queued.
+
+use loro::PeerID;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+const SNAPSHOT_FILE: &str = "snapshot.loro";
+const UPDATES_FILE: &str = "updates.log";
+const PEERS_FILE: &str = "peers.log";
+const ORIGIN_FILE: &str = "origin.json";
+
+// Most queued writes done in one trip to the blocking pool
+const WRITE_BATCH: usize = 64;
+
+/// Snapshot and pending updates read back from storage
+pub struct StoredSong {
+    pub snapshot: Vec<u8>,
+    pub updates: Vec<Vec<u8>>,
+}
+
+/// Where a forked song came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForkOrigin {
+    /// Room the song was forked from
+    pub room: String,
+    /// Frontiers of the source song the fork starts at, as `counter@peer`
+    pub version: Vec<String>,
+    /// Unix seconds
+    pub forked_at: u64,
+}
+
+/// Pluggable persistence backend for a song document
+pub trait SongStorage: Send + Sync {
+    /// Load the latest snapshot and the updates logged after it, if any
+    fn load(&self) -> Result<Option<StoredSong>, StorageError>;
+
+    /// Append an accepted update to the log
+    fn append_update(&self, update: &[u8]) -> Result<(), StorageError>;
+
+    /// Replace the stored snapshot and truncate the update log
+    fn write_snapshot(&self, snapshot: &[u8]) -> Result<(), StorageError>;
+
+    /// Load the user each Loro peer of the song belongs to
+    fn load_peers(&self) -> Result<HashMap<PeerID, Uuid>, StorageError>;
+
+    /// Record that a Loro peer belongs to a user
+    fn append_peer(&self, peer: PeerID, user_id: Uuid) -> Result<(), StorageError>;
+
+    /// Where the song was forked from, `None` if it was not
+    fn load_origin(&self) -> Result<Option<ForkOrigin>, StorageError>;
+
+    /// Record where the song was forked from
+    fn write_origin(&self, origin: &ForkOrigin) -> Result<(), StorageError>;
+}
+
+/// Opens the storage of each room
+pub trait StorageProvider: Send + Sync {
+    fn open(&self, room_id: &str) -> Result<Box<dyn SongStorage>, StorageError>;
+
+    /// Whether a song has been stored for the room
+    fn exists(&self, room_id: &str) -> bool;
+
+    /// Number of rooms with a stored song
+    fn count(&self) -> Result<usize, StorageError>;
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    Import(loro::LoroError),
+    Export(loro::LoroEncodeError),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage I/O error: {}", e),
+            StorageError::Import(e) => write!(f, "failed to import stored song: {}", e),
+            StorageError::Export(e) => write!(f, "failed to export song: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<loro::LoroError> for StorageError {
+    fn from(e: loro::LoroError) -> Self {
+        StorageError::Import(e)
+    }
+}
+
+impl From<loro::LoroEncodeError> for StorageError {
+    fn from(e: loro::LoroEncodeError) -> Self {
+        StorageError::Export(e)
+    }
+}
+
+/// Stores a song as `snapshot.loro` and `updates.log` inside a directory.
+///
+/// Log records are a little-endian `u32` length followed by the update bytes.
+/// A record cut short by a crash is ignored on load. Peers are kept in
+/// `peers.log`, one `peer user` line each, and the fork origin in `origin.json`.
+pub struct FileStorage {
+    dir: PathBuf,
+    log: Mutex<Option<BufWriter<File>>>,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            log: Mutex::new(None),
+        })
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(SNAPSHOT_FILE)
+    }
+
+    fn updates_path(&self) -> PathBuf {
+        self.dir.join(UPDATES_FILE)
+    }
+
+    fn peers_path(&self) -> PathBuf {
+        self.dir.join(PEERS_FILE)
+    }
+
+    fn origin_path(&self) -> PathBuf {
+        self.dir.join(ORIGIN_FILE)
+    }
+
+    fn read_updates(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut data = Vec::new();
+        match File::open(self.updates_path()) {
+            Ok(mut file) => {
+                file.read_to_end(&mut data)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        }
+
+        let mut updates = Vec::new();
+        let mut offset = 0;
+        while offset + 4 <= data.len() {
+            let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            let start = offset + 4;
+            if start + len > data.len() {
+                tracing::warn!(
+                    "Ignoring truncated record at the end of {}",
+                    self.updates_path().display()
+                );
+                break;
+            }
+            updates.push(data[start..start + len].to_vec());
+            offset = start + len;
+        }
+        Ok(updates)
+    }
+}
+
+impl SongStorage for FileStorage {
+    fn load(&self) -> Result<Option<StoredSong>, StorageError> {
+        let snapshot = match fs::read(self.snapshot_path()) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let updates = self.read_updates()?;
+        Ok(Some(StoredSong { snapshot, updates }))
+    }
+
+    fn append_update(&self, update: &[u8]) -> Result<(), StorageError> {
+        let mut log = self.log.lock().unwrap();
+        if log.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.updates_path())?;
+            *log = Some(BufWriter::new(file));
+        }
+
+        let writer = log.as_mut().unwrap();
+        writer.write_all(&(update.len() as u32).to_le_bytes())?;
+        writer.write_all(update)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_snapshot(&self, snapshot: &[u8]) -> Result<(), StorageError> {
+        // Hold the log lock so no update lands between the snapshot and the truncation
+        let mut log = self.log.lock().unwrap();
+
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(snapshot)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.snapshot_path())?;
+
+        // Updates replayed twice after a crash here are harmless, Loro imports are idempotent
+        *log = None;
+        File::create(self.updates_path())?;
+        Ok(())
+    }
+
+    fn load_peers(&self) -> Result<HashMap<PeerID, Uuid>, StorageError> {
+        let text = match fs::read_to_string(self.peers_path()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut peers = HashMap::new();
+        for line in text.lines() {
+            let record = line.split_once(' ').and_then(|(peer, user_id)| {
+                Some((peer.parse().ok()?, Uuid::parse_str(user_id).ok()?))
+            });
+            match record {
+                Some((peer, user_id)) => {
+                    peers.insert(peer, user_id);
+                }
+                None => tracing::warn!(
+                    "Ignoring malformed record {:?} in {}",
+                    line,
+                    self.peers_path().display()
+                ),
+            }
+        }
+        Ok(peers)
+    }
+
+    fn append_peer(&self, peer: PeerID, user_id: Uuid) -> Result<(), StorageError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.peers_path())?;
+        writeln!(file, "{} {}", peer, user_id)?;
+        Ok(())
+    }
+
+    fn load_origin(&self) -> Result<Option<ForkOrigin>, StorageError> {
+        match fs::read(self.origin_path()) {
+            Ok(data) => Ok(Some(
+                serde_json::from_slice(&data).map_err(io::Error::from)?,
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_origin(&self, origin: &ForkOrigin) -> Result<(), StorageError> {
+        let data = serde_json::to_vec_pretty(origin).map_err(io::Error::from)?;
+        fs::write(self.origin_path(), data)?;
+        Ok(())
+    }
+}
+
+/// A write queued for a song's storage
+enum StorageWrite {
+    Update(Vec<u8>),
+    Peer(PeerID, Uuid),
+    Snapshot(Vec<u8>, oneshot::Sender<Result<(), StorageError>>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Writes to a song's storage in the order they were queued, off the async
+/// runtime.
+///
+/// Queueing never blocks, so a caller can queue while holding the document
+/// lock to keep the log in import order without waiting on the disk.
+pub struct StorageWriter {
+    queue: mpsc::UnboundedSender<StorageWrite>,
+    // Size of the stored snapshot plus logged updates
+    stored_bytes: Arc<AtomicU64>,
+}
+
+impl StorageWriter {
+    /// Start writing to `storage`, which holds `stored_bytes` so far
+    pub fn spawn(storage: Arc<dyn SongStorage>, stored_bytes: u64) -> Self {
+        let (queue, mut writes) = mpsc::unbounded_channel();
+        let stored_bytes = Arc::new(AtomicU64::new(stored_bytes));
+        let stored = stored_bytes.clone();
+        tokio::spawn(async move {
+            let mut batch = Vec::new();
+            while writes.recv_many(&mut batch, WRITE_BATCH).await > 0 {
+                let batch = std::mem::take(&mut batch);
+                let storage = storage.clone();
+                let stored = stored.clone();
+                let written = tokio::task::spawn_blocking(move || {
+                    for write in batch {
+                        apply_write(storage.as_ref(), &stored, write);
+                    }
+                })
+                .await;
+                if let Err(e) = written {
+                    tracing::error!("Song storage writer failed: {}", e);
+                }
+            }
+        });
+        Self {
+            queue,
+            stored_bytes,
+        }
+    }
+
+    fn queue(&self, write: StorageWrite) {
+        if self.queue.send(write).is_err() {
+            tracing::error!("Song storage writer is gone, dropping a write");
+        }
+    }
+
+    /// Queue an accepted update for the log
+    pub fn append_update(&self, update: Vec<u8>) {
+        self.queue(StorageWrite::Update(update));
+    }
+
+    /// Queue the owner of a Loro peer
+    pub fn append_peer(&self, peer: PeerID, user_id: Uuid) {
+        self.queue(StorageWrite::Peer(peer, user_id));
+    }
+
+    /// Queue a snapshot that replaces the stored one and truncates the log.
+    ///
+    /// The returned future resolves once it is written; dropping it leaves
+    /// failures to the log.
+    pub fn write_snapshot(
+        &self,
+        snapshot: Vec<u8>,
+    ) -> impl Future<Output = Result<(), StorageError>> + 'static {
+        let (done, written) = oneshot::channel();
+        self.queue(StorageWrite::Snapshot(snapshot, done));
+        async move {
+            written
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("song storage writer is gone").into()))
+        }
+    }
+
+    /// Resolves once every write queued so far has been done
+    pub fn flush(&self) -> impl Future<Output = ()> + 'static {
+        let (done, flushed) = oneshot::channel();
+        self.queue(StorageWrite::Flush(done));
+        async move {
+            let _ = flushed.await;
+        }
+    }
+
+    /// Bytes written so far, snapshot plus logged updates
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes.load(Ordering::SeqCst)
+    }
+}
+
+fn apply_write(storage: &dyn SongStorage, stored_bytes: &AtomicU64, write: StorageWrite) {
+    match write {
+        StorageWrite::Update(update) => match storage.append_update(&update) {
+            Ok(()) => {
+                stored_bytes.fetch_add(update.len() as u64, Ordering::SeqCst);
+            }
+            Err(e) => tracing::error!("Failed to persist synthesizer update: {}", e),
+        },
+        StorageWrite::Peer(peer, user_id) => {
+            if let Err(e) = storage.append_peer(peer, user_id) {
+                tracing::error!("Failed to persist owner of peer {}: {}", peer, e);
+            }
+        }
+        StorageWrite::Snapshot(snapshot, done) => {
+            let result = storage.write_snapshot(&snapshot);
+            if result.is_ok() {
+                stored_bytes.store(snapshot.len() as u64, Ordering::SeqCst);
+                tracing::debug!("Wrote song snapshot ({} bytes)", snapshot.len());
+            }
+            // Nobody waits for a compaction, so report its failure here
+            if let Err(Err(e)) = done.send(result) {
+                tracing::error!("Failed to write song snapshot: {}", e);
+            }
+        }
+        StorageWrite::Flush(done) => {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Keeps every room in its own `FileStorage` directory under `root/rooms`
+pub struct FileStorageProvider {
+    root: PathBuf,
+}
+
+impl FileStorageProvider {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn room_dir(&self, room_id: &str) -> PathBuf {
+        self.root.join("rooms").join(room_id)
+    }
+}
+
+impl StorageProvider for FileStorageProvider {
+    fn open(&self, room_id: &str) -> Result<Box<dyn SongStorage>, StorageError> {
+        let storage = FileStorage::open(self.room_dir(room_id))?;
+        Ok(Box::new(storage))
+    }
+
+    fn exists(&self, room_id: &str) -> bool {
+        self.room_dir(room_id).join(SNAPSHOT_FILE).exists()
+    }
+
+    fn count(&self) -> Result<usize, StorageError> {
+        let entries = match fs::read_dir(self.root.join("rooms")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut count = 0;
+        for entry in entries {
+            if entry?.path().join(SNAPSHOT_FILE).exists() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("the-song-{}-{}", name, uuid::Uuid::now_v7()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_empty_directory() {
+        let storage = FileStorage::open(temp_dir("empty")).unwrap();
+        assert!(storage.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_and_updates_roundtrip() {
+        let dir = temp_dir("roundtrip");
+        let storage = FileStorage::open(&dir).unwrap();
+        storage.write_snapshot(&[1, 2, 3]).unwrap();
+        storage.append_update(&[4, 5]).unwrap();
+        storage.append_update(&[6]).unwrap();
+
+        let reopened = FileStorage::open(&dir).unwrap();
+        let stored = reopened.load().unwrap().unwrap();
+        assert_eq!(stored.snapshot, vec![1, 2, 3]);
+        assert_eq!(stored.updates, vec![vec![4, 5], vec![6]]);
+
+        reopened.write_snapshot(&[7]).unwrap();
+        let stored = reopened.load().unwrap().unwrap();
+        assert_eq!(stored.snapshot, vec![7]);
+        assert!(stored.updates.is_empty());
+    }
+
+    #[test]
+    fn test_truncated_record_is_ignored() {
+        let dir = temp_dir("truncated");
+        let storage = FileStorage::open(&dir).unwrap();
+        storage.write_snapshot(&[0]).unwrap();
+        storage.append_update(&[1, 2, 3]).unwrap();
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(UPDATES_FILE))
+            .unwrap();
+        file.write_all(&10u32.to_le_bytes()).unwrap();
+        file.write_all(&[9, 9]).unwrap();
+
+        let stored = storage.load().unwrap().unwrap();
+        assert_eq!(stored.updates, vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn test_peers_survive_compaction() {
+        let dir = temp_dir("peers");
+        let storage = FileStorage::open(&dir).unwrap();
+        assert!(storage.load_peers().unwrap().is_empty());
+
+        let user_id = uuid::Uuid::now_v7();
+        storage.append_peer(42, user_id).unwrap();
+        storage.append_peer(u64::MAX, user_id).unwrap();
+        storage.write_snapshot(&[0]).unwrap();
+
+        let peers = FileStorage::open(&dir).unwrap().load_peers().unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[&42], user_id);
+        assert_eq!(peers[&u64::MAX], user_id);
+    }
+
+    #[tokio::test]
+    async fn test_writer_keeps_queue_order() {
+        let dir = temp_dir("writer");
+        let writer = StorageWriter::spawn(Arc::new(FileStorage::open(&dir).unwrap()), 0);
+        writer.append_update(vec![1, 2]);
+        writer.append_peer(42, uuid::Uuid::now_v7());
+        writer.append_update(vec![3]);
+        // Compaction truncates the updates queued before it
+        writer.write_snapshot(vec![9]).await.unwrap();
+
+        let storage = FileStorage::open(&dir).unwrap();
+        let stored = storage.load().unwrap().unwrap();
+        assert_eq!(stored.snapshot, vec![9]);
+        assert!(stored.updates.is_empty());
+        assert_eq!(storage.load_peers().unwrap().len(), 1);
+        assert_eq!(writer.stored_bytes(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_writer_flush() {
+        let dir = temp_dir("flush");
+        let writer = StorageWriter::spawn(Arc::new(FileStorage::open(&dir).unwrap()), 0);
+        writer.write_snapshot(vec![9]).await.unwrap();
+        writer.append_update(vec![1, 2]);
+        writer.append_update(vec![3]);
+        writer.flush().await;
+
+        let stored = FileStorage::open(&dir).unwrap().load().unwrap().unwrap();
+        assert_eq!(stored.updates, vec![vec![1, 2], vec![3]]);
+        assert_eq!(writer.stored_bytes(), 4);
+    }
+
+    #[test]
+    fn test_origin_roundtrip() {
+        let storage = FileStorage::open(temp_dir("origin")).unwrap();
+        assert_eq!(storage.load_origin().unwrap(), None);
+
+        let origin = ForkOrigin {
+            room: "public".to_string(),
+            version: vec!["3@7".to_string()],
+            forked_at: 1_700_000_000,
+        };
+        storage.write_origin(&origin).unwrap();
+        assert_eq!(storage.load_origin().unwrap(), Some(origin));
+    }
+}
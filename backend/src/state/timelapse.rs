@@ -0,0 +1,222 @@
+//! Timelapse of a song.
+//!
+//! The history is replayed change by change into an empty document, in
+//! Lamport order, and the notes each change touched are compared before and
+//! after it. What comes out is a time-ordered list of note events that can be
+//! played back to watch the song grow.
+
+use loro::{
+    ContainerID, ContainerTrait, ExportMode, IdSpan, JsonChange, JsonMapOp, JsonOpContent, LoroDoc,
+    LoroValue, PeerID, ToJson, ID,
+};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use uuid::Uuid;
+
+use the_song_model::schema;
+
+/// What happened to a note
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NoteEvent {
+    Added {
+        id: String,
+        note: serde_json::Value,
+    },
+    Removed {
+        id: String,
+    },
+    /// Fields that changed with their new value, `null` for removed fields
+    Edited {
+        id: String,
+        fields: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimelapseEvent {
+    /// Version of the change, as listed in the version history
+    pub version: String,
+    /// Unix seconds of the change, or of the latest earlier change that has one
+    pub timestamp: Option<i64>,
+    pub author: Option<Uuid>,
+    #[serde(flatten)]
+    pub event: NoteEvent,
+}
+
+/// Note events of the whole history of `doc`, oldest first
+pub fn events(doc: &LoroDoc, owners: &HashMap<PeerID, Uuid>) -> Vec<TimelapseEvent> {
+    let mut changes: Vec<JsonChange> = doc
+        .oplog_vv()
+        .iter()
+        .flat_map(|(&peer, &end)| doc.export_json_in_id_span(IdSpan::new(peer, 0, end)))
+        .collect();
+    changes.sort_by_key(|change| (change.lamport, change.id.peer));
+
+    let replay = LoroDoc::new();
+    let notes = schema::notes_map(&replay);
+    let notes_id = notes.id();
+    let note_value = |id: &str| notes.get(id).map(|note| note.get_deep_value());
+    // Note maps by container, learnt from the ops that create them
+    let mut note_ids: HashMap<ContainerID, String> = HashMap::new();
+    let mut timestamp = None;
+    let mut events = Vec::new();
+
+    for change in changes {
+        let mut touched = BTreeSet::new();
+        for op in &change.ops {
+            match &op.content {
+                JsonOpContent::Map(JsonMapOp::Insert { key, value })
+                    if op.container == notes_id =>
+                {
+                    if let LoroValue::Container(created) = value {
+                        note_ids.insert(created.clone(), key.clone());
+                    }
+                    touched.insert(key.clone());
+                }
+                JsonOpContent::Map(JsonMapOp::Delete { key }) if op.container == notes_id => {
+                    touched.insert(key.clone());
+                }
+                JsonOpContent::Map(_) => {
+                    if let Some(id) = note_ids.get(&op.container) {
+                        touched.insert(id.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let before: Vec<_> = touched.iter().map(|id| note_value(id)).collect();
+        let span = IdSpan::new(
+            change.id.peer,
+            change.id.counter,
+            change.id.counter + change.op_len() as i32,
+        );
+        let imported = doc
+            .export(ExportMode::updates_in_range(vec![span]))
+            .map_err(|e| e.to_string())
+            .and_then(|update| replay.import(&update).map_err(|e| e.to_string()));
+        if let Err(e) = imported {
+            tracing::warn!("Timelapse stopped at change {}: {}", change.id, e);
+            break;
+        }
+
+        if change.timestamp > 0 {
+            // Clocks of different authors disagree, keep the stream in order
+            timestamp = Some(timestamp.unwrap_or(0).max(change.timestamp));
+        }
+        let version = ID::new(change.id.peer, span.counter.end - 1).to_string();
+        let author = owners.get(&change.id.peer).copied();
+        for (id, before) in touched.into_iter().zip(before) {
+            let after = note_value(&id);
+            let Some(event) = note_event(id, before, after) else {
+                continue;
+            };
+            events.push(TimelapseEvent {
+                version: version.clone(),
+                timestamp,
+                author,
+                event,
+            });
+        }
+    }
+    events
+}
+
+/// Compare a note before and after a change
+fn note_event(
+    id: String,
+    before: Option<LoroValue>,
+    after: Option<LoroValue>,
+) -> Option<NoteEvent> {
+    match (before, after) {
+        (None, Some(note)) => Some(NoteEvent::Added {
+            id,
+            note: note.to_json_value(),
+        }),
+        (Some(_), None) => Some(NoteEvent::Removed { id }),
+        (Some(LoroValue::Map(before)), Some(LoroValue::Map(after))) => {
+            let mut fields = serde_json::Map::new();
+            for (field, value) in after.iter() {
+                if before.get(field) != Some(value) {
+                    fields.insert(field.clone(), value.to_json_value());
+                }
+            }
+            for field in before.keys() {
+                if !after.contains_key(field) {
+                    fields.insert(field.clone(), serde_json::Value::Null);
+                }
+            }
+            (!fields.is_empty()).then_some(NoteEvent::Edited { id, fields })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use the_song_model::{delete_note, insert_note, update_note, Note, NoteUpdate};
+
+    fn note(id: &str) -> Note {
+        Note {
+            id: id.to_string(),
+            pitch: 0,
+            start: 0.0,
+            duration: 1.0,
+            velocity: 100,
+            track: 0,
+            created_by: "me".to_string(),
+            created_at: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let doc = LoroDoc::new();
+        doc.set_peer_id(1).unwrap();
+        doc.set_record_timestamp(true);
+        insert_note(&doc, &note("a")).unwrap();
+        insert_note(&doc, &note("b")).unwrap();
+        doc.commit();
+        // Commits of one peer in quick succession merge into one change
+        doc.set_peer_id(3).unwrap();
+        let longer = NoteUpdate {
+            duration: Some(2.0),
+            ..NoteUpdate::default()
+        };
+        update_note(&doc, "a", &longer).unwrap();
+        doc.commit();
+        doc.set_peer_id(2).unwrap();
+        delete_note(&doc, "b").unwrap();
+        doc.commit();
+
+        let user = Uuid::now_v7();
+        let events = events(&doc, &HashMap::from([(2, user)]));
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["type"].clone())
+            .collect();
+        assert_eq!(kinds, vec!["added", "added", "edited", "removed"]);
+
+        assert_eq!(events[0].author, None);
+        assert!(events[0].timestamp.is_some());
+        match &events[2].event {
+            NoteEvent::Edited { id, fields } => {
+                assert_eq!(id, "a");
+                assert_eq!(fields.get("duration"), Some(&json!(2.0)));
+                assert_eq!(fields.len(), 1);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert_eq!(
+            events[3].event,
+            NoteEvent::Removed {
+                id: "b".to_string()
+            }
+        );
+        assert_eq!(events[3].author, Some(user));
+        assert_eq!(events[3].version, "0@2");
+    }
+}
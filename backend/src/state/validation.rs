@@ -0,0 +1,716 @@
+//! Schema validation for the synthesizer document.
+//!
+//! Client updates are imported into a staged copy of the song first and the
+//! parts of the song they changed are checked here, before and after. Updates
+//! that introduce a violation are rejected and never reach the live document
+//! or other clients.
+
+use loro::{ContainerID, IdSpan, Index, JsonMapOp, JsonOpContent, LoroDoc, LoroValue};
+use std::collections::HashSet;
+use std::fmt;
+
+use the_song_model::schema::{self, ROOT_CONTAINERS};
+use the_song_model::{note_value, pitch_list, SongDimensions};
+
+/// Lowest BPM the editor allows
+pub const MIN_BPM: f64 = 60.0;
+/// Highest BPM the editor allows
+pub const MAX_BPM: f64 = 160.0;
+/// Highest MIDI velocity
+pub const MAX_VELOCITY: f64 = 127.0;
+/// Length of the song in the editor, `SONG_LEN_IN_SECONDS` in `ui/src/config.ts`
+pub const SONG_SECONDS: f64 = 100.0;
+/// Beat every note must end by: the song's length at the highest BPM, so
+/// changing the tempo never leaves notes out of bounds
+pub const MAX_SONG_BEATS: f64 = SONG_SECONDS * MAX_BPM / 60.0;
+
+/// A single way in which the document breaks the song schema
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub reason: String,
+}
+
+impl SchemaViolation {
+    fn new(path: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+/// Check the whole document against the song schema for a song of `dimensions`
+pub fn validate(doc: &LoroDoc, dimensions: SongDimensions) -> HashSet<SchemaViolation> {
+    let mut violations = HashSet::new();
+    let root = match doc.get_deep_value() {
+        LoroValue::Map(root) => root,
+        _ => {
+            violations.insert(SchemaViolation::new("/", "document root is not a map"));
+            return violations;
+        }
+    };
+
+    for key in root.keys() {
+        if !ROOT_CONTAINERS.contains(&key.as_str()) {
+            violations.insert(SchemaViolation::new(key.as_str(), "unknown root container"));
+        }
+    }
+
+    let notes = match root.get(schema::NOTES) {
+        Some(LoroValue::Map(notes)) => notes.clone(),
+        _ => Default::default(),
+    };
+    validate_bpm(root.get(schema::BPM), &mut violations);
+    validate_tracks(
+        root.get(schema::TRACKS),
+        dimensions,
+        &|id| notes.get(id).cloned(),
+        &mut violations,
+    );
+    validate_track_configs(root.get(schema::TRACK_CONFIGS), dimensions, &mut violations);
+    validate_notes(root.get(schema::NOTES), dimensions, &mut violations);
+    violations
+}
+
+/// Parts of the song some ops changed, the only parts they can have broken
+#[derive(Debug, Default)]
+pub struct Touched {
+    /// Root containers outside the schema
+    unknown_roots: HashSet<String>,
+    bpm: bool,
+    /// The `tracks` list or one of the tracks changed shape
+    tracks: bool,
+    track_configs: bool,
+    /// Pitch lists as `(track, pitch)`
+    pitch_lists: HashSet<(usize, usize)>,
+    notes: HashSet<String>,
+}
+
+impl Touched {
+    /// What the ops `doc` has beyond `before` changed
+    pub fn since(doc: &LoroDoc, before: &loro::VersionVector) -> Self {
+        let mut touched = Touched::default();
+        for (&peer, &end) in doc.oplog_vv().iter() {
+            let start = before.get(&peer).copied().unwrap_or(0);
+            if end <= start {
+                continue;
+            }
+            for change in doc.export_json_in_id_span(IdSpan::new(peer, start, end)) {
+                for op in change.ops {
+                    touched.add(doc, &op.container, &op.content);
+                }
+            }
+        }
+        touched
+    }
+
+    fn add(&mut self, doc: &LoroDoc, container: &ContainerID, content: &JsonOpContent) {
+        // Containers no longer in the song cannot break it
+        let Some(path) = doc.get_path_to_container(container) else {
+            return;
+        };
+        let mut indexes = path.iter().map(|(_, index)| index);
+        let Some(Index::Key(root)) = indexes.next() else {
+            return;
+        };
+        match root.as_str() {
+            schema::BPM => self.bpm = true,
+            schema::TRACK_CONFIGS => self.track_configs = true,
+            schema::TRACKS => match (indexes.next(), indexes.next()) {
+                (Some(Index::Seq(track)), Some(Index::Seq(pitch))) => {
+                    self.pitch_lists.insert((*track, *pitch));
+                }
+                _ => self.tracks = true,
+            },
+            schema::NOTES => match (indexes.next(), content) {
+                (Some(Index::Key(id)), _) => {
+                    self.notes.insert(id.to_string());
+                }
+                (
+                    None,
+                    JsonOpContent::Map(JsonMapOp::Insert { key, .. } | JsonMapOp::Delete { key }),
+                ) => {
+                    self.notes.insert(key.clone());
+                }
+                _ => {}
+            },
+            root => {
+                self.unknown_roots.insert(root.to_string());
+            }
+        }
+    }
+
+    /// Add the pitch lists the touched notes are on in `doc`
+    fn add_note_lists(&mut self, doc: &LoroDoc, dimensions: SongDimensions) {
+        for id in &self.notes {
+            let position = note_value(doc, id).as_ref().and_then(note_position);
+            if let Some((track, pitch)) = position {
+                if track < dimensions.tracks && pitch < dimensions.pitches {
+                    self.pitch_lists.insert((track, pitch));
+                }
+            }
+        }
+    }
+}
+
+/// A violation the change from `before` to `after` introduced in the parts it touched.
+///
+/// Violations already in those parts are tolerated so one bad state does not
+/// block every later update.
+pub fn introduced(
+    before: &LoroDoc,
+    after: &LoroDoc,
+    dimensions: SongDimensions,
+    mut touched: Touched,
+) -> Option<SchemaViolation> {
+    // A note that moved or went away can leave its old pitch list behind
+    touched.add_note_lists(before, dimensions);
+    touched.add_note_lists(after, dimensions);
+
+    let known = validate_touched(before, dimensions, &touched);
+    validate_touched(after, dimensions, &touched)
+        .into_iter()
+        .find(|violation| !known.contains(violation))
+}
+
+/// Check the `touched` parts of the document against the song schema
+fn validate_touched(
+    doc: &LoroDoc,
+    dimensions: SongDimensions,
+    touched: &Touched,
+) -> HashSet<SchemaViolation> {
+    let mut violations = HashSet::new();
+    let LoroValue::Map(root) = doc.get_value() else {
+        violations.insert(SchemaViolation::new("/", "document root is not a map"));
+        return violations;
+    };
+    let root_value = |name: &str| match root.get(name)? {
+        LoroValue::Container(id) => doc
+            .get_container(id.clone())
+            .map(|container| loro::ValueOrContainer::Container(container).get_deep_value()),
+        value => Some(value.clone()),
+    };
+
+    for name in &touched.unknown_roots {
+        if root.contains_key(name) {
+            violations.insert(SchemaViolation::new(
+                name.as_str(),
+                "unknown root container",
+            ));
+        }
+    }
+    if touched.bpm {
+        validate_bpm(root_value(schema::BPM).as_ref(), &mut violations);
+    }
+    if touched.track_configs {
+        validate_track_configs(
+            root_value(schema::TRACK_CONFIGS).as_ref(),
+            dimensions,
+            &mut violations,
+        );
+    }
+
+    let note = |id: &str| note_value(doc, id);
+    if touched.tracks {
+        validate_tracks(
+            root_value(schema::TRACKS).as_ref(),
+            dimensions,
+            &note,
+            &mut violations,
+        );
+    } else {
+        for &(track, pitch) in &touched.pitch_lists {
+            if let Some(list) = pitch_list(doc, track, pitch) {
+                validate_pitch_list(track, pitch, &list.get_value(), &note, &mut violations);
+            }
+        }
+    }
+
+    for id in &touched.notes {
+        if let Some(value) = note_value(doc, id) {
+            validate_note(id, &value, dimensions, &mut violations);
+        }
+    }
+    violations
+}
+
+fn as_number(value: &LoroValue) -> Option<f64> {
+    match value {
+        LoroValue::Double(n) => Some(*n),
+        LoroValue::I64(n) => Some(*n as f64),
+        _ => None,
+    }
+}
+
+fn validate_bpm(bpm: Option<&LoroValue>, violations: &mut HashSet<SchemaViolation>) {
+    match bpm.and_then(as_number) {
+        Some(bpm) if (MIN_BPM..=MAX_BPM).contains(&bpm) => {}
+        Some(bpm) => {
+            violations.insert(SchemaViolation::new(
+                schema::BPM,
+                format!("{} is outside {}..={}", bpm, MIN_BPM, MAX_BPM),
+            ));
+        }
+        None => {
+            violations.insert(SchemaViolation::new(
+                schema::BPM,
+                "missing or not a counter",
+            ));
+        }
+    }
+}
+
+fn validate_tracks(
+    tracks: Option<&LoroValue>,
+    dimensions: SongDimensions,
+    note: &dyn Fn(&str) -> Option<LoroValue>,
+    violations: &mut HashSet<SchemaViolation>,
+) {
+    let Some(LoroValue::List(tracks)) = tracks else {
+        violations.insert(SchemaViolation::new(
+            schema::TRACKS,
+            "missing or not a list",
+        ));
+        return;
+    };
+    if tracks.len() != dimensions.tracks {
+        violations.insert(SchemaViolation::new(
+            schema::TRACKS,
+            format!(
+                "expected {} tracks, found {}",
+                dimensions.tracks,
+                tracks.len()
+            ),
+        ));
+    }
+
+    for (track_index, track) in tracks.iter().enumerate() {
+        let path = format!("{}/{}", schema::TRACKS, track_index);
+        let LoroValue::List(pitches) = track else {
+            violations.insert(SchemaViolation::new(path, "track is not a list"));
+            continue;
+        };
+        if pitches.len() != dimensions.pitches {
+            violations.insert(SchemaViolation::new(
+                path.as_str(),
+                format!(
+                    "expected {} pitch lists, found {}",
+                    dimensions.pitches,
+                    pitches.len()
+                ),
+            ));
+        }
+        for (pitch, note_ids) in pitches.iter().enumerate() {
+            validate_pitch_list(track_index, pitch, note_ids, note, violations);
+        }
+    }
+}
+
+/// Track and pitch a note value says it is on
+fn note_position(note: &LoroValue) -> Option<(usize, usize)> {
+    let LoroValue::Map(note) = note else {
+        return None;
+    };
+    let index = |field: &str| {
+        note.get(field)
+            .and_then(as_number)
+            .filter(|n| n.fract() == 0.0 && *n >= 0.0)
+            .map(|n| n as usize)
+    };
+    Some((
+        index(schema::note::TRACK_INDEX)?,
+        index(schema::note::PITCH)?,
+    ))
+}
+
+/// Check that a pitch list only lists notes on its own track and pitch
+fn validate_pitch_list(
+    track: usize,
+    pitch: usize,
+    note_ids: &LoroValue,
+    note: &dyn Fn(&str) -> Option<LoroValue>,
+    violations: &mut HashSet<SchemaViolation>,
+) {
+    let path = format!("{}/{}/{}", schema::TRACKS, track, pitch);
+    let LoroValue::List(note_ids) = note_ids else {
+        violations.insert(SchemaViolation::new(path, "pitch list is not a list"));
+        return;
+    };
+    for id in note_ids.iter() {
+        let LoroValue::String(id) = id else {
+            violations.insert(SchemaViolation::new(
+                path.as_str(),
+                "pitch list contains a non-string note ID",
+            ));
+            continue;
+        };
+        match note(id) {
+            None => {
+                violations.insert(SchemaViolation::new(
+                    path.as_str(),
+                    format!("lists {}, which is not in {}", id.as_str(), schema::NOTES),
+                ));
+            }
+            Some(value) if note_position(&value) != Some((track, pitch)) => {
+                violations.insert(SchemaViolation::new(
+                    path.as_str(),
+                    format!("lists {}, which is on another track or pitch", id.as_str()),
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+fn validate_track_configs(
+    configs: Option<&LoroValue>,
+    dimensions: SongDimensions,
+    violations: &mut HashSet<SchemaViolation>,
+) {
+    let Some(LoroValue::List(configs)) = configs else {
+        violations.insert(SchemaViolation::new(
+            schema::TRACK_CONFIGS,
+            "missing or not a list",
+        ));
+        return;
+    };
+    if configs.len() != dimensions.tracks {
+        violations.insert(SchemaViolation::new(
+            schema::TRACK_CONFIGS,
+            format!(
+                "expected {} configs, found {}",
+                dimensions.tracks,
+                configs.len()
+            ),
+        ));
+    }
+
+    for (track_index, config) in configs.iter().enumerate() {
+        let path = format!("{}/{}", schema::TRACK_CONFIGS, track_index);
+        let LoroValue::Map(config) = config else {
+            violations.insert(SchemaViolation::new(path, "config is not a map"));
+            continue;
+        };
+        if let Some(color) = config.get(schema::track_config::ACCENT_COLOR) {
+            if !matches!(color, LoroValue::String(_)) {
+                violations.insert(SchemaViolation::new(
+                    format!("{}/{}", path, schema::track_config::ACCENT_COLOR),
+                    "not a string",
+                ));
+            }
+        }
+    }
+}
+
+fn validate_notes(
+    notes: Option<&LoroValue>,
+    dimensions: SongDimensions,
+    violations: &mut HashSet<SchemaViolation>,
+) {
+    let Some(LoroValue::Map(notes)) = notes else {
+        violations.insert(SchemaViolation::new(schema::NOTES, "missing or not a map"));
+        return;
+    };
+
+    for (note_id, note) in notes.iter() {
+        validate_note(note_id, note, dimensions, violations);
+    }
+}
+
+fn validate_note(
+    note_id: &str,
+    note: &LoroValue,
+    dimensions: SongDimensions,
+    violations: &mut HashSet<SchemaViolation>,
+) {
+    let path = format!("{}/{}", schema::NOTES, note_id);
+    let LoroValue::Map(note) = note else {
+        violations.insert(SchemaViolation::new(path, "note is not a map"));
+        return;
+    };
+
+    let mut check_number = |field: &str, valid: &dyn Fn(f64) -> bool, expected: &str| match note
+        .get(field)
+        .and_then(as_number)
+    {
+        Some(n) if n.is_finite() && valid(n) => {}
+        Some(n) => {
+            violations.insert(SchemaViolation::new(
+                format!("{}/{}", path, field),
+                format!("{} is not {}", n, expected),
+            ));
+        }
+        None => {
+            violations.insert(SchemaViolation::new(
+                format!("{}/{}", path, field),
+                "missing or not a number",
+            ));
+        }
+    };
+    let is_index_below = |n: f64, limit: usize| n.fract() == 0.0 && n >= 0.0 && n < limit as f64;
+
+    check_number(
+        schema::note::PITCH,
+        &|n| is_index_below(n, dimensions.pitches),
+        &format!("a pitch index below {}", dimensions.pitches),
+    );
+    check_number(
+        schema::note::TRACK_INDEX,
+        &|n| is_index_below(n, dimensions.tracks),
+        &format!("a track index below {}", dimensions.tracks),
+    );
+    check_number(
+        schema::note::START_TIME,
+        &|n| (0.0..MAX_SONG_BEATS).contains(&n),
+        &format!("a beat within 0..{}", MAX_SONG_BEATS),
+    );
+    check_number(
+        schema::note::DURATION,
+        &|n| n > 0.0 && n <= MAX_SONG_BEATS,
+        &format!("a beat count within 0..={}", MAX_SONG_BEATS),
+    );
+    check_number(
+        schema::note::VELOCITY,
+        &|n| (0.0..=MAX_VELOCITY).contains(&n),
+        "a MIDI velocity",
+    );
+    check_number(schema::note::CREATED_AT, &|_| true, "a timestamp");
+    let start = note.get(schema::note::START_TIME).and_then(as_number);
+    let duration = note.get(schema::note::DURATION).and_then(as_number);
+    if let (Some(start), Some(duration)) = (start, duration) {
+        if start + duration > MAX_SONG_BEATS {
+            violations.insert(SchemaViolation::new(
+                path.as_str(),
+                format!("ends after beat {}", MAX_SONG_BEATS),
+            ));
+        }
+    }
+
+    match note.get(schema::note::ID) {
+        Some(LoroValue::String(id)) if id.as_str() == note_id => {}
+        Some(LoroValue::String(_)) => {
+            violations.insert(SchemaViolation::new(
+                format!("{}/{}", path, schema::note::ID),
+                "does not match the note's key",
+            ));
+        }
+        _ => {
+            violations.insert(SchemaViolation::new(
+                format!("{}/{}", path, schema::note::ID),
+                "missing or not a string",
+            ));
+        }
+    }
+    if !matches!(
+        note.get(schema::note::CREATED_BY),
+        Some(LoroValue::String(_))
+    ) {
+        violations.insert(SchemaViolation::new(
+            format!("{}/{}", path, schema::note::CREATED_BY),
+            "missing or not a string",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SongConfig;
+    use crate::state::SynthesizerState;
+
+    use the_song_model::schema::note;
+
+    /// Write a note with integer fields, the way JavaScript numbers can arrive
+    fn add_note(doc: &loro::LoroDoc, id: &str, pitch: i64) {
+        let note_map = schema::notes_map(doc)
+            .insert_container(id, loro::LoroMap::new())
+            .unwrap();
+        note_map.insert(note::ID, id).unwrap();
+        note_map.insert(note::PITCH, pitch).unwrap();
+        note_map.insert(note::START_TIME, 0.0).unwrap();
+        note_map.insert(note::DURATION, 1.0).unwrap();
+        note_map.insert(note::VELOCITY, 100).unwrap();
+        note_map.insert(note::CREATED_AT, 0).unwrap();
+        note_map.insert(note::CREATED_BY, "user").unwrap();
+        note_map.insert(note::TRACK_INDEX, 0).unwrap();
+        doc.commit();
+    }
+
+    fn list_note(doc: &loro::LoroDoc, id: &str, track: usize, pitch: usize) {
+        pitch_list(doc, track, pitch).unwrap().push(id).unwrap();
+        doc.commit();
+    }
+
+    /// Violation `edit` introduces when staged on a copy of `doc`
+    fn stage(doc: &loro::LoroDoc, edit: impl FnOnce(&loro::LoroDoc)) -> Option<SchemaViolation> {
+        let staged = doc.fork();
+        let before = staged.oplog_vv();
+        edit(&staged);
+        staged.commit();
+        let touched = Touched::since(&staged, &before);
+        introduced(doc, &staged, SongDimensions::default(), touched)
+    }
+
+    #[test]
+    fn test_new_song_is_valid() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        add_note(&doc, "a", 12);
+        assert!(validate(&doc, SongDimensions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_bpm_out_of_range() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        schema::bpm_counter(&doc).increment(100000.0).unwrap();
+        doc.commit();
+        let violations = validate(&doc, SongDimensions::default());
+        assert!(violations.iter().any(|v| v.path == "bpm"));
+    }
+
+    #[test]
+    fn test_note_pitch_out_of_range() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        add_note(&doc, "a", 500);
+        let violations = validate(&doc, SongDimensions::default());
+        assert_eq!(violations.len(), 1);
+        assert!(violations.iter().all(|v| v.path == "notes/a/pitch"));
+    }
+
+    #[test]
+    fn test_note_past_the_end_of_the_song() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        add_note(&doc, "a", 0);
+        let a = schema::notes_map(&doc).get("a").unwrap();
+        let a = a.into_container().unwrap().into_map().unwrap();
+
+        a.insert(note::START_TIME, 1e300).unwrap();
+        doc.commit();
+        let violations = validate(&doc, SongDimensions::default());
+        assert!(violations.iter().any(|v| v.path == "notes/a/startTime"));
+
+        a.insert(note::START_TIME, MAX_SONG_BEATS - 1.0).unwrap();
+        a.insert(note::DURATION, 2.0).unwrap();
+        doc.commit();
+        let violations = validate(&doc, SongDimensions::default());
+        assert_eq!(violations.len(), 1);
+        assert!(violations.iter().all(|v| v.path == "notes/a"));
+
+        a.insert(note::DURATION, 1.0).unwrap();
+        doc.commit();
+        assert!(validate(&doc, SongDimensions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_pitch_list_entries() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        add_note(&doc, "a", 12);
+        list_note(&doc, "a", 0, 12);
+        assert!(validate(&doc, SongDimensions::default()).is_empty());
+
+        list_note(&doc, "ghost", 0, 12);
+        list_note(&doc, "a", 1, 12);
+        let violations = validate(&doc, SongDimensions::default());
+        assert_eq!(violations.len(), 2);
+        assert!(violations.iter().any(|v| v.path == "tracks/0/12"));
+        assert!(violations.iter().any(|v| v.path == "tracks/1/12"));
+    }
+
+    #[test]
+    fn test_staged_changes() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        add_note(&doc, "a", 12);
+        list_note(&doc, "a", 0, 12);
+
+        // Moving a note along with its pitch list entry is fine
+        assert_eq!(
+            stage(&doc, |staged| {
+                pitch_list(staged, 0, 12).unwrap().delete(0, 1).unwrap();
+                list_note(staged, "a", 0, 13);
+                let a = schema::notes_map(staged).get("a").unwrap();
+                let a = a.into_container().unwrap().into_map().unwrap();
+                a.insert(note::PITCH, 13).unwrap();
+            }),
+            None
+        );
+
+        // Removing a note but not its entry leaves the pitch list dangling
+        let violation = stage(&doc, |staged| {
+            schema::notes_map(staged).delete("a").unwrap();
+        });
+        assert_eq!(violation.unwrap().path, "tracks/0/12");
+
+        let violation = stage(&doc, |staged| list_note(staged, "a", 2, 12));
+        assert_eq!(violation.unwrap().path, "tracks/2/12");
+
+        let violation = stage(&doc, |staged| add_note(staged, "b", 500));
+        assert_eq!(violation.unwrap().path, "notes/b/pitch");
+
+        let violation = stage(&doc, |staged| {
+            schema::bpm_counter(staged).increment(1000.0).unwrap();
+        });
+        assert_eq!(violation.unwrap().path, "bpm");
+
+        let violation = stage(&doc, |staged| {
+            staged.get_map("junk").insert("key", 1).unwrap();
+        });
+        assert_eq!(violation.unwrap().path, "junk");
+    }
+
+    #[test]
+    fn test_staged_changes_keep_known_violations() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        add_note(&doc, "a", 500);
+        list_note(&doc, "ghost", 0, 12);
+
+        // Violations already there do not block changes next to them
+        assert_eq!(
+            stage(&doc, |staged| {
+                add_note(staged, "b", 12);
+                list_note(staged, "b", 0, 12);
+            }),
+            None
+        );
+        assert_eq!(
+            stage(&doc, |staged| {
+                let a = schema::notes_map(staged).get("a").unwrap();
+                let a = a.into_container().unwrap().into_map().unwrap();
+                a.insert(note::VELOCITY, 90).unwrap();
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_cleared_tracks() {
+        let doc = SynthesizerState::new_song_doc(&SongConfig::default());
+        let tracks = schema::tracks_list(&doc);
+        tracks.delete(0, tracks.len()).unwrap();
+        doc.commit();
+        let violations = validate(&doc, SongDimensions::default());
+        assert!(violations.iter().any(|v| v.path == "tracks"));
+    }
+
+    #[test]
+    fn test_configured_dimensions() {
+        let config = SongConfig {
+            tracks: 4,
+            pitches: 24,
+            ..SongConfig::default()
+        };
+        let doc = SynthesizerState::new_song_doc(&config);
+        assert_eq!(SongDimensions::of(&doc), Some(config.dimensions()));
+        add_note(&doc, "a", 23);
+        assert!(validate(&doc, config.dimensions()).is_empty());
+
+        add_note(&doc, "b", 24);
+        let violations = validate(&doc, config.dimensions());
+        assert!(violations.iter().all(|v| v.path == "notes/b/pitch"));
+    }
+}
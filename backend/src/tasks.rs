@@ -1,57 +1,102 @@
 use axum::extract::ws::Message;
+use std::collections::HashMap;
 use tokio::time::{interval, Duration};
 
 use crate::{
     dto::{create_mouse_positions_message, create_stats_message, encode_server_message},
-    state::AppState,
+    metrics::metrics,
+    state::{AppState, Delivery},
 };
 
-/// Global task that broadcasts server stats to all connected clients
+/// Global task that broadcasts server stats to the clients of every room
 pub async fn global_stats_broadcast_task(state: AppState) {
-    let mut interval = interval(Duration::from_millis(1000));
-    let mut last_server_stat = None;
+    let mut interval = interval(state.config().ticks.stats_interval());
+    let mut last_server_stats = HashMap::new();
+    let shutdown = state.shutdown_token();
     loop {
-        interval.tick().await;
-
-        // Check if there are any connections before broadcasting
-        let connection_count = state.connection_count().await;
-        if connection_count == 0 {
-            continue;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => break,
         }
 
-        let server_stats = state.get_server_stats();
-        if last_server_stat == Some(server_stats) {
-            continue;
-        }
-        last_server_stat = Some(server_stats);
-        let response = create_stats_message(server_stats.online_users as u32);
-        let bytes = encode_server_message(&response);
+        let rooms = state.loaded_rooms().await;
+        last_server_stats
+            .retain(|room_id: &String, _| rooms.iter().any(|room| room.id() == room_id));
+
+        for room in rooms {
+            // Check if there are any connections before broadcasting
+            let connection_count = room.connection_count().await;
+            if connection_count == 0 {
+                continue;
+            }
+
+            let server_stats = room.get_server_stats();
+            if last_server_stats.get(room.id()) == Some(&server_stats) {
+                continue;
+            }
+            last_server_stats.insert(room.id().to_string(), server_stats);
+            let response = create_stats_message(server_stats.online_users);
+            let bytes = encode_server_message(&response);
 
-        state.broadcast(Message::Binary(bytes.into())).await;
-        tracing::trace!("Broadcasted stats to {} connections", connection_count);
+            room.broadcast(Message::Binary(bytes.into()), Delivery::Droppable)
+                .await;
+            tracing::trace!(
+                "Broadcasted stats to {} connections in room {}",
+                connection_count,
+                room.id()
+            );
+        }
     }
+    tracing::debug!("Stats broadcast task stopped");
 }
 
-/// Global task that broadcasts mouse positions to all connected clients
+/// Global task that broadcasts mouse positions to the clients of every room
 pub async fn global_mouse_broadcast_task(state: AppState) {
-    let mut interval = interval(Duration::from_millis(100));
+    let mut interval = interval(state.config().ticks.mouse_interval());
+    let shutdown = state.shutdown_token();
 
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => break,
+        }
+
+        let _timer = metrics().mouse_tick_seconds.start_timer();
+        for room in state.loaded_rooms().await {
+            let positions = room.get_dirty_mouse_positions().await;
+            if positions.is_empty() {
+                continue;
+            }
 
-        let positions = state.get_dirty_mouse_positions().await;
-        if positions.is_empty() {
-            continue;
+            let positions_count = positions.keys().len();
+            let response = create_mouse_positions_message(positions);
+            let bytes = encode_server_message(&response);
+
+            room.broadcast(Message::Binary(bytes.into()), Delivery::Droppable)
+                .await;
+            tracing::trace!(
+                "Broadcasted {} mouse positions to room {}",
+                positions_count,
+                room.id()
+            );
         }
+    }
+    tracing::debug!("Mouse broadcast task stopped");
+}
 
-        let positions_count = positions.keys().len();
-        let response = create_mouse_positions_message(positions);
-        let bytes = encode_server_message(&response);
+/// Global task that unloads rooms once they have been idle for the configured timeout
+pub async fn room_unload_task(state: AppState) {
+    let idle_timeout = state.config().rooms.idle_timeout();
+    let mut interval = interval((idle_timeout / 4).max(Duration::from_secs(1)));
+    let shutdown = state.shutdown_token();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => break,
+        }
 
-        state.broadcast(Message::Binary(bytes.into())).await;
-        tracing::trace!(
-            "Broadcasted {} mouse positions to all connections",
-            positions_count
-        );
+        state.unload_idle_rooms(idle_timeout).await;
     }
+    tracing::debug!("Room unload task stopped");
 }
@@ -1,112 +1,529 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, State,
     },
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
-use futures::{SinkExt, StreamExt};
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use the_song_protocol::{CAPABILITY_DELTA_RESUME, CAPABILITY_SERVER_ERRORS};
+use tokio::sync::mpsc::Sender;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
-    dto::{client_message, create_welcome_message, decode_client_message, encode_server_message},
-    state::AppState,
+    dto::{
+        client_message, client_payload_type, create_ack_message, create_error_message,
+        create_welcome_message, decode_client_message, encode_server_message, encoded_payload_type,
+        ClientHello, ClientMessage, ErrorCode, ServerMessage,
+    },
+    handshake,
+    metrics::metrics,
+    rate_limit::{Traffic, Verdict},
+    state::{AppState, ClientSender, Room, RoomError, UpdateError, DEFAULT_ROOM_ID},
 };
 
-pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+/// Close code for a client disconnected because its send queue overflowed
+const CLOSE_SLOW_CONSUMER: u16 = 4000;
+
+/// Close code for a client refused by the handshake, it should not reconnect as is
+const CLOSE_INCOMPATIBLE_CLIENT: u16 = 4001;
+
+/// Close code for a client refused because its room is at capacity
+const CLOSE_ROOM_FULL: u16 = 4002;
+
+/// Close code for a client that kept going over the rate limits
+const CLOSE_RATE_LIMITED: u16 = 4003;
+
+/// Close code for a connection whose user resumed on a newer one
+const CLOSE_SUPERSEDED: u16 = 4004;
+
+/// Frames this many times over `max_frame_bytes` are cut off by the socket
+/// itself, smaller ones are refused with a warning first
+const HARD_FRAME_LIMIT_FACTOR: usize = 4;
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let ip = state.client_ip(addr, &headers);
+    join_room(ws, state, DEFAULT_ROOM_ID, ip).await
+}
+
+pub async fn room_ws_handler(
+    ws: WebSocketUpgrade,
+    Path(room_id): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    if !Room::is_valid_id(&room_id) {
+        return (StatusCode::BAD_REQUEST, "Invalid room ID").into_response();
+    }
+    let ip = state.client_ip(addr, &headers);
+    join_room(ws, state, &room_id, ip).await
+}
+
+async fn join_room(ws: WebSocketUpgrade, state: AppState, room_id: &str, ip: IpAddr) -> Response {
+    if state.is_shutting_down() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down").into_response();
+    }
+    let room = match state.room_for(room_id, ip).await {
+        Ok(room) => room,
+        Err(RoomError::TooManyRooms) => {
+            return (StatusCode::SERVICE_UNAVAILABLE, "Room limit reached").into_response();
+        }
+        Err(RoomError::RateLimited) => {
+            return (StatusCode::TOO_MANY_REQUESTS, "Too many new rooms").into_response();
+        }
+        Err(e) => {
+            tracing::error!("Failed to load room {}: {}", room_id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load room").into_response();
+        }
+    };
+    let max_frame_bytes = state.config().rate_limits.max_frame_bytes;
+    ws.max_message_size(max_frame_bytes.saturating_mul(HARD_FRAME_LIMIT_FACTOR))
+        .on_upgrade(move |socket| handle_socket(socket, state, room, ip))
+}
+
+/// The `ClientHello` in a frame, `None` for any other frame
+fn read_hello(msg: &Result<Message, axum::Error>) -> Option<ClientHello> {
+    let Ok(Message::Binary(data)) = msg else {
+        return None;
+    };
+    match decode_client_message(data).ok()?.payload? {
+        client_message::Payload::Hello(hello) => Some(hello),
+        _ => None,
+    }
+}
+
+/// Tell a client why it cannot join and close the connection
+async fn refuse(
+    sender: &mut SplitSink<WebSocket, Message>,
+    code: ErrorCode,
+    message: String,
+    close_code: u16,
+    reason: &'static str,
+) {
+    let error = create_error_message(code, message, 0);
+    let frames = [
+        Message::Binary(encode_server_message(&error).into()),
+        Message::Close(Some(CloseFrame {
+            code: close_code,
+            reason: reason.into(),
+        })),
+    ];
+    for frame in frames {
+        record_sent(&frame);
+        if sender.send(frame).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Answers addressed only to one client
+struct Replies {
+    queue: Sender<Message>,
+    // Clients that do not handle errors are not sent any
+    errors: bool,
+}
+
+impl Replies {
+    fn send(&self, message: ServerMessage) {
+        let bytes = encode_server_message(&message);
+        // Best effort, a full queue means the client is lagging anyway
+        let _ = self.queue.try_send(Message::Binary(bytes.into()));
+    }
+
+    fn ack(&self, seq: u32) {
+        if seq != 0 {
+            self.send(create_ack_message(seq));
+        }
+    }
+
+    fn error(&self, code: ErrorCode, message: String, seq: u32) {
+        if self.errors {
+            self.send(create_error_message(code, message, seq));
+        }
+    }
+}
+
+/// Act on one message from a client, answering it if it carries a seq
+async fn handle_client_message(room: &Room, user_id: Uuid, msg: ClientMessage, replies: &Replies) {
+    let seq = msg.seq;
+    match msg.payload {
+        Some(client_message::Payload::MouseUpdate(mouse_update)) => {
+            room.update_mouse(
+                user_id,
+                mouse_update.x,
+                mouse_update.y,
+                mouse_update.vx,
+                mouse_update.vy,
+            )
+            .await;
+            replies.ack(seq);
+        }
+        Some(client_message::Payload::SynthesizerUpdate(synth_update)) => {
+            match room
+                .apply_synthesizer_update(user_id, synth_update.data)
+                .await
+            {
+                Ok(()) => replies.ack(seq),
+                Err(e) => {
+                    let code = match e {
+                        UpdateError::Export(_) => ErrorCode::InternalError,
+                        UpdateError::Import(_)
+                        | UpdateError::Invalid(_)
+                        | UpdateError::Forged(_) => ErrorCode::InvalidUpdate,
+                        UpdateError::Quota(_) => ErrorCode::QuotaExceeded,
+                    };
+                    replies.error(code, e.to_string(), seq);
+                }
+            }
+        }
+        Some(client_message::Payload::Hello(_)) => {
+            tracing::debug!("Ignoring repeated hello from {}", user_id);
+        }
+        None => {
+            tracing::warn!("Received client message with no payload");
+            replies.error(
+                ErrorCode::DecodeError,
+                "Message has no payload".to_string(),
+                seq,
+            );
+        }
+    }
+}
+
+/// Answer a message the rate limits turned down, `true` if the client has to go
+fn reject(verdict: Verdict, user_id: Uuid, replies: &Replies, seq: u32) -> bool {
+    match verdict {
+        Verdict::Allow => false,
+        Verdict::Throttle(violation) => {
+            metrics()
+                .rate_limited
+                .with_label_values(&[violation.label()])
+                .inc();
+            replies.error(ErrorCode::RateLimited, violation.to_string(), seq);
+            false
+        }
+        Verdict::Disconnect(violation) => {
+            tracing::warn!("Disconnecting {}, still going: {}", user_id, violation);
+            metrics().rate_limit_disconnects.inc();
+            true
+        }
+    }
+}
+
+/// Count a frame about to be written to a socket
+fn record_sent(msg: &Message) {
+    let (payload, len) = match msg {
+        Message::Binary(data) => (encoded_payload_type(data), data.len()),
+        Message::Close(_) => ("close", 0),
+        _ => ("other", 0),
+    };
+    let metrics = metrics();
+    metrics.messages_out.with_label_values(&[payload]).inc();
+    metrics.bytes_sent.inc_by(len as u64);
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState) {
+async fn handle_socket(socket: WebSocket, state: AppState, room: Arc<Room>, ip: IpAddr) {
     // Generate a unique user ID for this connection
     let user_id = Uuid::now_v7();
-    tracing::info!("New WebSocket connection from user {}", user_id);
+    tracing::info!(
+        "New WebSocket connection from user {} in room {}",
+        user_id,
+        room.id()
+    );
+
+    let connection_config = state.config().connections.clone();
 
     // Increment online users count
-    state.increment_users();
+    room.increment_users();
 
     // Split the socket into sender and receiver
     let (mut sender, mut receiver) = socket.split();
 
-    let synthesizer_snapshot = if let Ok(snapshot) = state.get_synthesizer_snapshot().await {
+    // Clients open with a hello saying what they speak and, when reconnecting,
+    // what they already have so they only get what they missed. Clients that
+    // start without one are refused once they are known not to send it.
+    let hello = match timeout(connection_config.hello_timeout(), receiver.next()).await {
+        Ok(None) => {
+            room.decrement_users();
+            return;
+        }
+        Ok(Some(msg)) => {
+            let hello = read_hello(&msg);
+            if hello.is_some() {
+                metrics().messages_in.with_label_values(&["hello"]).inc();
+            }
+            hello
+        }
+        Err(_) => None,
+    };
+
+    let session = match handshake::negotiate(
+        hello,
+        room.song_dimensions(),
+        connection_config.min_protocol_version,
+    ) {
+        Ok(session) => session,
+        Err(incompatible) => {
+            tracing::info!("Refusing client {}: {}", user_id, incompatible);
+            refuse(
+                &mut sender,
+                ErrorCode::IncompatibleClient,
+                incompatible.to_string(),
+                CLOSE_INCOMPATIBLE_CLIENT,
+                "Incompatible client, update required",
+            )
+            .await;
+            room.decrement_users();
+            return;
+        }
+    };
+
+    // The online user count already includes this connection
+    let max_connections = state.config().rooms.max_connections;
+    if max_connections > 0 && room.get_server_stats().online_users as usize > max_connections {
+        tracing::info!("Refusing client {}, room {} is full", user_id, room.id());
+        refuse(
+            &mut sender,
+            ErrorCode::RoomFull,
+            format!("Room {} is full", room.id()),
+            CLOSE_ROOM_FULL,
+            "Room is full",
+        )
+        .await;
+        room.decrement_users();
+        return;
+    }
+
+    // A client reconnecting with its token stays the same user and keeps its Loro
+    // peers and quotas, even if its old connection has not gone away yet
+    let user_id = match room.resume_user(&session.resume_token).await {
+        Some(resumed) => {
+            tracing::info!("Connection {} resumes user {}", user_id, resumed);
+            resumed
+        }
+        None => user_id,
+    };
+
+    let synthesizer_updates = if session.supports(CAPABILITY_DELTA_RESUME) {
+        match room
+            .get_synthesizer_updates_since(&session.version_vector)
+            .await
+        {
+            Ok(updates) => updates,
+            Err(e) => {
+                tracing::warn!("Failed to export updates for {}: {}", user_id, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let synthesizer_snapshot = if let Some(updates) = &synthesizer_updates {
+        tracing::debug!(
+            "Resuming {} with {} bytes of updates",
+            user_id,
+            updates.len()
+        );
+        Vec::new()
+    } else if let Ok(snapshot) = room.get_synthesizer_snapshot().await {
         snapshot
     } else {
         tracing::error!("Failed to get synthesizer snapshot");
+        room.decrement_users();
         return;
     };
 
     // Send welcome message with user ID (binary format)
-    let welcome_msg =
-        create_welcome_message(user_id, state.get_server_stats(), synthesizer_snapshot);
-    let welcome_bytes = encode_server_message(&welcome_msg);
-
-    if sender
-        .send(Message::Binary(welcome_bytes.into()))
-        .await
-        .is_err()
-    {
+    let welcome_msg = create_welcome_message(
+        user_id,
+        room.get_server_stats(),
+        synthesizer_snapshot,
+        synthesizer_updates.unwrap_or_default(),
+        handshake::song_shape(room.song_dimensions()),
+        session.capabilities.clone(),
+        room.issue_resume_token(user_id),
+    );
+    let welcome = Message::Binary(encode_server_message(&welcome_msg).into());
+    record_sent(&welcome);
+
+    if sender.send(welcome).await.is_err() {
         tracing::error!("Failed to send welcome message to {}", user_id);
-        state.decrement_users();
+        room.decrement_users();
         return;
     }
 
-    // Create a channel for this connection
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    // Create a bounded channel for this connection
+    let (tx, mut rx) = tokio::sync::mpsc::channel(connection_config.send_queue_capacity);
+    let lagged = CancellationToken::new();
+    let superseded = CancellationToken::new();
+    let abort = state.abort_token();
+
+    // Keep a handle for replies addressed only to this client
+    let replies = Replies {
+        queue: tx.clone(),
+        errors: session.supports(CAPABILITY_SERVER_ERRORS),
+    };
 
-    // Register this connection in the global registry
-    state.register_connection(user_id, tx).await;
+    // Register this connection in the room's registry
+    room.register_connection(
+        user_id,
+        ClientSender::new(tx, lagged.clone(), superseded.clone()),
+    )
+    .await;
     tracing::info!(
-        "User {} connected, total connections: {}",
+        "User {} connected to room {}, room connections: {}",
         user_id,
-        state.connection_count().await
+        room.id(),
+        room.connection_count().await
     );
 
     // Spawn a task to forward messages from the channel to the WebSocket
-    let sender_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+    let sender_lagged = lagged.clone();
+    let sender_superseded = superseded.clone();
+    let close_timeout = connection_config.slow_consumer_close_timeout();
+    let mut sender_task = tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                biased;
+                _ = sender_lagged.cancelled() => {
+                    let close = Message::Close(Some(CloseFrame {
+                        code: CLOSE_SLOW_CONSUMER,
+                        reason: "Too far behind, reconnect to resync".into(),
+                    }));
+                    record_sent(&close);
+                    // The client is not reading, so do not wait on it for long
+                    let _ = timeout(close_timeout, sender.send(close)).await;
+                    break;
+                }
+                _ = sender_superseded.cancelled() => {
+                    let close = Message::Close(Some(CloseFrame {
+                        code: CLOSE_SUPERSEDED,
+                        reason: "Resumed on another connection".into(),
+                    }));
+                    record_sent(&close);
+                    // The old socket is likely dead, so do not wait on it for long
+                    let _ = timeout(close_timeout, sender.send(close)).await;
+                    break;
+                }
+                msg = rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
+            record_sent(&msg);
+            let closing = matches!(msg, Message::Close(_));
             if sender.send(msg).await.is_err() {
                 tracing::debug!("Failed to send message to WebSocket, connection likely closed");
                 break;
             }
+            if closing {
+                break;
+            }
         }
     });
 
+    // The going-away broadcast may have missed a connection registered during shutdown
+    if state.is_shutting_down() {
+        sender_task.abort();
+        if room.unregister_connection(&user_id, &replies.queue).await {
+            room.remove_mouse(&user_id).await;
+        }
+        room.decrement_users();
+        return;
+    }
+
     // Handle incoming messages from the client
-    while let Some(msg) = receiver.next().await {
+    let mut limiter = state.rate_limiter().connection(ip);
+    let mut rate_limited = false;
+    while let Some(msg) = tokio::select! {
+        msg = receiver.next() => msg,
+        _ = lagged.cancelled() => None,
+        _ = superseded.cancelled() => None,
+        _ = abort.cancelled() => None,
+    } {
+        let len = match &msg {
+            Ok(Message::Binary(data)) => data.len(),
+            Ok(Message::Text(text)) => text.len(),
+            _ => 0,
+        };
+        let verdict = limiter.check_size(len);
+        if verdict != Verdict::Allow {
+            if reject(verdict, user_id, &replies, 0) {
+                rate_limited = true;
+                break;
+            }
+            continue;
+        }
+
         match msg {
             Ok(Message::Binary(data)) => {
-                // Parse binary protobuf message
-                match decode_client_message(&data) {
-                    Ok(client_msg) => match client_msg.payload {
-                        Some(client_message::Payload::MouseUpdate(mouse_update)) => {
-                            state
-                                .update_mouse(
-                                    user_id,
-                                    mouse_update.x,
-                                    mouse_update.y,
-                                    mouse_update.vx,
-                                    mouse_update.vy,
-                                )
-                                .await;
-                        }
-                        Some(client_message::Payload::SynthesizerUpdate(synth_update)) => {
-                            state.apply_synthesizer_update(synth_update.data).await;
-                        }
-                        None => {
-                            tracing::warn!("Received client message with no payload");
-                        }
-                    },
+                let decoded = decode_client_message(&data);
+                // Anything but mouse updates, even garbage, costs a synthesizer token
+                let (traffic, seq) = match &decoded {
+                    Ok(ClientMessage {
+                        payload: Some(client_message::Payload::MouseUpdate(_)),
+                        seq,
+                    }) => (Traffic::Mouse, *seq),
+                    Ok(client_msg) => (Traffic::Synthesizer, client_msg.seq),
+                    Err(_) => (Traffic::Synthesizer, 0),
+                };
+                let verdict = limiter.check_rate(traffic);
+                if verdict != Verdict::Allow {
+                    if reject(verdict, user_id, &replies, seq) {
+                        rate_limited = true;
+                        break;
+                    }
+                    continue;
+                }
+                match decoded {
+                    Ok(client_msg) => {
+                        metrics()
+                            .messages_in
+                            .with_label_values(&[client_payload_type(&client_msg)])
+                            .inc();
+                        handle_client_message(&room, user_id, client_msg, &replies).await;
+                    }
                     Err(e) => {
-                        tracing::error!("Failed to decode client message: {}", e);
+                        metrics().messages_in.with_label_values(&["invalid"]).inc();
+                        tracing::warn!("Failed to decode client message from {}: {}", user_id, e);
+                        replies.error(
+                            ErrorCode::DecodeError,
+                            format!("Failed to decode message: {}", e),
+                            0,
+                        );
                     }
                 }
             }
             Ok(Message::Text(_text)) => {
+                let verdict = limiter.check_rate(Traffic::Synthesizer);
+                if verdict != Verdict::Allow {
+                    if reject(verdict, user_id, &replies, 0) {
+                        rate_limited = true;
+                        break;
+                    }
+                    continue;
+                }
                 // Legacy text messages - log warning
                 tracing::warn!(
                     "Received text message from {}, expected binary. Ignoring.",
                     user_id
                 );
+                replies.error(
+                    ErrorCode::DecodeError,
+                    "Expected a binary frame".to_string(),
+                    0,
+                );
             }
             Ok(Message::Ping(_)) => {
                 // Axum handles pings automatically
@@ -124,13 +541,38 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
     }
 
     // Cleanup when the connection closes
-    sender_task.abort();
-    state.unregister_connection(&user_id).await;
-    state.remove_mouse(&user_id).await;
-    state.decrement_users();
+    if lagged.is_cancelled() {
+        tracing::info!("User {} fell too far behind", user_id);
+        // Let the sender task deliver the close frame, it gives up on its own
+        let _ = sender_task.await;
+    } else if superseded.is_cancelled() {
+        tracing::info!("User {} resumed on another connection", user_id);
+        let _ = sender_task.await;
+    } else if rate_limited {
+        let close = Message::Close(Some(CloseFrame {
+            code: CLOSE_RATE_LIMITED,
+            reason: "Too many messages".into(),
+        }));
+        // The sender task stops after the close frame, unless the client is not reading
+        let _ = timeout(close_timeout, async {
+            if replies.queue.send(close).await.is_ok() {
+                let _ = (&mut sender_task).await;
+            }
+        })
+        .await;
+        sender_task.abort();
+    } else {
+        sender_task.abort();
+    }
+    // A user that resumed elsewhere keeps its mouse on the new connection
+    if room.unregister_connection(&user_id, &replies.queue).await {
+        room.remove_mouse(&user_id).await;
+    }
+    room.decrement_users();
     tracing::info!(
-        "User {} disconnected, remaining connections: {}",
+        "User {} disconnected from room {}, remaining connections: {}",
         user_id,
-        state.connection_count().await
+        room.id(),
+        room.connection_count().await
     );
 }
@@ -0,0 +1,83 @@
+//! Instruments the editor plays the default tracks with.
+
+use crate::DEFAULT_TRACKS;
+
+/// Instrument a track is played with
+pub struct Instrument {
+    pub name: &'static str,
+    /// General MIDI program (0-based), `None` for drum kits on the percussion channel
+    pub program: Option<u8>,
+}
+
+/// Instruments of the default tracks, mirrors `ui/src/assets/channel.json`
+pub const INSTRUMENTS: [Instrument; DEFAULT_TRACKS] = [
+    Instrument {
+        name: "Acoustic Piano",
+        program: Some(0),
+    },
+    Instrument {
+        name: "Acoustic Guitar",
+        program: Some(24),
+    },
+    Instrument {
+        name: "Trumpet",
+        program: Some(56),
+    },
+    Instrument {
+        name: "Acoustic Drum Kit",
+        program: None,
+    },
+    Instrument {
+        name: "Electric Drum Kit",
+        program: None,
+    },
+    Instrument {
+        name: "FX Percussion",
+        program: None,
+    },
+    Instrument {
+        name: "Electric Bass",
+        program: Some(33),
+    },
+    Instrument {
+        name: "Synth Bass",
+        program: Some(38),
+    },
+    Instrument {
+        name: "Electric Piano",
+        program: Some(4),
+    },
+    Instrument {
+        name: "Polysynth",
+        program: Some(90),
+    },
+    Instrument {
+        name: "Square",
+        program: Some(80),
+    },
+    Instrument {
+        name: "String Ensemble",
+        program: Some(48),
+    },
+    Instrument {
+        name: "Choir",
+        program: Some(91),
+    },
+    Instrument {
+        name: "Ocarina",
+        program: Some(79),
+    },
+    Instrument {
+        name: "Choir Aahs",
+        program: Some(52),
+    },
+    Instrument {
+        name: "Flute",
+        program: Some(73),
+    },
+];
+
+/// Instrument of `track`, songs with more tracks than instruments repeat them
+pub fn instrument(track: usize) -> &'static Instrument {
+    &INSTRUMENTS[track % INSTRUMENTS.len()]
+}
@@ -0,0 +1,58 @@
+//! Typed view of the song held in THE SONG's Loro document.
+//!
+//! The editor and the server edit one shared document. Its layout is set by
+//! the `Crdt` class in `ui/src/lib/crdt.ts`:
+//!
+//! - `bpm`: counter with the tempo
+//! - `notes`: map of note ID to a map of the note's fields
+//! - `tracks`: list of tracks, each a list with the note IDs of every pitch
+//! - `trackConfigs`: list with a map of settings per track
+//!
+//! The `schema` module names those containers and fields. Everything else
+//! reads and writes the document through typed accessors instead of looking
+//! containers up by name, and writes keep the `notes` map and the pitch lists
+//! in step the way the editor does.
+//!
+//! # Example
+//!
+//! ```rust
+//! use the_song_model::{init_song, insert_note, Note, Song, SongDimensions, TrackConfig};
+//!
+//! let doc = loro::LoroDoc::new();
+//! init_song(&doc, 120.0, SongDimensions::default(), |_| TrackConfig::default()).unwrap();
+//! insert_note(
+//!     &doc,
+//!     &Note {
+//!         id: "user:0:a".to_string(),
+//!         pitch: 24,
+//!         start: 0.0,
+//!         duration: 1.0,
+//!         velocity: 100,
+//!         track: 0,
+//!         created_by: "user".to_string(),
+//!         created_at: 0.0,
+//!     },
+//! )
+//! .unwrap();
+//!
+//! let song = Song::from_doc(&doc);
+//! assert_eq!(song.notes[0].midi_key(), 60);
+//! ```
+
+pub mod schema;
+
+mod instrument;
+mod note;
+mod song;
+mod track;
+
+pub use instrument::{instrument, Instrument, INSTRUMENTS};
+pub use note::{
+    clear_notes, delete_note, insert_note, note_author, note_value, parse_note, update_note, Note,
+    NoteUpdate,
+};
+pub use song::{
+    init_song, set_bpm, Song, SongDimensions, BASE_MIDI_NOTE, DEFAULT_BPM, DEFAULT_PITCHES,
+    DEFAULT_TRACKS, MAX_PITCHES,
+};
+pub use track::{pitch_list, push_track, set_track_config, track_configs, Track, TrackConfig};
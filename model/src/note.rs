@@ -0,0 +1,289 @@
+//! Notes in the `notes` map and their IDs on the pitch lists of the tracks.
+
+use loro::{LoroDoc, LoroMap, LoroResult, LoroValue, ValueOrContainer};
+
+use crate::schema::{self, note as field};
+use crate::track::pitch_list;
+use crate::{SongDimensions, BASE_MIDI_NOTE};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub id: String,
+    /// Pitch index, 0 is `BASE_MIDI_NOTE`
+    pub pitch: usize,
+    /// Start in beats
+    pub start: f64,
+    /// Length in beats
+    pub duration: f64,
+    pub velocity: u8,
+    pub track: usize,
+    pub created_by: String,
+    pub created_at: f64,
+}
+
+impl Note {
+    pub fn midi_key(&self) -> u8 {
+        BASE_MIDI_NOTE + self.pitch as u8
+    }
+
+    pub fn end(&self) -> f64 {
+        self.start + self.duration
+    }
+}
+
+/// Changes to a note, `None` leaves a field as it is. The track and author of
+/// a note never change.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NoteUpdate {
+    pub pitch: Option<usize>,
+    pub start: Option<f64>,
+    pub duration: Option<f64>,
+    pub velocity: Option<u8>,
+}
+
+fn number(value: Option<&LoroValue>) -> Option<f64> {
+    match value? {
+        LoroValue::Double(n) if n.is_finite() => Some(*n),
+        LoroValue::I64(n) => Some(*n as f64),
+        _ => None,
+    }
+}
+
+fn index_below(value: Option<&LoroValue>, limit: usize) -> Option<usize> {
+    let n = number(value)?;
+    (n.fract() == 0.0 && n >= 0.0 && n < limit as f64).then_some(n as usize)
+}
+
+/// Read one value of the `notes` map, `None` if it breaks the schema
+pub fn parse_note(id: &str, value: &LoroValue, dimensions: SongDimensions) -> Option<Note> {
+    let LoroValue::Map(note) = value else {
+        return None;
+    };
+
+    let start = number(note.get(field::START_TIME)).filter(|start| *start >= 0.0)?;
+    let duration = number(note.get(field::DURATION)).filter(|duration| *duration > 0.0)?;
+    let created_by = match note.get(field::CREATED_BY) {
+        Some(LoroValue::String(user)) => user.to_string(),
+        _ => String::new(),
+    };
+
+    Some(Note {
+        id: id.to_string(),
+        pitch: index_below(note.get(field::PITCH), dimensions.pitches)?,
+        start,
+        duration,
+        velocity: number(note.get(field::VELOCITY))?.clamp(0.0, 127.0) as u8,
+        track: index_below(note.get(field::TRACK_INDEX), dimensions.tracks)?,
+        created_by,
+        created_at: number(note.get(field::CREATED_AT)).unwrap_or(0.0),
+    })
+}
+
+/// Deep value of the note `id` in the `notes` map
+pub fn note_value(doc: &LoroDoc, id: &str) -> Option<LoroValue> {
+    schema::notes_map(doc)
+        .get(id)
+        .map(|note| note.get_deep_value())
+}
+
+/// `createdBy` of a note map, empty when missing
+pub fn note_author(note: &ValueOrContainer) -> String {
+    let ValueOrContainer::Container(loro::Container::Map(note)) = note else {
+        return String::new();
+    };
+    match note.get(field::CREATED_BY) {
+        Some(ValueOrContainer::Value(LoroValue::String(author))) => author.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Write a note the way the editor's `addNote` does: into the `notes` map
+/// and onto the pitch list of its track
+pub fn insert_note(doc: &LoroDoc, note: &Note) -> LoroResult<()> {
+    let note_map = schema::notes_map(doc).insert_container(&note.id, LoroMap::new())?;
+    note_map.insert(field::ID, note.id.as_str())?;
+    note_map.insert(field::PITCH, note.pitch as f64)?;
+    note_map.insert(field::START_TIME, note.start)?;
+    note_map.insert(field::DURATION, note.duration)?;
+    note_map.insert(field::VELOCITY, note.velocity as f64)?;
+    note_map.insert(field::CREATED_AT, note.created_at)?;
+    note_map.insert(field::CREATED_BY, note.created_by.as_str())?;
+    note_map.insert(field::TRACK_INDEX, note.track as f64)?;
+
+    if let Some(pitch_list) = pitch_list(doc, note.track, note.pitch) {
+        pitch_list.push(note.id.as_str())?;
+    }
+    Ok(())
+}
+
+/// Take the note `id` off the pitch list of its track
+fn unlist_note(doc: &LoroDoc, id: &str, track: usize, pitch: usize) -> LoroResult<()> {
+    let Some(pitch_list) = pitch_list(doc, track, pitch) else {
+        return Ok(());
+    };
+    let LoroValue::List(ids) = pitch_list.get_value() else {
+        return Ok(());
+    };
+    if let Some(pos) = ids
+        .iter()
+        .position(|listed| matches!(listed, LoroValue::String(listed) if listed.as_str() == id))
+    {
+        pitch_list.delete(pos, 1)?;
+    }
+    Ok(())
+}
+
+/// Track and pitch index a note map is listed under
+fn note_place(note_map: &LoroMap) -> Option<(usize, usize)> {
+    let LoroValue::Map(note) = note_map.get_deep_value() else {
+        return None;
+    };
+    Some((
+        index_below(note.get(field::TRACK_INDEX), usize::MAX)?,
+        index_below(note.get(field::PITCH), usize::MAX)?,
+    ))
+}
+
+fn note_map(doc: &LoroDoc, id: &str) -> Option<LoroMap> {
+    match schema::notes_map(doc).get(id) {
+        Some(ValueOrContainer::Container(loro::Container::Map(note_map))) => Some(note_map),
+        _ => None,
+    }
+}
+
+/// Change a note the way the editor's `updateNote` does, moving its ID to
+/// another pitch list when the pitch changes. Returns whether the note exists.
+pub fn update_note(doc: &LoroDoc, id: &str, update: &NoteUpdate) -> LoroResult<bool> {
+    let Some(note_map) = note_map(doc, id) else {
+        return Ok(false);
+    };
+
+    if let Some(pitch) = update.pitch {
+        let place = note_place(&note_map);
+        if place.map(|(_, old)| old) != Some(pitch) {
+            if let Some((track, old)) = place {
+                unlist_note(doc, id, track, old)?;
+                if let Some(pitch_list) = pitch_list(doc, track, pitch) {
+                    pitch_list.push(id)?;
+                }
+            }
+            note_map.insert(field::PITCH, pitch as f64)?;
+        }
+    }
+    if let Some(start) = update.start {
+        note_map.insert(field::START_TIME, start)?;
+    }
+    if let Some(duration) = update.duration {
+        note_map.insert(field::DURATION, duration)?;
+    }
+    if let Some(velocity) = update.velocity {
+        note_map.insert(field::VELOCITY, velocity as f64)?;
+    }
+    Ok(true)
+}
+
+/// Remove a note the way the editor's `deleteNote` does, from its pitch list
+/// and the `notes` map
+pub fn delete_note(doc: &LoroDoc, id: &str) -> LoroResult<()> {
+    if let Some((track, pitch)) = note_map(doc, id).as_ref().and_then(note_place) {
+        unlist_note(doc, id, track, pitch)?;
+    }
+    schema::notes_map(doc).delete(id)
+}
+
+/// Remove every note from the `notes` map and the pitch lists
+pub fn clear_notes(doc: &LoroDoc) -> LoroResult<()> {
+    let notes = schema::notes_map(doc);
+    for id in notes.keys().collect::<Vec<_>>() {
+        notes.delete(&id)?;
+    }
+    let dimensions = SongDimensions::of(doc).unwrap_or_default();
+    for track in 0..dimensions.tracks {
+        for pitch in 0..dimensions.pitches {
+            if let Some(pitch_list) = pitch_list(doc, track, pitch) {
+                pitch_list.delete(0, pitch_list.len())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::{Track, TrackConfig};
+    use crate::{init_song, Song};
+
+    fn song_doc() -> LoroDoc {
+        let doc = LoroDoc::new();
+        init_song(&doc, 120.0, SongDimensions::default(), |_| {
+            TrackConfig::default()
+        })
+        .unwrap();
+        doc
+    }
+
+    fn note(id: &str, pitch: usize) -> Note {
+        Note {
+            id: id.to_string(),
+            pitch,
+            start: 1.0,
+            duration: 0.5,
+            velocity: 90,
+            track: 2,
+            created_by: "user".to_string(),
+            created_at: 5.0,
+        }
+    }
+
+    #[test]
+    fn test_insert_note() {
+        let doc = song_doc();
+        insert_note(&doc, &note("a", 7)).unwrap();
+        assert_eq!(Song::from_doc(&doc).notes, vec![note("a", 7)]);
+        assert_eq!(Track::get(&doc, 2).unwrap().note_ids(7), vec!["a"]);
+        let value = note_value(&doc, "a").unwrap();
+        assert_eq!(
+            parse_note("a", &value, SongDimensions::default()),
+            Some(note("a", 7))
+        );
+    }
+
+    #[test]
+    fn test_update_note_moves_pitch_list() {
+        let doc = song_doc();
+        insert_note(&doc, &note("a", 7)).unwrap();
+        let update = NoteUpdate {
+            pitch: Some(9),
+            velocity: Some(30),
+            ..NoteUpdate::default()
+        };
+        assert!(update_note(&doc, "a", &update).unwrap());
+        assert!(!update_note(&doc, "b", &update).unwrap());
+
+        let track = Track::get(&doc, 2).unwrap();
+        assert!(track.note_ids(7).is_empty());
+        assert_eq!(track.note_ids(9), vec!["a"]);
+        let song = Song::from_doc(&doc);
+        assert_eq!((song.notes[0].pitch, song.notes[0].velocity), (9, 30));
+        assert_eq!(song.notes[0].start, 1.0);
+    }
+
+    #[test]
+    fn test_delete_and_clear_notes() {
+        let doc = song_doc();
+        insert_note(&doc, &note("a", 7)).unwrap();
+        insert_note(&doc, &note("b", 7)).unwrap();
+        insert_note(&doc, &note("c", 8)).unwrap();
+
+        delete_note(&doc, "a").unwrap();
+        let track = Track::get(&doc, 2).unwrap();
+        assert_eq!(track.note_ids(7), vec!["b"]);
+        assert_eq!(Song::from_doc(&doc).notes.len(), 2);
+
+        clear_notes(&doc).unwrap();
+        assert!(Song::from_doc(&doc).notes.is_empty());
+        assert!(track.note_ids(7).is_empty());
+        assert!(track.note_ids(8).is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+//! Names of the containers and fields of the song document.
+//!
+//! Keep in sync with the `Crdt` class in `ui/src/lib/crdt.ts`.
+
+use loro::{LoroCounter, LoroDoc, LoroList, LoroMap};
+
+/// Counter holding the tempo in beats per minute
+pub const BPM: &str = "bpm";
+/// Map of note ID to note map
+pub const NOTES: &str = "notes";
+/// List of tracks, each a list of note ID lists, one per pitch
+pub const TRACKS: &str = "tracks";
+/// List of track config maps, one per track
+pub const TRACK_CONFIGS: &str = "trackConfigs";
+
+/// Every root container of a song, anything else does not belong in the document
+pub const ROOT_CONTAINERS: [&str; 4] = [BPM, NOTES, TRACKS, TRACK_CONFIGS];
+
+/// Fields of a note map, `NoteData` in the UI
+pub mod note {
+    pub const ID: &str = "id";
+    /// Pitch index within the track
+    pub const PITCH: &str = "pitch";
+    /// Start in beats
+    pub const START_TIME: &str = "startTime";
+    /// Length in beats
+    pub const DURATION: &str = "duration";
+    pub const VELOCITY: &str = "velocity";
+    /// Milliseconds since the Unix epoch
+    pub const CREATED_AT: &str = "createdAt";
+    /// User ID of the author
+    pub const CREATED_BY: &str = "createdBy";
+    pub const TRACK_INDEX: &str = "trackIndex";
+}
+
+/// Fields of a track config map
+pub mod track_config {
+    pub const ACCENT_COLOR: &str = "accentColor";
+}
+
+pub fn bpm_counter(doc: &LoroDoc) -> LoroCounter {
+    doc.get_counter(BPM)
+}
+
+pub fn notes_map(doc: &LoroDoc) -> LoroMap {
+    doc.get_map(NOTES)
+}
+
+pub fn tracks_list(doc: &LoroDoc) -> LoroList {
+    doc.get_list(TRACKS)
+}
+
+pub fn track_configs_list(doc: &LoroDoc) -> LoroList {
+    doc.get_list(TRACK_CONFIGS)
+}
@@ -0,0 +1,182 @@
+//! The whole song: its tempo, shape and notes.
+
+use loro::{LoroDoc, LoroResult, LoroValue};
+
+use crate::note::{parse_note, Note};
+use crate::schema;
+use crate::track::{push_track, Track, TrackConfig};
+
+/// MIDI key of pitch index 0, matches `BASE_MIDI_NOTE` in the UI
+pub const BASE_MIDI_NOTE: u8 = 36;
+
+/// Tracks in a song unless configured otherwise
+pub const DEFAULT_TRACKS: usize = 16;
+
+/// Pitches per track unless configured otherwise (5 octaves * 12 notes per octave)
+pub const DEFAULT_PITCHES: usize = 60;
+
+/// Most pitches a track can have before the top one is no longer a MIDI key
+pub const MAX_PITCHES: usize = 128 - BASE_MIDI_NOTE as usize;
+
+/// BPM of a document without a readable `bpm` counter
+pub const DEFAULT_BPM: f64 = 120.0;
+
+/// Number of tracks and pitches per track of a song
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SongDimensions {
+    pub tracks: usize,
+    pub pitches: usize,
+}
+
+impl Default for SongDimensions {
+    fn default() -> Self {
+        Self {
+            tracks: DEFAULT_TRACKS,
+            pitches: DEFAULT_PITCHES,
+        }
+    }
+}
+
+impl SongDimensions {
+    /// Dimensions of the `tracks` list of a document, `None` if it has no tracks
+    pub fn of(doc: &LoroDoc) -> Option<Self> {
+        let first = Track::get(doc, 0)?;
+        Some(Self {
+            tracks: schema::tracks_list(doc).len(),
+            pitches: first.pitches(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Song {
+    pub bpm: f64,
+    pub dimensions: SongDimensions,
+    /// Notes ordered by start time, then track and pitch
+    pub notes: Vec<Note>,
+}
+
+impl Song {
+    /// Read the song out of a document, skipping notes that break the schema
+    pub fn from_doc(doc: &LoroDoc) -> Self {
+        let bpm = schema::bpm_counter(doc).get_value();
+        let bpm = if bpm.is_finite() && bpm > 0.0 {
+            bpm
+        } else {
+            DEFAULT_BPM
+        };
+
+        let dimensions = SongDimensions::of(doc).unwrap_or_default();
+        let mut notes = Vec::new();
+        if let LoroValue::Map(values) = schema::notes_map(doc).get_deep_value() {
+            for (id, value) in values.iter() {
+                match parse_note(id, value, dimensions) {
+                    Some(note) => notes.push(note),
+                    None => tracing::debug!("Skipping malformed note {}", id),
+                }
+            }
+        }
+        notes.sort_by(|a, b| {
+            a.start
+                .total_cmp(&b.start)
+                .then(a.track.cmp(&b.track))
+                .then(a.pitch.cmp(&b.pitch))
+                .then(a.id.cmp(&b.id))
+        });
+
+        Self {
+            bpm,
+            dimensions,
+            notes,
+        }
+    }
+
+    pub fn track_notes(&self, track: usize) -> impl Iterator<Item = &Note> {
+        self.notes.iter().filter(move |note| note.track == track)
+    }
+}
+
+/// Lay out an empty song in a fresh document: the `bpm` counter at `bpm`, an
+/// empty `notes` map and the tracks of `dimensions` with the config `config`
+/// gives each
+pub fn init_song(
+    doc: &LoroDoc,
+    bpm: f64,
+    dimensions: SongDimensions,
+    config: impl Fn(usize) -> TrackConfig,
+) -> LoroResult<()> {
+    set_bpm(doc, bpm)?;
+    let _notes = schema::notes_map(doc);
+    for track in 0..dimensions.tracks {
+        push_track(doc, dimensions.pitches, &config(track))?;
+    }
+    Ok(())
+}
+
+/// Move the `bpm` counter to `bpm`
+pub fn set_bpm(doc: &LoroDoc, bpm: f64) -> LoroResult<()> {
+    let counter = schema::bpm_counter(doc);
+    let delta = bpm - counter.get_value();
+    if delta == 0.0 {
+        return Ok(());
+    }
+    counter.increment(delta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note::insert_note;
+
+    #[test]
+    fn test_init_song() {
+        let doc = LoroDoc::new();
+        let dimensions = SongDimensions {
+            tracks: 3,
+            pitches: 24,
+        };
+        init_song(&doc, 96.0, dimensions, |track| TrackConfig {
+            accent_color: Some(format!("#00000{}", track)),
+        })
+        .unwrap();
+
+        assert_eq!(SongDimensions::of(&doc), Some(dimensions));
+        let song = Song::from_doc(&doc);
+        assert_eq!(song.bpm, 96.0);
+        assert!(song.notes.is_empty());
+        assert_eq!(
+            crate::track_configs(&doc)[2].accent_color.as_deref(),
+            Some("#000002")
+        );
+    }
+
+    #[test]
+    fn test_from_doc_sorts_and_skips_malformed_notes() {
+        let doc = LoroDoc::new();
+        init_song(&doc, 120.0, SongDimensions::default(), |_| {
+            TrackConfig::default()
+        })
+        .unwrap();
+        let note = |id: &str, start: f64, track: usize| Note {
+            id: id.to_string(),
+            pitch: 12,
+            start,
+            duration: 1.0,
+            velocity: 100,
+            track,
+            created_by: "user".to_string(),
+            created_at: 0.0,
+        };
+        insert_note(&doc, &note("late", 4.0, 0)).unwrap();
+        insert_note(&doc, &note("high", 0.0, 5)).unwrap();
+        insert_note(&doc, &note("low", 0.0, 1)).unwrap();
+        // Outside the song's tracks
+        insert_note(&doc, &note("stray", 0.0, DEFAULT_TRACKS)).unwrap();
+
+        let song = Song::from_doc(&doc);
+        let ids: Vec<_> = song.notes.iter().map(|note| note.id.as_str()).collect();
+        assert_eq!(ids, vec!["low", "high", "late"]);
+        assert_eq!(song.notes[0], note("low", 0.0, 1));
+        assert_eq!(song.track_notes(5).count(), 1);
+    }
+}
@@ -0,0 +1,114 @@
+//! Tracks in the `tracks` list and their configs in `trackConfigs`.
+
+use loro::{Container, LoroDoc, LoroList, LoroMap, LoroResult, LoroValue, ValueOrContainer};
+
+use crate::schema::{self, track_config as field};
+
+/// A track of the `tracks` list: a list of note IDs per pitch, in the order
+/// the notes were added
+#[derive(Debug, Clone)]
+pub struct Track {
+    list: LoroList,
+}
+
+impl Track {
+    /// Track `index` of a document, `None` if the layout has no such track
+    pub fn get(doc: &LoroDoc, index: usize) -> Option<Self> {
+        match schema::tracks_list(doc).get(index) {
+            Some(ValueOrContainer::Container(Container::List(list))) => Some(Self { list }),
+            _ => None,
+        }
+    }
+
+    /// Number of pitch lists
+    pub fn pitches(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn pitch_list(&self, pitch: usize) -> Option<LoroList> {
+        match self.list.get(pitch) {
+            Some(ValueOrContainer::Container(Container::List(pitch_list))) => Some(pitch_list),
+            _ => None,
+        }
+    }
+
+    /// IDs on the pitch list of `pitch`, skipping anything that is not a string
+    pub fn note_ids(&self, pitch: usize) -> Vec<String> {
+        let Some(LoroValue::List(ids)) = self.pitch_list(pitch).map(|list| list.get_value()) else {
+            return Vec::new();
+        };
+        ids.iter()
+            .filter_map(|id| match id {
+                LoroValue::String(id) => Some(id.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Pitch list of `track` in the `tracks` container, if the layout has it
+pub fn pitch_list(doc: &LoroDoc, track: usize, pitch: usize) -> Option<LoroList> {
+    Track::get(doc, track)?.pitch_list(pitch)
+}
+
+/// Settings of a track, `TrackConfig` in the UI
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackConfig {
+    /// CSS color the editor draws the track in, the UI falls back to a
+    /// default color per track when missing
+    pub accent_color: Option<String>,
+}
+
+impl TrackConfig {
+    /// Read a value of the `trackConfigs` list, fields that break the schema are left out
+    pub fn from_value(value: &LoroValue) -> Self {
+        let LoroValue::Map(config) = value else {
+            return Self::default();
+        };
+        let accent_color = match config.get(field::ACCENT_COLOR) {
+            Some(LoroValue::String(color)) => Some(color.to_string()),
+            _ => None,
+        };
+        Self { accent_color }
+    }
+
+    fn write(&self, config_map: &LoroMap) -> LoroResult<()> {
+        if let Some(color) = &self.accent_color {
+            config_map.insert(field::ACCENT_COLOR, color.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Config of each track in `trackConfigs`
+pub fn track_configs(doc: &LoroDoc) -> Vec<TrackConfig> {
+    let LoroValue::List(configs) = schema::track_configs_list(doc).get_deep_value() else {
+        return Vec::new();
+    };
+    configs.iter().map(TrackConfig::from_value).collect()
+}
+
+/// Set the fields `config` has on the config of `track`, like the editor's
+/// `setTrackConfig`. Returns whether the track has a config.
+pub fn set_track_config(doc: &LoroDoc, track: usize, config: &TrackConfig) -> LoroResult<bool> {
+    match schema::track_configs_list(doc).get(track) {
+        Some(ValueOrContainer::Container(Container::Map(config_map))) => {
+            config.write(&config_map)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Append an empty track with `pitches` pitch lists and its config
+pub fn push_track(doc: &LoroDoc, pitches: usize, config: &TrackConfig) -> LoroResult<()> {
+    let tracks = schema::tracks_list(doc);
+    let track = tracks.insert_container(tracks.len(), LoroList::new())?;
+    for pitch in 0..pitches {
+        track.insert_container(pitch, LoroList::new())?;
+    }
+
+    let configs = schema::track_configs_list(doc);
+    let config_map = configs.insert_container(configs.len(), LoroMap::new())?;
+    config.write(&config_map)
+}
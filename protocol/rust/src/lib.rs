@@ -19,6 +19,7 @@
 //!             vy: -1.0,
 //!         }
 //!     )),
+//!     seq: 0,
 //! };
 //! let bytes = msg.encode_to_vec();
 //!
@@ -34,6 +35,22 @@ pub mod thesong {
 pub use thesong::*;
 pub use prost::Message;
 
+/// Version of the wire protocol, bumped when older peers would misread a message.
+///
+/// Keep in sync with `PROTOCOL_VERSION` in the TypeScript package.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Version of the song document layout (root containers and note fields).
+///
+/// Keep in sync with `SCHEMA_VERSION` in the TypeScript package.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Capability: the client imports `ServerWelcome.synthesizer_updates` instead of a snapshot
+pub const CAPABILITY_DELTA_RESUME: &str = "delta_resume";
+
+/// Capability: the client handles `ServerError` messages
+pub const CAPABILITY_SERVER_ERRORS: &str = "server_errors";
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -47,6 +64,7 @@ mod tests {
                 vx: 1.5,
                 vy: -0.5,
             })),
+            seq: 0,
         };
 
         let bytes = msg.encode_to_vec();
@@ -63,12 +81,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_client_hello_roundtrip() {
+        let msg = ClientMessage {
+            payload: Some(client_message::Payload::Hello(ClientHello {
+                version_vector: vec![5, 6, 7],
+                protocol_version: PROTOCOL_VERSION,
+                schema_version: SCHEMA_VERSION,
+                song_shape: Some(SongShape {
+                    tracks: 16,
+                    pitches: 60,
+                }),
+                capabilities: vec![CAPABILITY_DELTA_RESUME.to_string()],
+                resume_token: String::new(),
+            })),
+            seq: 0,
+        };
+
+        let bytes = msg.encode_to_vec();
+        let decoded = ClientMessage::decode(bytes.as_slice()).unwrap();
+
+        match decoded.payload {
+            Some(client_message::Payload::Hello(hello)) => {
+                assert_eq!(hello.version_vector, vec![5, 6, 7]);
+                assert_eq!(hello.protocol_version, PROTOCOL_VERSION);
+                assert_eq!(hello.song_shape.unwrap().pitches, 60);
+                assert_eq!(hello.capabilities, vec![CAPABILITY_DELTA_RESUME]);
+            }
+            _ => panic!("Expected Hello payload"),
+        }
+    }
+
     #[test]
     fn test_server_welcome_roundtrip() {
         let msg = ServerMessage {
             payload: Some(server_message::Payload::Welcome(ServerWelcome {
                 user_id: "test-user-123".to_string(),
                 synthesizer_snapshot: vec![1, 2, 3, 4],
+                stats: Some(ServerStats { online_users: 3 }),
+                synthesizer_updates: vec![],
+                protocol_version: PROTOCOL_VERSION,
+                schema_version: SCHEMA_VERSION,
+                song_shape: None,
+                capabilities: vec![],
+                resume_token: "token".to_string(),
             })),
         };
 
@@ -79,6 +135,7 @@ mod tests {
             Some(server_message::Payload::Welcome(welcome)) => {
                 assert_eq!(welcome.user_id, "test-user-123");
                 assert_eq!(welcome.synthesizer_snapshot, vec![1, 2, 3, 4]);
+                assert_eq!(welcome.stats.unwrap().online_users, 3);
             }
             _ => panic!("Expected Welcome payload"),
         }
@@ -118,5 +175,46 @@ mod tests {
             _ => panic!("Expected MousePositions payload"),
         }
     }
-}
 
+    #[test]
+    fn test_server_error_code_roundtrip() {
+        let msg = ServerMessage {
+            payload: Some(server_message::Payload::Error(ServerError {
+                message: "update required".to_string(),
+                code: ErrorCode::IncompatibleClient as i32,
+                seq: 0,
+            })),
+        };
+
+        let bytes = msg.encode_to_vec();
+        let decoded = ServerMessage::decode(bytes.as_slice()).unwrap();
+
+        match decoded.payload {
+            Some(server_message::Payload::Error(error)) => {
+                assert_eq!(error.code(), ErrorCode::IncompatibleClient);
+            }
+            _ => panic!("Expected Error payload"),
+        }
+    }
+
+    #[test]
+    fn test_seq_and_ack_roundtrip() {
+        let msg = ClientMessage {
+            payload: Some(client_message::Payload::SynthesizerUpdate(
+                ClientSynthesizerUpdate { data: vec![1] },
+            )),
+            seq: 42,
+        };
+        let decoded = ClientMessage::decode(msg.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded.seq, 42);
+
+        let ack = ServerMessage {
+            payload: Some(server_message::Payload::Ack(ServerAck { seq: 42 })),
+        };
+        let decoded = ServerMessage::decode(ack.encode_to_vec().as_slice()).unwrap();
+        match decoded.payload {
+            Some(server_message::Payload::Ack(ack)) => assert_eq!(ack.seq, 42),
+            _ => panic!("Expected Ack payload"),
+        }
+    }
+}
@@ -2,15 +2,15 @@
 // @generated from file the-song.proto (package thesong, syntax proto3)
 /* eslint-disable */
 
-import type { GenFile, GenMessage } from "@bufbuild/protobuf/codegenv2";
-import { fileDesc, messageDesc } from "@bufbuild/protobuf/codegenv2";
+import type { GenEnum, GenFile, GenMessage } from "@bufbuild/protobuf/codegenv2";
+import { enumDesc, fileDesc, messageDesc } from "@bufbuild/protobuf/codegenv2";
 import type { Message } from "@bufbuild/protobuf";
 
 /**
  * Describes the file the-song.proto.
  */
 export const file_the_song: GenFile = /*@__PURE__*/
-  fileDesc("Cg50aGUtc29uZy5wcm90bxIHdGhlc29uZyI0Cg1Nb3VzZVBvc2l0aW9uEgkKAXgYASABKAISCQoBeRgCIAEoAhINCgVkaXJ0eRgDIAEoCCIjCgtTZXJ2ZXJTdGF0cxIUCgxvbmxpbmVfdXNlcnMYASABKA0iQQoRQ2xpZW50TW91c2VVcGRhdGUSCQoBeBgBIAEoAhIJCgF5GAIgASgCEgoKAnZ4GAMgASgCEgoKAnZ5GAQgASgCIicKF0NsaWVudFN5bnRoZXNpemVyVXBkYXRlEgwKBGRhdGEYASABKAwijgEKDUNsaWVudE1lc3NhZ2USMgoMbW91c2VfdXBkYXRlGAEgASgLMhoudGhlc29uZy5DbGllbnRNb3VzZVVwZGF0ZUgAEj4KEnN5bnRoZXNpemVyX3VwZGF0ZRgCIAEoCzIgLnRoZXNvbmcuQ2xpZW50U3ludGhlc2l6ZXJVcGRhdGVIAEIJCgdwYXlsb2FkImMKDVNlcnZlcldlbGNvbWUSDwoHdXNlcl9pZBgBIAEoCRIcChRzeW50aGVzaXplcl9zbmFwc2hvdBgCIAEoDBIjCgVzdGF0cxgDIAEoCzIULnRoZXNvbmcuU2VydmVyU3RhdHMiOAoRU2VydmVyU3RhdHNVcGRhdGUSIwoFc3RhdHMYASABKAsyFC50aGVzb25nLlNlcnZlclN0YXRzIqEBChRTZXJ2ZXJNb3VzZVBvc2l0aW9ucxI/Cglwb3NpdGlvbnMYASADKAsyLC50aGVzb25nLlNlcnZlck1vdXNlUG9zaXRpb25zLlBvc2l0aW9uc0VudHJ5GkgKDlBvc2l0aW9uc0VudHJ5EgsKA2tleRgBIAEoCRIlCgV2YWx1ZRgCIAEoCzIWLnRoZXNvbmcuTW91c2VQb3NpdGlvbjoCOAEiJwoXU2VydmVyU3ludGhlc2l6ZXJVcGRhdGUSDAoEZGF0YRgBIAEoDCLsAQoNU2VydmVyTWVzc2FnZRIpCgd3ZWxjb21lGAEgASgLMhYudGhlc29uZy5TZXJ2ZXJXZWxjb21lSAASKwoFc3RhdHMYAiABKAsyGi50aGVzb25nLlNlcnZlclN0YXRzVXBkYXRlSAASOAoPbW91c2VfcG9zaXRpb25zGAMgASgLMh0udGhlc29uZy5TZXJ2ZXJNb3VzZVBvc2l0aW9uc0gAEj4KEnN5bnRoZXNpemVyX3VwZGF0ZRgEIAEoCzIgLnRoZXNvbmcuU2VydmVyU3ludGhlc2l6ZXJVcGRhdGVIAEIJCgdwYXlsb2FkYgZwcm90bzM");
+  fileDesc("Cg50aGUtc29uZy5wcm90bxIHdGhlc29uZyI0Cg1Nb3VzZVBvc2l0aW9uEgkKAXgYASABKAISCQoBeRgCIAEoAhINCgVkaXJ0eRgDIAEoCCIjCgtTZXJ2ZXJTdGF0cxIUCgxvbmxpbmVfdXNlcnMYASABKA0iLAoJU29uZ1NoYXBlEg4KBnRyYWNrcxgBIAEoDRIPCgdwaXRjaGVzGAIgASgNIkEKEUNsaWVudE1vdXNlVXBkYXRlEgkKAXgYASABKAISCQoBeRgCIAEoAhIKCgJ2eBgDIAEoAhIKCgJ2eRgEIAEoAiInChdDbGllbnRTeW50aGVzaXplclVwZGF0ZRIMCgRkYXRhGAEgASgMIqsBCgtDbGllbnRIZWxsbxIWCg52ZXJzaW9uX3ZlY3RvchgBIAEoDBIYChBwcm90b2NvbF92ZXJzaW9uGAIgASgNEhYKDnNjaGVtYV92ZXJzaW9uGAMgASgNEiYKCnNvbmdfc2hhcGUYBCABKAsyEi50aGVzb25nLlNvbmdTaGFwZRIUCgxjYXBhYmlsaXRpZXMYBSADKAkSFAoMcmVzdW1lX3Rva2VuGAYgASgJIsIBCg1DbGllbnRNZXNzYWdlEjIKDG1vdXNlX3VwZGF0ZRgBIAEoCzIaLnRoZXNvbmcuQ2xpZW50TW91c2VVcGRhdGVIABI+ChJzeW50aGVzaXplcl91cGRhdGUYAiABKAsyIC50aGVzb25nLkNsaWVudFN5bnRoZXNpemVyVXBkYXRlSAASJQoFaGVsbG8YAyABKAsyFC50aGVzb25nLkNsaWVudEhlbGxvSAASCwoDc2VxGAQgASgNQgkKB3BheWxvYWQihgIKDVNlcnZlcldlbGNvbWUSDwoHdXNlcl9pZBgBIAEoCRIcChRzeW50aGVzaXplcl9zbmFwc2hvdBgCIAEoDBIjCgVzdGF0cxgDIAEoCzIULnRoZXNvbmcuU2VydmVyU3RhdHMSGwoTc3ludGhlc2l6ZXJfdXBkYXRlcxgEIAEoDBIYChBwcm90b2NvbF92ZXJzaW9uGAUgASgNEhYKDnNjaGVtYV92ZXJzaW9uGAYgASgNEiYKCnNvbmdfc2hhcGUYByABKAsyEi50aGVzb25nLlNvbmdTaGFwZRIUCgxjYXBhYmlsaXRpZXMYCCADKAkSFAoMcmVzdW1lX3Rva2VuGAkgASgJIjgKEVNlcnZlclN0YXRzVXBkYXRlEiMKBXN0YXRzGAEgASgLMhQudGhlc29uZy5TZXJ2ZXJTdGF0cyKhAQoUU2VydmVyTW91c2VQb3NpdGlvbnMSPwoJcG9zaXRpb25zGAEgAygLMiwudGhlc29uZy5TZXJ2ZXJNb3VzZVBvc2l0aW9ucy5Qb3NpdGlvbnNFbnRyeRpICg5Qb3NpdGlvbnNFbnRyeRILCgNrZXkYASABKAkSJQoFdmFsdWUYAiABKAsyFi50aGVzb25nLk1vdXNlUG9zaXRpb246AjgBIicKF1NlcnZlclN5bnRoZXNpemVyVXBkYXRlEgwKBGRhdGEYASABKAwiTQoLU2VydmVyRXJyb3ISDwoHbWVzc2FnZRgBIAEoCRIgCgRjb2RlGAIgASgOMhIudGhlc29uZy5FcnJvckNvZGUSCwoDc2VxGAMgASgNIhgKCVNlcnZlckFjaxILCgNzZXEYASABKA0itgIKDVNlcnZlck1lc3NhZ2USKQoHd2VsY29tZRgBIAEoCzIWLnRoZXNvbmcuU2VydmVyV2VsY29tZUgAEisKBXN0YXRzGAIgASgLMhoudGhlc29uZy5TZXJ2ZXJTdGF0c1VwZGF0ZUgAEjgKD21vdXNlX3Bvc2l0aW9ucxgDIAEoCzIdLnRoZXNvbmcuU2VydmVyTW91c2VQb3NpdGlvbnNIABI+ChJzeW50aGVzaXplcl91cGRhdGUYBCABKAsyIC50aGVzb25nLlNlcnZlclN5bnRoZXNpemVyVXBkYXRlSAASJQoFZXJyb3IYBSABKAsyFC50aGVzb25nLlNlcnZlckVycm9ySAASIQoDYWNrGAYgASgLMhIudGhlc29uZy5TZXJ2ZXJBY2tIAEIJCgdwYXlsb2FkKvwBCglFcnJvckNvZGUSGgoWRVJST1JfQ09ERV9VTlNQRUNJRklFRBAAEiIKHkVSUk9SX0NPREVfSU5DT01QQVRJQkxFX0NMSUVOVBABEhsKF0VSUk9SX0NPREVfREVDT0RFX0VSUk9SEAISHQoZRVJST1JfQ09ERV9JTlZBTElEX1VQREFURRADEhsKF0VSUk9SX0NPREVfUkFURV9MSU1JVEVEEAQSGAoURVJST1JfQ09ERV9ST09NX0ZVTEwQBRIdChlFUlJPUl9DT0RFX0lOVEVSTkFMX0VSUk9SEAYSHQoZRVJST1JfQ09ERV9RVU9UQV9FWENFRURFRBAHYgZwcm90bzM");
 
 /**
  * Mouse position for a user
@@ -60,6 +60,30 @@ export type ServerStats = Message<"thesong.ServerStats"> & {
 export const ServerStatsSchema: GenMessage<ServerStats> = /*@__PURE__*/
   messageDesc(file_the_song, 1);
 
+/**
+ * Number of tracks and pitches per track of a song
+ *
+ * @generated from message thesong.SongShape
+ */
+export type SongShape = Message<"thesong.SongShape"> & {
+  /**
+   * @generated from field: uint32 tracks = 1;
+   */
+  tracks: number;
+
+  /**
+   * @generated from field: uint32 pitches = 2;
+   */
+  pitches: number;
+};
+
+/**
+ * Describes the message thesong.SongShape.
+ * Use `create(SongShapeSchema)` to create a new message.
+ */
+export const SongShapeSchema: GenMessage<SongShape> = /*@__PURE__*/
+  messageDesc(file_the_song, 2);
+
 /**
  * Mouse update from client
  *
@@ -96,7 +120,7 @@ export type ClientMouseUpdate = Message<"thesong.ClientMouseUpdate"> & {
  * Use `create(ClientMouseUpdateSchema)` to create a new message.
  */
 export const ClientMouseUpdateSchema: GenMessage<ClientMouseUpdate> = /*@__PURE__*/
-  messageDesc(file_the_song, 2);
+  messageDesc(file_the_song, 3);
 
 /**
  * Synthesizer CRDT update from client
@@ -117,7 +141,63 @@ export type ClientSynthesizerUpdate = Message<"thesong.ClientSynthesizerUpdate">
  * Use `create(ClientSynthesizerUpdateSchema)` to create a new message.
  */
 export const ClientSynthesizerUpdateSchema: GenMessage<ClientSynthesizerUpdate> = /*@__PURE__*/
-  messageDesc(file_the_song, 3);
+  messageDesc(file_the_song, 4);
+
+/**
+ * First message from a client: what it speaks and, when reconnecting, what it already has
+ *
+ * @generated from message thesong.ClientHello
+ */
+export type ClientHello = Message<"thesong.ClientHello"> & {
+  /**
+   * loro-crdt encoded version vector, empty for a fresh document
+   *
+   * @generated from field: bytes version_vector = 1;
+   */
+  versionVector: Uint8Array;
+
+  /**
+   * PROTOCOL_VERSION the client was built with, 0 predates the handshake
+   *
+   * @generated from field: uint32 protocol_version = 2;
+   */
+  protocolVersion: number;
+
+  /**
+   * SCHEMA_VERSION of the song document the client edits
+   *
+   * @generated from field: uint32 schema_version = 3;
+   */
+  schemaVersion: number;
+
+  /**
+   * song shape the client can display
+   *
+   * @generated from field: thesong.SongShape song_shape = 4;
+   */
+  songShape?: SongShape;
+
+  /**
+   * optional features the client understands
+   *
+   * @generated from field: repeated string capabilities = 5;
+   */
+  capabilities: string[];
+
+  /**
+   * resume_token of an earlier welcome, to keep the same user across reconnects
+   *
+   * @generated from field: string resume_token = 6;
+   */
+  resumeToken: string;
+};
+
+/**
+ * Describes the message thesong.ClientHello.
+ * Use `create(ClientHelloSchema)` to create a new message.
+ */
+export const ClientHelloSchema: GenMessage<ClientHello> = /*@__PURE__*/
+  messageDesc(file_the_song, 5);
 
 /**
  * Wrapper for all client messages
@@ -140,7 +220,20 @@ export type ClientMessage = Message<"thesong.ClientMessage"> & {
      */
     value: ClientSynthesizerUpdate;
     case: "synthesizerUpdate";
+  } | {
+    /**
+     * @generated from field: thesong.ClientHello hello = 3;
+     */
+    value: ClientHello;
+    case: "hello";
   } | { case: undefined; value?: undefined };
+
+  /**
+   * echoed in the ServerAck or ServerError answering this message, 0 asks for neither
+   *
+   * @generated from field: uint32 seq = 4;
+   */
+  seq: number;
 };
 
 /**
@@ -148,7 +241,7 @@ export type ClientMessage = Message<"thesong.ClientMessage"> & {
  * Use `create(ClientMessageSchema)` to create a new message.
  */
 export const ClientMessageSchema: GenMessage<ClientMessage> = /*@__PURE__*/
-  messageDesc(file_the_song, 4);
+  messageDesc(file_the_song, 6);
 
 /**
  * Welcome message sent when client connects
@@ -164,7 +257,7 @@ export type ServerWelcome = Message<"thesong.ServerWelcome"> & {
   userId: string;
 
   /**
-   * loro-crdt snapshot
+   * loro-crdt snapshot, empty when synthesizer_updates is sent
    *
    * @generated from field: bytes synthesizer_snapshot = 2;
    */
@@ -174,6 +267,48 @@ export type ServerWelcome = Message<"thesong.ServerWelcome"> & {
    * @generated from field: thesong.ServerStats stats = 3;
    */
   stats?: ServerStats;
+
+  /**
+   * loro-crdt updates missing from the version vector in ClientHello
+   *
+   * @generated from field: bytes synthesizer_updates = 4;
+   */
+  synthesizerUpdates: Uint8Array;
+
+  /**
+   * PROTOCOL_VERSION of the server
+   *
+   * @generated from field: uint32 protocol_version = 5;
+   */
+  protocolVersion: number;
+
+  /**
+   * SCHEMA_VERSION of the room's song
+   *
+   * @generated from field: uint32 schema_version = 6;
+   */
+  schemaVersion: number;
+
+  /**
+   * shape of the room's song
+   *
+   * @generated from field: thesong.SongShape song_shape = 7;
+   */
+  songShape?: SongShape;
+
+  /**
+   * capabilities both sides support
+   *
+   * @generated from field: repeated string capabilities = 8;
+   */
+  capabilities: string[];
+
+  /**
+   * secret to send in the next ClientHello to keep user_id and its Loro peers
+   *
+   * @generated from field: string resume_token = 9;
+   */
+  resumeToken: string;
 };
 
 /**
@@ -181,7 +316,7 @@ export type ServerWelcome = Message<"thesong.ServerWelcome"> & {
  * Use `create(ServerWelcomeSchema)` to create a new message.
  */
 export const ServerWelcomeSchema: GenMessage<ServerWelcome> = /*@__PURE__*/
-  messageDesc(file_the_song, 5);
+  messageDesc(file_the_song, 7);
 
 /**
  * Server stats broadcast
@@ -200,7 +335,7 @@ export type ServerStatsUpdate = Message<"thesong.ServerStatsUpdate"> & {
  * Use `create(ServerStatsUpdateSchema)` to create a new message.
  */
 export const ServerStatsUpdateSchema: GenMessage<ServerStatsUpdate> = /*@__PURE__*/
-  messageDesc(file_the_song, 6);
+  messageDesc(file_the_song, 8);
 
 /**
  * Mouse positions for all users
@@ -221,7 +356,7 @@ export type ServerMousePositions = Message<"thesong.ServerMousePositions"> & {
  * Use `create(ServerMousePositionsSchema)` to create a new message.
  */
 export const ServerMousePositionsSchema: GenMessage<ServerMousePositions> = /*@__PURE__*/
-  messageDesc(file_the_song, 7);
+  messageDesc(file_the_song, 9);
 
 /**
  * Synthesizer update broadcast
@@ -242,7 +377,57 @@ export type ServerSynthesizerUpdate = Message<"thesong.ServerSynthesizerUpdate">
  * Use `create(ServerSynthesizerUpdateSchema)` to create a new message.
  */
 export const ServerSynthesizerUpdateSchema: GenMessage<ServerSynthesizerUpdate> = /*@__PURE__*/
-  messageDesc(file_the_song, 8);
+  messageDesc(file_the_song, 10);
+
+/**
+ * Error sent to a single client, e.g. when its update was rejected
+ *
+ * @generated from message thesong.ServerError
+ */
+export type ServerError = Message<"thesong.ServerError"> & {
+  /**
+   * @generated from field: string message = 1;
+   */
+  message: string;
+
+  /**
+   * @generated from field: thesong.ErrorCode code = 2;
+   */
+  code: ErrorCode;
+
+  /**
+   * seq of the client message that failed, 0 when it had none
+   *
+   * @generated from field: uint32 seq = 3;
+   */
+  seq: number;
+};
+
+/**
+ * Describes the message thesong.ServerError.
+ * Use `create(ServerErrorSchema)` to create a new message.
+ */
+export const ServerErrorSchema: GenMessage<ServerError> = /*@__PURE__*/
+  messageDesc(file_the_song, 11);
+
+/**
+ * Acknowledges a client message that carried a seq
+ *
+ * @generated from message thesong.ServerAck
+ */
+export type ServerAck = Message<"thesong.ServerAck"> & {
+  /**
+   * @generated from field: uint32 seq = 1;
+   */
+  seq: number;
+};
+
+/**
+ * Describes the message thesong.ServerAck.
+ * Use `create(ServerAckSchema)` to create a new message.
+ */
+export const ServerAckSchema: GenMessage<ServerAck> = /*@__PURE__*/
+  messageDesc(file_the_song, 12);
 
 /**
  * Wrapper for all server messages
@@ -277,6 +462,18 @@ export type ServerMessage = Message<"thesong.ServerMessage"> & {
      */
     value: ServerSynthesizerUpdate;
     case: "synthesizerUpdate";
+  } | {
+    /**
+     * @generated from field: thesong.ServerError error = 5;
+     */
+    value: ServerError;
+    case: "error";
+  } | {
+    /**
+     * @generated from field: thesong.ServerAck ack = 6;
+     */
+    value: ServerAck;
+    case: "ack";
   } | { case: undefined; value?: undefined };
 };
 
@@ -285,5 +482,72 @@ export type ServerMessage = Message<"thesong.ServerMessage"> & {
  * Use `create(ServerMessageSchema)` to create a new message.
  */
 export const ServerMessageSchema: GenMessage<ServerMessage> = /*@__PURE__*/
-  messageDesc(file_the_song, 9);
+  messageDesc(file_the_song, 13);
+
+/**
+ * What went wrong, so clients can react without parsing the message
+ *
+ * @generated from enum thesong.ErrorCode
+ */
+export enum ErrorCode {
+  /**
+   * @generated from enum value: ERROR_CODE_UNSPECIFIED = 0;
+   */
+  UNSPECIFIED = 0,
+
+  /**
+   * the client must be updated, the connection is closed after this error
+   *
+   * @generated from enum value: ERROR_CODE_INCOMPATIBLE_CLIENT = 1;
+   */
+  INCOMPATIBLE_CLIENT = 1,
+
+  /**
+   * the frame is not a ClientMessage
+   *
+   * @generated from enum value: ERROR_CODE_DECODE_ERROR = 2;
+   */
+  DECODE_ERROR = 2,
+
+  /**
+   * the synthesizer update was rejected, resending it will not help
+   *
+   * @generated from enum value: ERROR_CODE_INVALID_UPDATE = 3;
+   */
+  INVALID_UPDATE = 3,
+
+  /**
+   * the message was dropped, send it again later
+   *
+   * @generated from enum value: ERROR_CODE_RATE_LIMITED = 4;
+   */
+  RATE_LIMITED = 4,
+
+  /**
+   * the room has no space left, the connection is closed after this error
+   *
+   * @generated from enum value: ERROR_CODE_ROOM_FULL = 5;
+   */
+  ROOM_FULL = 5,
+
+  /**
+   * the server failed, sending the message again may work
+   *
+   * @generated from enum value: ERROR_CODE_INTERNAL_ERROR = 6;
+   */
+  INTERNAL_ERROR = 6,
+
+  /**
+   * the update adds or removes more notes than the user may right now
+   *
+   * @generated from enum value: ERROR_CODE_QUOTA_EXCEEDED = 7;
+   */
+  QUOTA_EXCEEDED = 7,
+}
+
+/**
+ * Describes the enum thesong.ErrorCode.
+ */
+export const ErrorCodeSchema: GenEnum<ErrorCode> = /*@__PURE__*/
+  enumDesc(file_the_song, 0);
 
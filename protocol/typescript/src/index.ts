@@ -29,3 +29,21 @@
  */
 
 export * from "./gen/the-song_pb.js";
+
+/**
+ * Version of the wire protocol, bumped when older peers would misread a message.
+ * Keep in sync with `PROTOCOL_VERSION` in the Rust crate.
+ */
+export const PROTOCOL_VERSION = 1;
+
+/**
+ * Version of the song document layout (root containers and note fields).
+ * Keep in sync with `SCHEMA_VERSION` in the Rust crate.
+ */
+export const SCHEMA_VERSION = 1;
+
+/** Capability: the client imports `ServerWelcome.synthesizerUpdates` instead of a snapshot */
+export const CAPABILITY_DELTA_RESUME = "delta_resume";
+
+/** Capability: the client handles `ServerError` messages */
+export const CAPABILITY_SERVER_ERRORS = "server_errors";
@@ -6,6 +6,7 @@ import { useMemo } from "react";
 export default function OnlineUser() {
   const status = useStore((state) => state.status);
   const onlineUsers = useStore((state) => state.serverStats?.online_users);
+  const serverError = useStore((state) => state.serverError);
 
   const text = () => {
     if (status === WsStatus.Connected) {
@@ -40,8 +41,12 @@ export default function OnlineUser() {
   }, [status]);
 
   return (
-    <div className={cn("border px-2 py-1 text-xs", borderColor, textColor)}>
+    <div
+      className={cn("border px-2 py-1 text-xs", borderColor, textColor)}
+      title={serverError ?? undefined}
+    >
       {text()}
+      {serverError && <span className="ml-1 text-red-500">· sync error</span>}
     </div>
   );
 }
@@ -6,6 +6,8 @@ export const THROTTLE_COMMIT_DELAY = 100;
 
 // Total number of pitches (5 octaves * 12 notes per octave)
 export const TOTAL_PITCHES = 60;
+// Number of tracks in a song
+export const NUM_TRACKS = 16;
 
 export const MIN_BPM = 60;
 export const MAX_BPM = 160;
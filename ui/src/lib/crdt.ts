@@ -1,6 +1,6 @@
 import { LoroCounter, LoroDoc, LoroList, LoroMap } from "loro-crdt";
 
-import { MIN_BPM, MAX_BPM } from "@/config";
+import { MIN_BPM, MAX_BPM, NUM_TRACKS, TOTAL_PITCHES } from "@/config";
 import { EventEmitter } from "@/lib/event";
 import type {
   NoteData,
@@ -8,10 +8,6 @@ import type {
   NoteIdsByPitch,
 } from "@/lib/piano-roll-renderer/types";
 
-// Total number of pitches (4 octaves * 12 notes per octave)
-const TOTAL_PITCHES = 48;
-const NUM_TRACKS = 16;
-
 // Default accent colors for tracks (16 distinct colors)
 export const DEFAULT_ACCENT_COLORS = [
   "#00ff88", // Mint green
@@ -53,20 +49,25 @@ export type BpmChangeCallback = (bpm: number) => void;
 
 export class Crdt extends EventEmitter<CommitEvent> {
   private doc: LoroDoc;
-  private bpm: LoroCounter;
-  private notes: LoroMap; // Map of noteId -> NoteData
-  private tracks: LoroList; // List of 16 tracks, each track is a LoroList of pitch lists
-  private trackConfigs: LoroList; // List of 16 track configs
+  private bpm!: LoroCounter;
+  private notes!: LoroMap; // Map of noteId -> NoteData
+  private tracks!: LoroList; // List of 16 tracks, each track is a LoroList of pitch lists
+  private trackConfigs!: LoroList; // List of 16 track configs
   private changeCallbacks: ChangeCallback[] = [];
   private bpmChangeCallbacks: BpmChangeCallback[] = [];
 
   constructor() {
     super();
-    this.doc = new LoroDoc();
-    this.bpm = this.doc.getCounter("bpm");
-    this.notes = this.doc.getMap("notes");
-    this.tracks = this.doc.getList("tracks");
-    this.trackConfigs = this.doc.getList("trackConfigs");
+    this.doc = this.attach(new LoroDoc());
+  }
+
+  private attach(doc: LoroDoc): LoroDoc {
+    // Timestamps show up in the server's version history
+    doc.setRecordTimestamp(true);
+    this.bpm = doc.getCounter("bpm");
+    this.notes = doc.getMap("notes");
+    this.tracks = doc.getList("tracks");
+    this.trackConfigs = doc.getList("trackConfigs");
 
     // Subscribe to notes changes
     this.notes.subscribe(() => {
@@ -88,15 +89,41 @@ export class Crdt extends EventEmitter<CommitEvent> {
       this.notifyBpmChange();
     });
 
-    this.doc.subscribeLocalUpdates((updates) => {
+    doc.subscribeLocalUpdates((updates) => {
       this.emit({ name: "commit", data: updates });
     });
+    return doc;
+  }
+
+  /**
+   * Drop everything, local edits included, and start over with an empty
+   * document on a new peer. Used when the server rejected local edits, the
+   * server's snapshot is imported right after.
+   */
+  public reset() {
+    this.doc = this.attach(new LoroDoc());
   }
 
   public import(snapshot: Uint8Array) {
     this.doc.import(snapshot);
   }
 
+  /**
+   * Continue editing as a new Loro peer. The server binds each peer to one
+   * user, so edits made under a new user ID need a peer of their own.
+   */
+  public renewPeer() {
+    const [peer] = crypto.getRandomValues(new BigUint64Array(1));
+    this.doc.setPeerId(peer);
+  }
+
+  /**
+   * Encoded version vector of everything this document has seen
+   */
+  public versionVector(): Uint8Array {
+    return this.doc.oplogVersion().encode();
+  }
+
   // --- Direct access to Loro containers ---
 
   public getNotesContainer(): LoroMap {
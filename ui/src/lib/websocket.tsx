@@ -1,8 +1,13 @@
 import { EventEmitter } from "@/lib/event";
-import { WS_URL } from "@/config";
+import { NUM_TRACKS, TOTAL_PITCHES, WS_URL } from "@/config";
 import {
+  CAPABILITY_DELTA_RESUME,
+  CAPABILITY_SERVER_ERRORS,
   type ClientMessage,
   ClientMessageSchema,
+  ErrorCode,
+  PROTOCOL_VERSION,
+  SCHEMA_VERSION,
   type ServerMessage,
   ServerMessageSchema,
 } from "@the-song/protocol";
@@ -29,12 +34,28 @@ export type WaitingEvent = {
   name: "waiting";
 };
 
+// The server rejected an update, the local song has to start over from the
+// server's copy. The next hello should carry an empty version vector.
+export type ResyncEvent = {
+  name: "resync";
+};
+
 export type WebsocketEvent =
   | ConnectedEvent
   | DisconnectedEvent
   | ReconnectingEvent
   | BinaryMessageEvent
-  | WaitingEvent;
+  | WaitingEvent
+  | ResyncEvent;
+
+// Close code of a server that refused this client, reconnecting will not help
+const CLOSE_INCOMPATIBLE_CLIENT = 4001;
+
+// Delay before sending an update again after a retryable error
+const UPDATE_RETRY_DELAY = 1000;
+
+// Delay before reconnecting after the connection closed
+const RECONNECT_DELAY = 2500;
 
 export enum WsStatus {
   Initial = "initial",
@@ -50,6 +71,13 @@ export class WebSocketClient extends EventEmitter<WebsocketEvent> {
   private status: WsStatus = WsStatus.Initial;
   private shouldConnect: boolean = true;
   private url: string;
+  // Synthesizer updates the server has not acknowledged yet, by seq
+  private pendingUpdates = new Map<number, Uint8Array>();
+  private nextSeq = 1;
+  // From the last welcome, lets a reconnect keep the same user ID
+  private resumeToken = "";
+  // Reconnecting for a fresh snapshot, local edits until its welcome are dropped
+  private resyncing = false;
 
   constructor(url: string) {
     super();
@@ -73,13 +101,18 @@ export class WebSocketClient extends EventEmitter<WebsocketEvent> {
       this.emit({ name: "waiting" });
     };
     this.socket.onclose = (event) => {
+      if (event.code === CLOSE_INCOMPATIBLE_CLIENT) {
+        console.error("[WS] Server refused this client:", event.reason);
+        this.shouldConnect = false;
+      }
       if (this.shouldConnect) {
-        console.debug("[WS] Connection closed, reconnecting in 2500ms");
+        const delay = this.resyncing ? 0 : RECONNECT_DELAY;
+        console.debug(`[WS] Connection closed, reconnecting in ${delay}ms`);
         this.status = WsStatus.Reconnecting;
         this.emit({ name: "reconnecting" });
         setTimeout(() => {
           this.connect();
-        }, 2500);
+        }, delay);
       } else {
         this.status = WsStatus.Disconnected;
         this.emit({ name: "disconnected" });
@@ -104,7 +137,13 @@ export class WebSocketClient extends EventEmitter<WebsocketEvent> {
           if (this.status === WsStatus.Waiting) {
             this.status = WsStatus.Connected;
             this.emit({ name: "connected" });
+            this.resendPendingUpdates();
           }
+          if (message.payload.case === "welcome") {
+            this.resumeToken = message.payload.value.resumeToken;
+            this.resyncing = false;
+          }
+          this.trackAcknowledgement(message);
           this.emit({ name: "message", data: message });
         } catch (error) {
           console.error("[WS] Failed to decode binary message:", error);
@@ -150,17 +189,87 @@ export class WebSocketClient extends EventEmitter<WebsocketEvent> {
   }
 
   /**
-   * Helper to create and send a synthesizer update message
+   * Helper to create and send the hello message, must be the first message
+   * after connecting
+   */
+  sendHello(versionVector: Uint8Array) {
+    const message = create(ClientMessageSchema, {
+      payload: {
+        case: "hello",
+        value: {
+          versionVector,
+          protocolVersion: PROTOCOL_VERSION,
+          schemaVersion: SCHEMA_VERSION,
+          songShape: { tracks: NUM_TRACKS, pitches: TOTAL_PITCHES },
+          capabilities: [CAPABILITY_DELTA_RESUME, CAPABILITY_SERVER_ERRORS],
+          resumeToken: this.resumeToken,
+        },
+      },
+    });
+    this.send(message);
+  }
+
+  /**
+   * Helper to create and send a synthesizer update message. The update is
+   * kept until the server acknowledges it and sent again after reconnecting.
    */
   sendSynthesizerUpdate(data: Uint8Array) {
+    if (this.resyncing) return;
+    const seq = this.nextSeq++;
+    this.pendingUpdates.set(seq, data);
+    this.sendPendingUpdate(seq);
+  }
+
+  private sendPendingUpdate(seq: number) {
+    const data = this.pendingUpdates.get(seq);
+    if (!data) return;
     const message = create(ClientMessageSchema, {
       payload: {
         case: "synthesizerUpdate",
         value: { data },
       },
+      seq,
     });
     this.send(message);
   }
+
+  private resendPendingUpdates() {
+    // Importing an update twice is harmless, so resend everything unconfirmed
+    for (const seq of this.pendingUpdates.keys()) {
+      this.sendPendingUpdate(seq);
+    }
+  }
+
+  private trackAcknowledgement(message: ServerMessage) {
+    const payload = message.payload;
+    if (payload.case === "ack") {
+      this.pendingUpdates.delete(payload.value.seq);
+    } else if (payload.case === "error" && payload.value.seq !== 0) {
+      const { seq, code } = payload.value;
+      if (
+        code === ErrorCode.RATE_LIMITED ||
+        code === ErrorCode.INTERNAL_ERROR
+      ) {
+        setTimeout(() => this.sendPendingUpdate(seq), UPDATE_RETRY_DELAY);
+      } else {
+        this.resync();
+      }
+    }
+  }
+
+  /**
+   * The server refused an update, so the local song has ops it will never
+   * have. Later updates may build on them and are dropped as well, then the
+   * connection starts over to fetch the server's song in full.
+   */
+  private resync() {
+    if (this.resyncing) return;
+    console.warn("[WS] Update rejected, reloading the song from the server");
+    this.resyncing = true;
+    this.pendingUpdates.clear();
+    this.emit({ name: "resync" });
+    this.socket?.close();
+  }
 }
 
 export const WS_CLIENT = new WebSocketClient(WS_URL);
@@ -6,6 +6,8 @@ import type { ServerMessage } from "@the-song/protocol";
 export interface ServerSlice {
   // State
   serverStats: ServerStats;
+  // Last error the server reported, cleared once a message goes through
+  serverError: string | null;
 }
 
 export const createServerSlice: StateCreator<
@@ -40,6 +42,14 @@ export const createServerSlice: StateCreator<
         }
         break;
       }
+      case "error": {
+        set({ serverError: payload.value.message });
+        break;
+      }
+      case "ack": {
+        set({ serverError: null });
+        break;
+      }
     }
   });
   return {
@@ -47,5 +57,6 @@ export const createServerSlice: StateCreator<
     serverStats: {
       online_users: 0,
     },
+    serverError: null,
   };
 };
@@ -2,7 +2,7 @@ import type { StateCreator } from "zustand";
 import { WS_CLIENT } from "@/lib/websocket";
 import { Crdt, crdt, type NoteUpdates, type TrackConfig } from "@/lib/crdt";
 import { EDITOR_CONTROLLER } from "@/lib/piano-roll-renderer";
-import type { ServerMessage } from "@the-song/protocol";
+import { ErrorCode, type ServerMessage } from "@the-song/protocol";
 
 export interface SynthesizedSlice {
   // BPM State
@@ -51,6 +51,21 @@ export const createSynthesizedSlice: StateCreator<
     WS_CLIENT.sendSynthesizerUpdate(event.data);
   });
 
+  // User the local Loro peer belongs to on the server
+  let peerUserId: string | null = null;
+
+  // Set when the server rejected our edits, the next welcome replaces the song
+  let resyncing = false;
+
+  WS_CLIENT.on("resync", () => {
+    resyncing = true;
+  });
+
+  // Tell the server what we already have so a reconnect only fetches the gap
+  WS_CLIENT.on("waiting", () => {
+    WS_CLIENT.sendHello(resyncing ? new Uint8Array() : crdt.versionVector());
+  });
+
   // Subscribe to message events (binary protobuf messages)
   WS_CLIENT.on("message", (event) => {
     if (event.name !== "message") {
@@ -66,11 +81,37 @@ export const createSynthesizedSlice: StateCreator<
 
     switch (payload.case) {
       case "welcome":
-        crdt.import(payload.value.synthesizerSnapshot);
+        if (resyncing) {
+          // Start over from the server's song, the reset doc has a new peer
+          crdt.reset();
+          resyncing = false;
+        } else if (
+          peerUserId !== null &&
+          peerUserId !== payload.value.userId
+        ) {
+          crdt.renewPeer();
+        }
+        peerUserId = payload.value.userId;
+        if (payload.value.synthesizerSnapshot.length > 0) {
+          crdt.import(payload.value.synthesizerSnapshot);
+        }
+        if (payload.value.synthesizerUpdates.length > 0) {
+          crdt.import(payload.value.synthesizerUpdates);
+        }
         break;
       case "synthesizerUpdate":
         crdt.import(payload.value.data);
         break;
+      case "error":
+        if (payload.value.code === ErrorCode.INCOMPATIBLE_CLIENT) {
+          console.error(
+            "This version of the editor is out of date, reload the page:",
+            payload.value.message
+          );
+        } else {
+          console.warn("Server rejected update:", payload.value.message);
+        }
+        break;
     }
   });
 